@@ -0,0 +1,781 @@
+//! Assembles SM83 source in RGBDS syntax into bytes
+//!
+//! Labels end in `:`, the ones starting with `.` are local to the last global label. Numbers can
+//! be `$FF`, `0xFF`, `%1010`, `0b1010` or decimal, and added or subtracted with labels. `db` and
+//! `dw` put data in, and a `SECTION "name", ROM0[$0150]` before any code sets where it starts.
+
+use std::{collections::HashMap, fmt};
+
+/// Why the source couldn't be assembled
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    /// Starting from 1
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Ident(String),
+    Number(i64),
+    Str(String),
+    Punct(char),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reg {
+    A,
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+    AF,
+    BC,
+    DE,
+    HL,
+    SP,
+    NZ,
+    Z,
+    NC,
+}
+
+impl Reg {
+    fn parse(name: &str) -> Option<Reg> {
+        Some(match name.to_ascii_lowercase().as_str() {
+            "a" => Reg::A,
+            "b" => Reg::B,
+            "c" => Reg::C,
+            "d" => Reg::D,
+            "e" => Reg::E,
+            "h" => Reg::H,
+            "l" => Reg::L,
+            "af" => Reg::AF,
+            "bc" => Reg::BC,
+            "de" => Reg::DE,
+            "hl" => Reg::HL,
+            "sp" => Reg::SP,
+            "nz" => Reg::NZ,
+            "z" => Reg::Z,
+            "nc" => Reg::NC,
+            _ => return None,
+        })
+    }
+}
+
+/// A register pair in brackets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Ind {
+    BC,
+    DE,
+    HL,
+    /// `[hl+]` or `[hli]`
+    HLInc,
+    /// `[hl-]` or `[hld]`
+    HLDec,
+    /// `[c]` or `[$FF00+c]`
+    C,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Term {
+    Number(i64),
+    Label(String),
+}
+
+/// Numbers and labels added together, each with its sign
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Expr(Vec<(i64, Term)>);
+
+impl Expr {
+    fn eval(&self, labels: &HashMap<String, u32>) -> Result<i64, String> {
+        self.0.iter().try_fold(0, |total, (sign, term)| {
+            let value = match term {
+                Term::Number(n) => *n,
+                Term::Label(name) => match labels.get(name) {
+                    Some(address) => i64::from(*address),
+                    None => return Err(format!("undefined label '{name}'")),
+                },
+            };
+            Ok(total + sign * value)
+        })
+    }
+
+    /// For operands that are part of the opcode, which have to be known in the first pass
+    fn constant(&self) -> Result<i64, String> {
+        self.eval(&HashMap::new())
+            .map_err(|_| "expected a number, not a label".to_string())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Operand {
+    Reg(Reg),
+    Ind(Ind),
+    /// `[n16]`
+    Addr(Expr),
+    /// `sp+e8`
+    SpOffset(Expr),
+    Imm(Expr),
+    Str(String),
+}
+
+/// Part of an encoded instruction, operands are only filled in once every label is known
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Piece {
+    Byte(u8),
+    Imm8(Expr),
+    Imm16(Expr),
+    /// Signed 8 bit, for `add sp` and `ld hl, sp+`
+    Offset(Expr),
+    /// An address `jr` can reach from the end of the instruction
+    Relative(Expr),
+    /// An address in 0xFF00 - 0xFFFF, for `ldh`
+    HighPage(Expr),
+}
+
+impl Piece {
+    fn size(&self) -> u32 {
+        match self {
+            Piece::Imm16(_) => 2,
+            _ => 1,
+        }
+    }
+}
+
+struct Item {
+    line: usize,
+    address: u32,
+    pieces: Vec<Piece>,
+}
+
+const ALU: [&str; 8] = ["add", "adc", "sub", "sbc", "and", "xor", "or", "cp"];
+const SHIFTS: [&str; 8] = ["rlc", "rrc", "rl", "rr", "sla", "sra", "swap", "srl"];
+const BITS: [&str; 3] = ["bit", "res", "set"];
+
+/// Assembles `source`, one instruction per line. Code starts at 0x0000 unless there's a `SECTION`
+/// with an address.
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    let mut labels = HashMap::new();
+    let mut items = Vec::new();
+    // The last global label, which local ones belong to
+    let mut scope = String::new();
+    let mut address = 0;
+
+    for (index, line) in source.lines().enumerate() {
+        let error = |message| AsmError {
+            line: index + 1,
+            message,
+        };
+
+        let tokens = tokenize(line).map_err(error)?;
+        let mut tokens = tokens.as_slice();
+
+        if let [Token::Ident(name), Token::Punct(':'), rest @ ..] = tokens {
+            // `name::` exports it in RGBDS, which doesn't mean anything here
+            tokens = rest.strip_prefix(&[Token::Punct(':')]).unwrap_or(rest);
+
+            if !name.starts_with('.') {
+                scope = name.clone();
+            }
+            let name = qualify(name, &scope).map_err(error)?;
+            if labels.insert(name.clone(), address).is_some() {
+                return Err(error(format!("label '{name}' is already defined")));
+            }
+        }
+
+        let [Token::Ident(mnemonic), operands @ ..] = tokens else {
+            if tokens.is_empty() {
+                continue;
+            }
+            return Err(error("expected an instruction".to_string()));
+        };
+        let mnemonic = mnemonic.to_ascii_lowercase();
+
+        if mnemonic == "section" {
+            if !items.is_empty() || !labels.is_empty() {
+                return Err(error("SECTION has to come before any code".to_string()));
+            }
+            address = section_address(operands).map_err(error)?.unwrap_or(address);
+            continue;
+        }
+
+        let operands = split_operands(operands)
+            .into_iter()
+            .map(|tokens| operand(tokens, &scope))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(error)?;
+        let pieces = encode(&mnemonic, &operands).map_err(error)?;
+
+        let size: u32 = pieces.iter().map(Piece::size).sum();
+        items.push(Item {
+            line: index + 1,
+            address,
+            pieces,
+        });
+        address += size;
+        if address > 0x10000 {
+            return Err(error("past the end of the address space".to_string()));
+        }
+    }
+
+    let mut bytes = Vec::new();
+    for item in items {
+        let error = |message| AsmError {
+            line: item.line,
+            message,
+        };
+        let end = item.address + item.pieces.iter().map(Piece::size).sum::<u32>();
+
+        for piece in &item.pieces {
+            let value = |expr: &Expr| expr.eval(&labels).map_err(error);
+            let out_of_range = |value| error(format!("{value} is out of range"));
+
+            match piece {
+                Piece::Byte(byte) => bytes.push(*byte),
+                Piece::Imm8(expr) => {
+                    let value = value(expr)?;
+                    if !(-0x80..=0xFF).contains(&value) {
+                        return Err(out_of_range(value));
+                    }
+                    bytes.push(value as u8);
+                }
+                Piece::Imm16(expr) => {
+                    let value = value(expr)?;
+                    if !(-0x8000..=0xFFFF).contains(&value) {
+                        return Err(out_of_range(value));
+                    }
+                    bytes.extend((value as u16).to_le_bytes());
+                }
+                Piece::Offset(expr) => {
+                    let value = value(expr)?;
+                    if !(-0x80..=0x7F).contains(&value) {
+                        return Err(out_of_range(value));
+                    }
+                    bytes.push(value as u8);
+                }
+                Piece::Relative(expr) => {
+                    let offset = value(expr)? - i64::from(end);
+                    if !(-0x80..=0x7F).contains(&offset) {
+                        return Err(error(format!("jr target is {offset} bytes away")));
+                    }
+                    bytes.push(offset as u8);
+                }
+                Piece::HighPage(expr) => match value(expr)? {
+                    value @ (0x00..=0xFF | 0xFF00..=0xFFFF) => bytes.push(value as u8),
+                    value => return Err(error(format!("${value:04X} isn't in $FF00 - $FFFF"))),
+                },
+            }
+        }
+    }
+    Ok(bytes)
+}
+
+fn tokenize(line: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = line.chars().collect();
+    let word_end = |start: usize| {
+        (start..chars.len())
+            .find(|&i| !(chars[i].is_ascii_alphanumeric() || chars[i] == '_' || chars[i] == '.'))
+            .unwrap_or(chars.len())
+    };
+
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            ';' => break,
+            c if c.is_whitespace() => i += 1,
+            '$' | '%' => {
+                let end = word_end(i + 1);
+                let digits: String = chars[i + 1..end].iter().collect();
+                let radix = if c == '$' { 16 } else { 2 };
+                tokens.push(Token::Number(number(&digits, radix)?));
+                i = end;
+            }
+            '0'..='9' => {
+                let end = word_end(i);
+                let word: String = chars[i..end].iter().collect();
+                let lower = word.to_ascii_lowercase();
+                let value = if let Some(digits) = lower.strip_prefix("0x") {
+                    number(digits, 16)?
+                } else if let Some(digits) = lower.strip_prefix("0b") {
+                    number(digits, 2)?
+                } else {
+                    number(&lower, 10)?
+                };
+                tokens.push(Token::Number(value));
+                i = end;
+            }
+            c if c.is_ascii_alphabetic() || c == '_' || c == '.' => {
+                let end = word_end(i);
+                tokens.push(Token::Ident(chars[i..end].iter().collect()));
+                i = end;
+            }
+            '"' => {
+                let Some(end) = (i + 1..chars.len()).find(|&j| chars[j] == '"') else {
+                    return Err("unterminated string".to_string());
+                };
+                tokens.push(Token::Str(chars[i + 1..end].iter().collect()));
+                i = end + 1;
+            }
+            '[' | ']' | ',' | '+' | '-' | ':' => {
+                tokens.push(Token::Punct(c));
+                i += 1;
+            }
+            c => return Err(format!("unexpected '{c}'")),
+        }
+    }
+    Ok(tokens)
+}
+
+fn number(digits: &str, radix: u32) -> Result<i64, String> {
+    let digits = digits.replace('_', "");
+    i64::from_str_radix(&digits, radix).map_err(|_| format!("invalid number '{digits}'"))
+}
+
+/// Local labels are stored under the global label they belong to, as `global.local`
+fn qualify(name: &str, scope: &str) -> Result<String, String> {
+    if !name.starts_with('.') {
+        return Ok(name.to_string());
+    }
+    if scope.is_empty() {
+        return Err(format!(
+            "local label '{name}' without a global label before it"
+        ));
+    }
+    Ok(format!("{scope}{name}"))
+}
+
+/// `SECTION "name", TYPE[address]`, the address is optional
+fn section_address(tokens: &[Token]) -> Result<Option<u32>, String> {
+    let [Token::Str(_), Token::Punct(','), Token::Ident(_), rest @ ..] = tokens else {
+        return Err("expected SECTION \"name\", TYPE[address]".to_string());
+    };
+    match rest {
+        [] => Ok(None),
+        [Token::Punct('['), inner @ .., Token::Punct(']')] => {
+            match expression(inner, "")?.constant()? {
+                address @ 0..=0xFFFF => Ok(Some(address as u32)),
+                address => Err(format!("{address} is out of range")),
+            }
+        }
+        _ => Err("expected an address in brackets".to_string()),
+    }
+}
+
+fn split_operands(tokens: &[Token]) -> Vec<&[Token]> {
+    if tokens.is_empty() {
+        return Vec::new();
+    }
+    tokens.split(|token| *token == Token::Punct(',')).collect()
+}
+
+fn operand(tokens: &[Token], scope: &str) -> Result<Operand, String> {
+    use Token::*;
+
+    Ok(match tokens {
+        [] => return Err("missing operand".to_string()),
+        [Str(s)] => Operand::Str(s.clone()),
+        [Punct('['), inner @ .., Punct(']')] => indirect(inner, scope)?,
+        [Ident(name)] if Reg::parse(name).is_some() => Operand::Reg(Reg::parse(name).unwrap()),
+        [Ident(name), Punct('+' | '-'), ..] if Reg::parse(name) == Some(Reg::SP) => {
+            Operand::SpOffset(expression(&tokens[1..], scope)?)
+        }
+        _ => Operand::Imm(expression(tokens, scope)?),
+    })
+}
+
+fn indirect(tokens: &[Token], scope: &str) -> Result<Operand, String> {
+    use Token::*;
+
+    let name = |name: &str| name.to_ascii_lowercase();
+    Ok(match tokens {
+        [Ident(reg)] if name(reg) == "bc" => Operand::Ind(Ind::BC),
+        [Ident(reg)] if name(reg) == "de" => Operand::Ind(Ind::DE),
+        [Ident(reg)] if name(reg) == "hl" => Operand::Ind(Ind::HL),
+        [Ident(reg)] if name(reg) == "hli" => Operand::Ind(Ind::HLInc),
+        [Ident(reg)] if name(reg) == "hld" => Operand::Ind(Ind::HLDec),
+        [Ident(reg)] if name(reg) == "c" => Operand::Ind(Ind::C),
+        [Ident(reg), Punct('+')] if name(reg) == "hl" => Operand::Ind(Ind::HLInc),
+        [Ident(reg), Punct('-')] if name(reg) == "hl" => Operand::Ind(Ind::HLDec),
+        [Number(0xFF00), Punct('+'), Ident(reg)] if name(reg) == "c" => Operand::Ind(Ind::C),
+        _ => Operand::Addr(expression(tokens, scope)?),
+    })
+}
+
+/// Numbers and labels separated by `+` and `-`, each can have its own signs in front
+fn expression(tokens: &[Token], scope: &str) -> Result<Expr, String> {
+    let mut terms = Vec::new();
+    let mut rest = tokens;
+
+    loop {
+        let mut sign = 1;
+        while let [Token::Punct(c @ ('+' | '-')), tail @ ..] = rest {
+            if *c == '-' {
+                sign = -sign;
+            }
+            rest = tail;
+        }
+
+        let term = match rest.first() {
+            Some(Token::Number(n)) => Term::Number(*n),
+            Some(Token::Ident(name)) if Reg::parse(name).is_some() => {
+                return Err(format!("register '{name}' can't be used here"));
+            }
+            Some(Token::Ident(name)) => Term::Label(qualify(name, scope)?),
+            _ => return Err("expected a number or label".to_string()),
+        };
+        terms.push((sign, term));
+        rest = &rest[1..];
+
+        match rest {
+            [] => return Ok(Expr(terms)),
+            [Token::Punct('+' | '-'), ..] => {}
+            _ => return Err("expected '+' or '-' between terms".to_string()),
+        }
+    }
+}
+
+fn r8(operand: &Operand) -> Result<u8, String> {
+    Ok(match operand {
+        Operand::Reg(Reg::B) => 0,
+        Operand::Reg(Reg::C) => 1,
+        Operand::Reg(Reg::D) => 2,
+        Operand::Reg(Reg::E) => 3,
+        Operand::Reg(Reg::H) => 4,
+        Operand::Reg(Reg::L) => 5,
+        Operand::Ind(Ind::HL) => 6,
+        Operand::Reg(Reg::A) => 7,
+        _ => return Err("expected b, c, d, e, h, l, [hl] or a".to_string()),
+    })
+}
+
+fn r16(operand: &Operand) -> Result<u8, String> {
+    Ok(match operand {
+        Operand::Reg(Reg::BC) => 0,
+        Operand::Reg(Reg::DE) => 1,
+        Operand::Reg(Reg::HL) => 2,
+        Operand::Reg(Reg::SP) => 3,
+        _ => return Err("expected bc, de, hl or sp".to_string()),
+    })
+}
+
+fn r16stk(operand: &Operand) -> Result<u8, String> {
+    Ok(match operand {
+        Operand::Reg(Reg::BC) => 0,
+        Operand::Reg(Reg::DE) => 1,
+        Operand::Reg(Reg::HL) => 2,
+        Operand::Reg(Reg::AF) => 3,
+        _ => return Err("expected bc, de, hl or af".to_string()),
+    })
+}
+
+fn r16mem(operand: &Operand) -> Result<u8, String> {
+    Ok(match operand {
+        Operand::Ind(Ind::BC) => 0,
+        Operand::Ind(Ind::DE) => 1,
+        Operand::Ind(Ind::HLInc) => 2,
+        Operand::Ind(Ind::HLDec) => 3,
+        _ => return Err("expected [bc], [de], [hl+] or [hl-]".to_string()),
+    })
+}
+
+fn cond(operand: &Operand) -> Result<u8, String> {
+    Ok(match operand {
+        Operand::Reg(Reg::NZ) => 0,
+        Operand::Reg(Reg::Z) => 1,
+        Operand::Reg(Reg::NC) => 2,
+        Operand::Reg(Reg::C) => 3,
+        _ => return Err("expected nz, z, nc or c".to_string()),
+    })
+}
+
+fn imm(operand: &Operand) -> Result<Expr, String> {
+    match operand {
+        Operand::Imm(expr) => Ok(expr.clone()),
+        _ => Err("expected a number or label".to_string()),
+    }
+}
+
+// https://gbdev.io/pandocs/CPU_Instruction_Set.html
+fn encode(mnemonic: &str, operands: &[Operand]) -> Result<Vec<Piece>, String> {
+    use Operand::{Imm, Reg as R};
+    use Piece::*;
+
+    let op = |opcode: u8| Ok(vec![Byte(opcode)]);
+
+    match (mnemonic, operands) {
+        ("nop", []) => op(0x00),
+        ("halt", []) => op(0x76),
+        ("stop", []) => Ok(vec![Byte(0x10), Byte(0x00)]),
+        ("di", []) => op(0xF3),
+        ("ei", []) => op(0xFB),
+        ("rlca", []) => op(0x07),
+        ("rrca", []) => op(0x0F),
+        ("rla", []) => op(0x17),
+        ("rra", []) => op(0x1F),
+        ("daa", []) => op(0x27),
+        ("cpl", []) => op(0x2F),
+        ("scf", []) => op(0x37),
+        ("ccf", []) => op(0x3F),
+        ("ld", [dst, src]) => ld(dst, src),
+        ("ldh", [dst, src]) => ldh(dst, src),
+        ("inc" | "dec", [reg]) => {
+            let dec = u8::from(mnemonic == "dec");
+            match (r8(reg), r16(reg)) {
+                (Ok(r), _) => op(0x04 | r << 3 | dec),
+                (_, Ok(p)) => op(0x03 | p << 4 | dec << 3),
+                _ => Err("expected an 8 or 16 bit register".to_string()),
+            }
+        }
+        ("add", [R(Reg::HL), reg]) => op(0x09 | r16(reg)? << 4),
+        ("add", [R(Reg::SP), e]) => Ok(vec![Byte(0xE8), Offset(imm(e)?)]),
+        (alu, [R(Reg::A), src] | [src]) if ALU.contains(&alu) => {
+            let index = ALU.iter().position(|name| *name == alu).unwrap() as u8;
+            match src {
+                Imm(expr) => Ok(vec![Byte(0xC6 | index << 3), Imm8(expr.clone())]),
+                reg => op(0x80 | index << 3 | r8(reg)?),
+            }
+        }
+        ("jr", [target]) => Ok(vec![Byte(0x18), Relative(imm(target)?)]),
+        ("jr", [cc, target]) => Ok(vec![Byte(0x20 | cond(cc)? << 3), Relative(imm(target)?)]),
+        ("jp", [R(Reg::HL)]) => op(0xE9),
+        ("jp", [target]) => Ok(vec![Byte(0xC3), Imm16(imm(target)?)]),
+        ("jp", [cc, target]) => Ok(vec![Byte(0xC2 | cond(cc)? << 3), Imm16(imm(target)?)]),
+        ("call", [target]) => Ok(vec![Byte(0xCD), Imm16(imm(target)?)]),
+        ("call", [cc, target]) => Ok(vec![Byte(0xC4 | cond(cc)? << 3), Imm16(imm(target)?)]),
+        ("ret", []) => op(0xC9),
+        ("ret", [cc]) => op(0xC0 | cond(cc)? << 3),
+        ("reti", []) => op(0xD9),
+        ("rst", [target]) => match imm(target)?.constant()? {
+            vector @ (0x00 | 0x08 | 0x10 | 0x18 | 0x20 | 0x28 | 0x30 | 0x38) => {
+                op(0xC7 | vector as u8)
+            }
+            vector => Err(format!("there's no rst vector at {vector:#04X}")),
+        },
+        ("push", [reg]) => op(0xC5 | r16stk(reg)? << 4),
+        ("pop", [reg]) => op(0xC1 | r16stk(reg)? << 4),
+        (shift, [reg]) if SHIFTS.contains(&shift) => {
+            let index = SHIFTS.iter().position(|name| *name == shift).unwrap() as u8;
+            Ok(vec![Byte(0xCB), Byte(index << 3 | r8(reg)?)])
+        }
+        (bit_op, [bit, reg]) if BITS.contains(&bit_op) => {
+            let index = BITS.iter().position(|name| *name == bit_op).unwrap() as u8 + 1;
+            let bit = match imm(bit)?.constant()? {
+                bit @ 0..=7 => bit as u8,
+                bit => return Err(format!("bit {bit} doesn't exist")),
+            };
+            Ok(vec![Byte(0xCB), Byte(index << 6 | bit << 3 | r8(reg)?)])
+        }
+        ("db", [_, ..]) => {
+            let mut pieces = Vec::new();
+            for operand in operands {
+                match operand {
+                    Operand::Str(s) => pieces.extend(s.bytes().map(Byte)),
+                    operand => pieces.push(Imm8(imm(operand)?)),
+                }
+            }
+            Ok(pieces)
+        }
+        ("dw", [_, ..]) => operands
+            .iter()
+            .map(|operand| Ok(Imm16(imm(operand)?)))
+            .collect(),
+        _ => Err(format!(
+            "no '{mnemonic}' instruction takes {} operands like that",
+            operands.len()
+        )),
+    }
+}
+
+fn ld(dst: &Operand, src: &Operand) -> Result<Vec<Piece>, String> {
+    use Operand::{Addr, Imm, Ind as I, Reg as R};
+    use Piece::*;
+
+    Ok(match (dst, src) {
+        (I(Ind::HL), I(Ind::HL)) => return Err("ld [hl], [hl] would be halt".to_string()),
+        (R(Reg::HL), Operand::SpOffset(e)) => vec![Byte(0xF8), Offset(e.clone())],
+        (R(Reg::SP), R(Reg::HL)) => vec![Byte(0xF9)],
+        (Addr(nn), R(Reg::SP)) => vec![Byte(0x08), Imm16(nn.clone())],
+        (Addr(nn), R(Reg::A)) => vec![Byte(0xEA), Imm16(nn.clone())],
+        (R(Reg::A), Addr(nn)) => vec![Byte(0xFA), Imm16(nn.clone())],
+        (I(Ind::C), R(Reg::A)) => vec![Byte(0xE2)],
+        (R(Reg::A), I(Ind::C)) => vec![Byte(0xF2)],
+        (I(Ind::BC | Ind::DE | Ind::HLInc | Ind::HLDec), R(Reg::A)) => {
+            vec![Byte(0x02 | r16mem(dst)? << 4)]
+        }
+        (R(Reg::A), I(Ind::BC | Ind::DE | Ind::HLInc | Ind::HLDec)) => {
+            vec![Byte(0x0A | r16mem(src)? << 4)]
+        }
+        (dst, Imm(n)) => match (r8(dst), r16(dst)) {
+            (Ok(r), _) => vec![Byte(0x06 | r << 3), Imm8(n.clone())],
+            (_, Ok(p)) => vec![Byte(0x01 | p << 4), Imm16(n.clone())],
+            _ => return Err("expected an 8 or 16 bit register".to_string()),
+        },
+        (dst, src) => vec![Byte(0x40 | r8(dst)? << 3 | r8(src)?)],
+    })
+}
+
+fn ldh(dst: &Operand, src: &Operand) -> Result<Vec<Piece>, String> {
+    use Operand::{Addr, Ind as I, Reg as R};
+    use Piece::*;
+
+    Ok(match (dst, src) {
+        (I(Ind::C), R(Reg::A)) => vec![Byte(0xE2)],
+        (R(Reg::A), I(Ind::C)) => vec![Byte(0xF2)],
+        (Addr(n), R(Reg::A)) => vec![Byte(0xE0), HighPage(n.clone())],
+        (R(Reg::A), Addr(n)) => vec![Byte(0xF0), HighPage(n.clone())],
+        _ => return Err("expected ldh [n8], a or ldh a, [n8]".to_string()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assemble;
+
+    fn error(source: &str) -> String {
+        assemble(source).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn instructions() {
+        let source = "
+            nop
+            ld a, $12             ; comment
+            LD HL, 0x1234
+            ld [hl+], a
+            ld a, [hld]
+            ld b, [hl]
+            ld [$C000], sp
+            ldh [$FF40], a
+            ldh a, [c]
+            ld [$FF00+c], a
+            ld hl, sp-2
+            add sp, -2
+            add hl, de
+            inc [hl]
+            dec sp
+            sbc a, a
+            cp %1010
+            xor $FF
+            rst $38
+            push af
+            swap a
+            bit 7, [hl]
+            set 0, c
+            stop
+        ";
+        #[rustfmt::skip]
+        let expected = [
+            0x00,
+            0x3E, 0x12,
+            0x21, 0x34, 0x12,
+            0x22,
+            0x3A,
+            0x46,
+            0x08, 0x00, 0xC0,
+            0xE0, 0x40,
+            0xF2,
+            0xE2,
+            0xF8, 0xFE,
+            0xE8, 0xFE,
+            0x19,
+            0x34,
+            0x3B,
+            0x9F,
+            0xFE, 0x0A,
+            0xEE, 0xFF,
+            0xFF,
+            0xF5,
+            0xCB, 0x37,
+            0xCB, 0x7E,
+            0xCB, 0xC1,
+            0x10, 0x00,
+        ];
+        assert_eq!(assemble(source).unwrap(), expected);
+    }
+
+    #[test]
+    fn labels() {
+        let source = r#"
+            SECTION "test", ROM0[$0150]
+        Main::
+            ld b, 3
+        .loop:
+            dec b
+            jr nz, .loop
+            call Func
+            jp Main
+        .data: db 1, -1, "hi"
+            dw .data, Func + 2
+        Func:
+            jr .skip
+            nop
+        .skip:
+            ret
+        "#;
+        #[rustfmt::skip]
+        let expected = [
+            0x06, 0x03,
+            0x05,
+            0x20, 0xFD,
+            0xCD, 0x63, 0x01,
+            0xC3, 0x50, 0x01,
+            0x01, 0xFF, b'h', b'i',
+            0x5B, 0x01, 0x65, 0x01,
+            0x18, 0x01,
+            0x00,
+            0xC9,
+        ];
+        assert_eq!(assemble(source).unwrap(), expected);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error("jp Nowhere"), "line 1: undefined label 'Nowhere'");
+        assert_eq!(error("a:\na:"), "line 2: label 'a' is already defined");
+        assert_eq!(
+            error("ld [hl], [hl]"),
+            "line 1: ld [hl], [hl] would be halt"
+        );
+        assert_eq!(error("ld a, 256"), "line 1: 256 is out of range");
+        assert_eq!(
+            error("ldh a, [$C000]"),
+            "line 1: $C000 isn't in $FF00 - $FFFF"
+        );
+        assert_eq!(error("rst 1"), "line 1: there's no rst vector at 0x01");
+        assert_eq!(error("bit 8, a"), "line 1: bit 8 doesn't exist");
+        assert_eq!(error("push sp"), "line 1: expected bc, de, hl or af");
+        assert_eq!(
+            error("ld a, b + 1"),
+            "line 1: register 'b' can't be used here"
+        );
+        assert_eq!(
+            error("mov a, b"),
+            "line 1: no 'mov' instruction takes 2 operands like that"
+        );
+        assert_eq!(
+            error(".local: nop"),
+            "line 1: local label '.local' without a global label before it"
+        );
+        assert_eq!(
+            error("nop\nSECTION \"late\", ROM0[0]"),
+            "line 2: SECTION has to come before any code"
+        );
+
+        let far = format!("Start:\n{}jr Start", "nop\n".repeat(127));
+        assert_eq!(error(&far), "line 129: jr target is -129 bytes away");
+        let near = format!("Start:\n{}jr Start", "nop\n".repeat(126));
+        assert!(assemble(&near).is_ok());
+    }
+}
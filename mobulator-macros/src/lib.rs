@@ -1,4 +1,4 @@
-use proc_macro::TokenStream;
+use proc_macro::{Delimiter, TokenStream, TokenTree};
 use quote::quote;
 use syn::{Lit, parse_macro_input};
 
@@ -32,9 +32,67 @@ pub fn opcode_list(tokens: TokenStream) -> TokenStream {
     })
 }
 
+/// Assembles RGBDS syntax into a `[u8; N]`, with `;` between instructions instead of newlines.
+/// Hex has to be written as `0x1E` or `$1E`, with `$` only when the digits don't start with a
+/// number, as Rust can't tokenize something like `$1E`.
+#[proc_macro]
+pub fn asm(tokens: TokenStream) -> TokenStream {
+    let mut source = String::new();
+    write_source(tokens, &mut source);
+
+    match mobulator_asm::assemble(&source) {
+        Ok(bytes) => TokenStream::from(quote! {
+            [#(#bytes),*]
+        }),
+        Err(e) => {
+            let message = format!("asm!: {e}");
+            TokenStream::from(quote! {
+                compile_error!(#message)
+            })
+        }
+    }
+}
+
+/// Turns the tokens back into source text, only putting spaces between words so `$C000` and
+/// `[hl+]` stay together
+fn write_source(tokens: TokenStream, source: &mut String) {
+    let mut after_word = false;
+    for tree in tokens {
+        match tree {
+            TokenTree::Group(group) => {
+                let (open, close) = match group.delimiter() {
+                    Delimiter::Parenthesis => ("(", ")"),
+                    Delimiter::Brace => ("{", "}"),
+                    Delimiter::Bracket => ("[", "]"),
+                    Delimiter::None => ("", ""),
+                };
+                source.push_str(open);
+                write_source(group.stream(), source);
+                source.push_str(close);
+                after_word = false;
+            }
+            TokenTree::Punct(punct) if punct.as_char() == ';' => {
+                source.push('\n');
+                after_word = false;
+            }
+            TokenTree::Punct(punct) => {
+                source.push(punct.as_char());
+                after_word = false;
+            }
+            TokenTree::Ident(_) | TokenTree::Literal(_) => {
+                if after_word {
+                    source.push(' ');
+                }
+                source.push_str(&tree.to_string());
+                after_word = true;
+            }
+        }
+    }
+}
+
 fn generate_instructions(bin_pat: String) -> Vec<u8> {
     let range = bin_pat
-        .match_indices(|c| c == '_')
+        .match_indices('_')
         .map(|(i, _)| i)
         .collect::<Vec<_>>();
     let min_i = *range.iter().min().expect("Unabled to get min");
@@ -7,7 +7,7 @@ pub fn gen_test(tokens: TokenStream) -> TokenStream {
     let punctuated =
         parse_macro_input!(tokens with Punctuated<Lit, syn::Token![,]>::parse_terminated);
     let punctuated = punctuated.iter().collect::<Vec<_>>();
-    if punctuated.len() == 0 {
+    if punctuated.is_empty() {
         panic!("One or two arguments expected");
     }
 
@@ -0,0 +1,971 @@
+use crate::{
+    audio::{HighPass, Resampling, SampleOutput},
+    utils::BitExt,
+};
+
+pub const NR10: u16 = 0xFF10;
+pub const NR11: u16 = 0xFF11;
+pub const NR12: u16 = 0xFF12;
+pub const NR13: u16 = 0xFF13;
+pub const NR14: u16 = 0xFF14;
+pub const NR21: u16 = 0xFF16;
+pub const NR22: u16 = 0xFF17;
+pub const NR23: u16 = 0xFF18;
+pub const NR24: u16 = 0xFF19;
+pub const NR30: u16 = 0xFF1A;
+pub const NR31: u16 = 0xFF1B;
+pub const NR32: u16 = 0xFF1C;
+pub const NR33: u16 = 0xFF1D;
+pub const NR34: u16 = 0xFF1E;
+pub const NR41: u16 = 0xFF20;
+pub const NR42: u16 = 0xFF21;
+pub const NR43: u16 = 0xFF22;
+pub const NR44: u16 = 0xFF23;
+pub const NR50: u16 = 0xFF24;
+pub const NR51: u16 = 0xFF25;
+pub const NR52: u16 = 0xFF26;
+pub const WAVE_RAM_START: u16 = 0xFF30;
+pub const WAVE_RAM_END: u16 = 0xFF3F;
+
+/// The channels are clocked once per normal speed M-cycle
+pub const CLOCK_RATE: u32 = 1_048_576;
+pub const DEFAULT_SAMPLE_RATE: u32 = 48_000;
+
+/// Bits that read back as 1 for every register from NR10 to 0xFF2F, unused ones read 0xFF
+// https://gbdev.io/pandocs/Audio_details.html#registers
+const READ_MASKS: [u8; 0x20] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10 - NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // NR20 - NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30 - NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // NR40 - NR44
+    0x00, 0x00, 0x70, // NR50 - NR52
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+];
+
+/// Waveforms for each duty setting, the leftmost bit is played first
+const DUTY_CYCLES: [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+
+/// Noise divisors in T-cycles, before the clock shift is applied
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+/// Shifts applied to wave samples for each NR32 output level, 4 mutes the channel
+const WAVE_SHIFTS: [u8; 4] = [4, 0, 1, 2];
+
+#[derive(Debug, Clone, Default)]
+struct Length {
+    counter: u16,
+    enabled: bool,
+}
+
+impl Length {
+    fn load(&mut self, max: u16, value: u16) {
+        self.counter = max - value;
+    }
+
+    fn trigger(&mut self, max: u16) {
+        if self.counter == 0 {
+            self.counter = max;
+        }
+    }
+
+    /// Returns true when the counter runs out and the channel should be turned off
+    fn clock(&mut self) -> bool {
+        if !self.enabled || self.counter == 0 {
+            return false;
+        }
+        self.counter -= 1;
+        self.counter == 0
+    }
+}
+
+/// ┌──────┬───┬───┬───┬───┬─────────┬───┬───┬───┐
+/// │ NRx2 │ 7 │ 6 │ 5 │ 4 │    3    │ 2 │ 1 │ 0 │
+/// ├──────┼───┴───┴───┴───┼─────────┼───┴───┴───┤
+/// │      │Initial volume │Direction│   Pace    │
+/// └──────┴───────────────┴─────────┴───────────┘
+#[derive(Debug, Clone, Default)]
+struct Envelope {
+    register: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn period(&self) -> u8 {
+        self.register & 0x07
+    }
+
+    /// The DAC is off when the initial volume is 0 and the envelope goes down
+    fn dac_enabled(&self) -> bool {
+        self.register & 0xF8 != 0
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.register >> 4;
+        self.timer = self.period();
+    }
+
+    fn clock(&mut self) {
+        if self.period() == 0 {
+            return;
+        }
+
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = self.period();
+
+        if self.register.is_bit_set(3) {
+            self.volume = (self.volume + 1).min(15);
+        } else {
+            self.volume = self.volume.saturating_sub(1);
+        }
+    }
+}
+
+/// ┌──────┬───┬───┬───┬───┬─────────┬───┬───┬───┐
+/// │ NR10 │ 7 │ 6 │ 5 │ 4 │    3    │ 2 │ 1 │ 0 │
+/// ├──────┼───┼───┴───┴───┼─────────┼───┴───┴───┤
+/// │      │   │   Pace    │Direction│   Step    │
+/// └──────┴───┴───────────┴─────────┴───────────┘
+#[derive(Debug, Clone, Default)]
+struct Sweep {
+    register: u8,
+    enabled: bool,
+    shadow: u16,
+    timer: u8,
+}
+
+impl Sweep {
+    fn period(&self) -> u8 {
+        (self.register >> 4) & 0x07
+    }
+
+    fn shift(&self) -> u8 {
+        self.register & 0x07
+    }
+
+    fn reload_timer(&mut self) {
+        // A pace of 0 is treated as 8 by the timer
+        self.timer = match self.period() {
+            0 => 8,
+            period => period,
+        };
+    }
+
+    /// The next frequency, or None if it overflows and the channel should be turned off
+    fn next_frequency(&self) -> Option<u16> {
+        let delta = self.shadow >> self.shift();
+        let frequency = if self.register.is_bit_set(3) {
+            self.shadow - delta
+        } else {
+            self.shadow + delta
+        };
+        (frequency <= 0x7FF).then_some(frequency)
+    }
+}
+
+/// Channels 1 and 2, only channel 1 has a sweep
+#[derive(Debug, Clone, Default)]
+struct Pulse {
+    enabled: bool,
+    duty: u8,
+    duty_step: u8,
+    frequency: u16,
+    /// T-cycles until the next duty step
+    timer: u32,
+    length: Length,
+    envelope: Envelope,
+    sweep: Option<Sweep>,
+}
+
+impl Pulse {
+    fn with_sweep() -> Self {
+        Self {
+            sweep: Some(Sweep::default()),
+            ..Default::default()
+        }
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - u32::from(self.frequency)) * 4
+    }
+
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                if let Some(sweep) = &mut self.sweep {
+                    sweep.register = value;
+                }
+            }
+            1 => {
+                self.duty = value >> 6;
+                self.length.load(64, u16::from(value & 0x3F));
+            }
+            2 => {
+                self.envelope.register = value;
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.frequency = (self.frequency & 0x700) | u16::from(value),
+            _ => {
+                self.frequency = (self.frequency & 0xFF) | (u16::from(value & 0x07) << 8);
+                self.length.enabled = value.is_bit_set(6);
+                if value.is_bit_set(7) {
+                    self.trigger();
+                }
+            }
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.timer = self.period();
+        self.length.trigger(64);
+        self.envelope.trigger();
+
+        if let Some(sweep) = &mut self.sweep {
+            sweep.shadow = self.frequency;
+            sweep.reload_timer();
+            sweep.enabled = sweep.period() != 0 || sweep.shift() != 0;
+            // Overflow is checked straight away, but the new frequency isn't used yet
+            if sweep.shift() != 0 && sweep.next_frequency().is_none() {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn clock_sweep(&mut self) {
+        let Some(sweep) = &mut self.sweep else {
+            return;
+        };
+
+        sweep.timer = sweep.timer.saturating_sub(1);
+        if sweep.timer > 0 {
+            return;
+        }
+        sweep.reload_timer();
+
+        if !sweep.enabled || sweep.period() == 0 {
+            return;
+        }
+
+        match sweep.next_frequency() {
+            Some(frequency) if sweep.shift() != 0 => {
+                sweep.shadow = frequency;
+                self.frequency = frequency;
+                // The new frequency is immediately checked again, but not written back
+                if sweep.next_frequency().is_none() {
+                    self.enabled = false;
+                }
+            }
+            Some(_) => {}
+            None => self.enabled = false,
+        }
+    }
+
+    fn step(&mut self, t_cycles: u32) {
+        let mut remaining = t_cycles;
+        while remaining >= self.timer {
+            remaining -= self.timer;
+            self.timer = self.period();
+            self.duty_step = (self.duty_step + 1) % 8;
+        }
+        self.timer -= remaining;
+    }
+
+    fn output(&self) -> Option<u8> {
+        if !self.envelope.dac_enabled() {
+            return None;
+        }
+        let high = self.enabled
+            && DUTY_CYCLES[usize::from(self.duty)].is_bit_set(7 - u32::from(self.duty_step));
+        Some(if high { self.envelope.volume } else { 0 })
+    }
+}
+
+/// Channel 3, plays the 32 4-bit samples in wave RAM
+#[derive(Debug, Clone, Default)]
+struct Wave {
+    enabled: bool,
+    dac_enabled: bool,
+    output_level: u8,
+    frequency: u16,
+    timer: u32,
+    position: u8,
+    sample: u8,
+    length: Length,
+    ram: [u8; 16],
+}
+
+impl Wave {
+    fn period(&self) -> u32 {
+        (2048 - u32::from(self.frequency)) * 2
+    }
+
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.dac_enabled = value.is_bit_set(7);
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            1 => self.length.load(256, u16::from(value)),
+            2 => self.output_level = (value >> 5) & 0x03,
+            3 => self.frequency = (self.frequency & 0x700) | u16::from(value),
+            _ => {
+                self.frequency = (self.frequency & 0xFF) | (u16::from(value & 0x07) << 8);
+                self.length.enabled = value.is_bit_set(6);
+                if value.is_bit_set(7) {
+                    self.enabled = self.dac_enabled;
+                    self.timer = self.period();
+                    self.position = 0;
+                    self.length.trigger(256);
+                }
+            }
+        }
+    }
+
+    /// While the channel is playing the CPU can only see the byte it's currently reading
+    fn ram_index(&self, addr: u16) -> usize {
+        if self.enabled {
+            usize::from(self.position / 2)
+        } else {
+            usize::from(addr - WAVE_RAM_START)
+        }
+    }
+
+    fn step(&mut self, t_cycles: u32) {
+        let mut remaining = t_cycles;
+        while remaining >= self.timer {
+            remaining -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) % 32;
+
+            let byte = self.ram[usize::from(self.position / 2)];
+            self.sample = if self.position.is_multiple_of(2) {
+                byte >> 4
+            } else {
+                byte & 0x0F
+            };
+        }
+        self.timer -= remaining;
+    }
+
+    fn output(&self) -> Option<u8> {
+        if !self.dac_enabled {
+            return None;
+        }
+        let sample = if self.enabled {
+            self.sample >> WAVE_SHIFTS[usize::from(self.output_level)]
+        } else {
+            0
+        };
+        Some(sample)
+    }
+}
+
+/// Channel 4, white noise from a linear feedback shift register
+///
+/// ┌──────┬───┬───┬───┬───┬─────┬───┬───┬───┐
+/// │ NR43 │ 7 │ 6 │ 5 │ 4 │  3  │ 2 │ 1 │ 0 │
+/// ├──────┼───┴───┴───┴───┼─────┼───┴───┴───┤
+/// │      │  Clock shift  │Width│  Divider  │
+/// └──────┴───────────────┴─────┴───────────┘
+#[derive(Debug, Clone)]
+struct Noise {
+    enabled: bool,
+    register: u8,
+    lfsr: u16,
+    timer: u32,
+    length: Length,
+    envelope: Envelope,
+}
+
+impl Default for Noise {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            register: 0,
+            lfsr: 0x7FFF,
+            timer: NOISE_DIVISORS[0],
+            length: Length::default(),
+            envelope: Envelope::default(),
+        }
+    }
+}
+
+impl Noise {
+    fn period(&self) -> u32 {
+        NOISE_DIVISORS[usize::from(self.register & 0x07)] << (self.register >> 4)
+    }
+
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            1 => self.length.load(64, u16::from(value & 0x3F)),
+            2 => {
+                self.envelope.register = value;
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.register = value,
+            4 => {
+                self.length.enabled = value.is_bit_set(6);
+                if value.is_bit_set(7) {
+                    self.enabled = self.envelope.dac_enabled();
+                    self.timer = self.period();
+                    self.lfsr = 0x7FFF;
+                    self.length.trigger(64);
+                    self.envelope.trigger();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn step(&mut self, t_cycles: u32) {
+        // Shifts of 14 and 15 stop the LFSR from being clocked at all
+        if self.register >> 4 >= 14 {
+            return;
+        }
+
+        let mut remaining = t_cycles;
+        while remaining >= self.timer {
+            remaining -= self.timer;
+            self.timer = self.period();
+
+            let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+            self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+            if self.register.is_bit_set(3) {
+                self.lfsr = (self.lfsr & !0x40) | (feedback << 6);
+            }
+        }
+        self.timer -= remaining;
+    }
+
+    fn output(&self) -> Option<u8> {
+        if !self.envelope.dac_enabled() {
+            return None;
+        }
+        let high = self.enabled && self.lfsr & 1 == 0;
+        Some(if high { self.envelope.volume } else { 0 })
+    }
+}
+
+/// ┌──────┬───────┬───┬───┬───┬─────┬─────┬─────┬─────┐
+/// │ NR52 │   7   │ 6 │ 5 │ 4 │  3  │  2  │  1  │  0  │
+/// ├──────┼───────┼───┼───┼───┼─────┼─────┼─────┼─────┤
+/// │      │Audio  │   │   │   │ CH4 │ CH3 │ CH2 │ CH1 │
+/// │      │on/off │   │   │   │ on? │ on? │ on? │ on? │
+/// └──────┴───────┴───┴───┴───┴─────┴─────┴─────┴─────┘
+///
+/// Samples are generated for every M-cycle and resampled down to the output sample rate, then
+/// buffered until they're pulled with `read_samples` or `read_samples_i16`.
+// https://gbdev.io/pandocs/Audio.html
+#[derive(Debug, Clone)]
+pub struct Apu {
+    enabled: bool,
+    registers: [u8; 0x20],
+    pulse1: Pulse,
+    pulse2: Pulse,
+    wave: Wave,
+    noise: Noise,
+    frame_step: u8,
+    /// The DIV bit that clocks the frame sequencer as of the last tick
+    div_bit: bool,
+    output: SampleOutput,
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            registers: [0; 0x20],
+            pulse1: Pulse::with_sweep(),
+            pulse2: Pulse::default(),
+            wave: Wave::default(),
+            noise: Noise::default(),
+            frame_step: 0,
+            div_bit: false,
+            output: SampleOutput::new(
+                DEFAULT_SAMPLE_RATE,
+                Resampling::default(),
+                HighPass::default(),
+            ),
+        }
+    }
+}
+
+impl Apu {
+    pub fn sample_rate(&self) -> u32 {
+        self.output.sample_rate()
+    }
+
+    pub fn resampling(&self) -> Resampling {
+        self.output.resampling()
+    }
+
+    pub fn high_pass(&self) -> HighPass {
+        self.output.high_pass()
+    }
+
+    /// Changes the output sample rate, throwing away anything that hasn't been read yet
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.output = SampleOutput::new(sample_rate, self.resampling(), self.high_pass());
+    }
+
+    /// Changes how samples are resampled, throwing away anything that hasn't been read yet
+    pub fn set_resampling(&mut self, resampling: Resampling) {
+        self.output = SampleOutput::new(self.sample_rate(), resampling, self.high_pass());
+    }
+
+    /// Changes the output filter, throwing away anything that hasn't been read yet
+    pub fn set_high_pass(&mut self, high_pass: HighPass) {
+        self.output = SampleOutput::new(self.sample_rate(), self.resampling(), high_pass);
+    }
+
+    /// Number of stereo samples waiting to be read
+    pub fn samples_available(&self) -> usize {
+        self.output.available()
+    }
+
+    /// Fills `out` with interleaved left and right samples between -1.0 and 1.0. Returns how many
+    /// stereo samples were written.
+    pub fn read_samples(&mut self, out: &mut [f32]) -> usize {
+        let mut written = 0;
+        for frame in out.chunks_exact_mut(2) {
+            let Some(sample) = self.output.pop() else {
+                break;
+            };
+            frame.copy_from_slice(&sample);
+            written += 1;
+        }
+        written
+    }
+
+    /// Same as `read_samples` but as signed 16 bit PCM
+    pub fn read_samples_i16(&mut self, out: &mut [i16]) -> usize {
+        let mut written = 0;
+        for frame in out.chunks_exact_mut(2) {
+            let Some(sample) = self.output.pop() else {
+                break;
+            };
+            for (out, sample) in frame.iter_mut().zip(sample) {
+                *out = (sample.clamp(-1.0, 1.0) * f32::from(i16::MAX)) as i16;
+            }
+            written += 1;
+        }
+        written
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            WAVE_RAM_START..=WAVE_RAM_END => self.wave.ram[self.wave.ram_index(addr)],
+            NR52 => {
+                0x70 | (u8::from(self.enabled) << 7)
+                    | (u8::from(self.noise.enabled) << 3)
+                    | (u8::from(self.wave.enabled) << 2)
+                    | (u8::from(self.pulse2.enabled) << 1)
+                    | u8::from(self.pulse1.enabled)
+            }
+            NR10..WAVE_RAM_START => {
+                let index = usize::from(addr - NR10);
+                self.registers[index] | READ_MASKS[index]
+            }
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            WAVE_RAM_START..=WAVE_RAM_END => {
+                let index = self.wave.ram_index(addr);
+                self.wave.ram[index] = value;
+            }
+            NR52 => self.set_power(value.is_bit_set(7)),
+            // Everything else is read only while the APU is off
+            _ if !self.enabled => return,
+            NR10..=NR14 => self.pulse1.write(addr - NR10, value),
+            NR21..=NR24 => self.pulse2.write(addr - NR21 + 1, value),
+            NR30..=NR34 => self.wave.write(addr - NR30, value),
+            NR41..=NR44 => self.noise.write(addr - NR41 + 1, value),
+            NR50 | NR51 => {}
+            _ => return,
+        }
+
+        if (NR10..NR52).contains(&addr) {
+            self.registers[usize::from(addr - NR10)] = value;
+        }
+    }
+
+    /// Writes NRx1 on models before the CGB, where the length counters can still be loaded while
+    /// the APU is off. The rest of the register stays read only.
+    pub fn write_length(&mut self, addr: u16, value: u8) {
+        if self.enabled {
+            return self.write(addr, value);
+        }
+
+        match addr {
+            NR11 => self.pulse1.length.load(64, u16::from(value & 0x3F)),
+            NR21 => self.pulse2.length.load(64, u16::from(value & 0x3F)),
+            NR31 => self.wave.length.load(256, u16::from(value)),
+            NR41 => self.noise.length.load(64, u16::from(value & 0x3F)),
+            _ => {}
+        }
+    }
+
+    /// Sets a register without the side effects of a CPU write. Powering on doesn't reset
+    /// anything, NRx4 never triggers and the bottom bits of NR52 say which channels are on.
+    pub fn set_register(&mut self, addr: u16, value: u8) {
+        let channel_value = match addr {
+            NR14 | NR24 | NR34 | NR44 => value & 0x7F,
+            _ => value,
+        };
+
+        match addr {
+            NR52 => {
+                self.enabled = value.is_bit_set(7);
+                self.pulse1.enabled = value.is_bit_set(0);
+                self.pulse2.enabled = value.is_bit_set(1);
+                self.wave.enabled = value.is_bit_set(2);
+                self.noise.enabled = value.is_bit_set(3);
+            }
+            NR10..=NR14 => self.pulse1.write(addr - NR10, channel_value),
+            NR21..=NR24 => self.pulse2.write(addr - NR21 + 1, channel_value),
+            NR30..=NR34 => self.wave.write(addr - NR30, channel_value),
+            NR41..=NR44 => self.noise.write(addr - NR41 + 1, channel_value),
+            NR50 | NR51 => {}
+            _ => return self.write(addr, value),
+        }
+
+        if (NR10..NR52).contains(&addr) {
+            self.registers[usize::from(addr - NR10)] = value;
+        }
+    }
+
+    fn set_power(&mut self, on: bool) {
+        if on == self.enabled {
+            return;
+        }
+
+        if on {
+            self.frame_step = 0;
+        } else {
+            // Every register is cleared but wave RAM is left alone
+            let ram = self.wave.ram;
+            self.registers = [0; 0x20];
+            self.pulse1 = Pulse::with_sweep();
+            self.pulse2 = Pulse::default();
+            self.wave = Wave {
+                ram,
+                ..Default::default()
+            };
+            self.noise = Noise::default();
+        }
+        self.enabled = on;
+    }
+
+    /// Advances by normal speed M-cycles. The frame sequencer is clocked when bit 12 of the
+    /// timer's counter falls, bit 13 in double speed mode, so it takes the counter as it is now.
+    pub fn tick(&mut self, m_cycles: u32, div_counter: u16, double_speed: bool) {
+        let div_bit = div_counter.is_bit_set(if double_speed { 13 } else { 12 });
+        if self.enabled && self.div_bit && !div_bit {
+            self.step_frame_sequencer();
+        }
+        self.div_bit = div_bit;
+
+        for _ in 0..m_cycles {
+            if self.enabled {
+                self.pulse1.step(4);
+                self.pulse2.step(4);
+                self.wave.step(4);
+                self.noise.step(4);
+            }
+            let (frame, dacs_enabled) = self.mix();
+            self.output.push(frame, dacs_enabled);
+        }
+    }
+
+    /// ┌──────┬─────┬─────┬─────┬─────┬─────┬─────┬─────┬─────┐
+    /// │ Step │  0  │  1  │  2  │  3  │  4  │  5  │  6  │  7  │
+    /// ├──────┼─────┼─────┼─────┼─────┼─────┼─────┼─────┼─────┤
+    /// │      │ Len │     │ Len │     │ Len │     │ Len │     │
+    /// │      │     │     │Sweep│     │     │     │Sweep│ Env │
+    /// └──────┴─────┴─────┴─────┴─────┴─────┴─────┴─────┴─────┘
+    fn step_frame_sequencer(&mut self) {
+        if self.frame_step.is_multiple_of(2) {
+            if self.pulse1.length.clock() {
+                self.pulse1.enabled = false;
+            }
+            if self.pulse2.length.clock() {
+                self.pulse2.enabled = false;
+            }
+            if self.wave.length.clock() {
+                self.wave.enabled = false;
+            }
+            if self.noise.length.clock() {
+                self.noise.enabled = false;
+            }
+        }
+
+        if self.frame_step == 2 || self.frame_step == 6 {
+            self.pulse1.clock_sweep();
+        }
+
+        if self.frame_step == 7 {
+            self.pulse1.envelope.clock();
+            self.pulse2.envelope.clock();
+            self.noise.envelope.clock();
+        }
+
+        self.frame_step = (self.frame_step + 1) % 8;
+    }
+
+    /// ┌──────┬──────┬──────┬──────┬──────┬──────┬──────┬──────┬──────┐
+    /// │ NR51 │  7   │  6   │  5   │  4   │  3   │  2   │  1   │  0   │
+    /// ├──────┼──────┼──────┼──────┼──────┼──────┼──────┼──────┼──────┤
+    /// │      │CH4 L │CH3 L │CH2 L │CH1 L │CH4 R │CH3 R │CH2 R │CH1 R │
+    /// └──────┴──────┴──────┴──────┴──────┴──────┴──────┴──────┴──────┘
+    ///
+    /// Also returns whether any DAC is on, the output capacitor only charges while one is.
+    fn mix(&self) -> ([f32; 2], bool) {
+        if !self.enabled {
+            return ([0.0; 2], false);
+        }
+
+        let outputs = [
+            self.pulse1.output(),
+            self.pulse2.output(),
+            self.wave.output(),
+            self.noise.output(),
+        ];
+        let panning = self.registers[usize::from(NR51 - NR10)];
+        let volume = self.registers[usize::from(NR50 - NR10)];
+
+        let dacs_enabled = outputs.iter().any(Option::is_some);
+        let mut frame = [0.0; 2];
+        for (channel, output) in outputs.into_iter().enumerate() {
+            // Each DAC maps 0 to 1.0 and 15 to -1.0, and outputs nothing while it's off
+            let Some(output) = output else {
+                continue;
+            };
+            let analog = 1.0 - f32::from(output) / 7.5;
+
+            if panning.is_bit_set(channel as u32 + 4) {
+                frame[0] += analog;
+            }
+            if panning.is_bit_set(channel as u32) {
+                frame[1] += analog;
+            }
+        }
+
+        let left_volume = f32::from((volume >> 4) & 0x07) + 1.0;
+        let right_volume = f32::from(volume & 0x07) + 1.0;
+        // Four channels at full volume come out at 1.0
+        let frame = [
+            frame[0] * left_volume / 32.0,
+            frame[1] * right_volume / 32.0,
+        ];
+        (frame, dacs_enabled)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        apu::{
+            Apu, CLOCK_RATE, NR10, NR11, NR12, NR13, NR14, NR21, NR22, NR23, NR24, NR30, NR32,
+            NR33, NR34, NR41, NR42, NR43, NR44, NR50, NR51, NR52, WAVE_RAM_START,
+        },
+        audio::Resampling,
+    };
+
+    fn powered_on() -> Apu {
+        let mut apu = Apu::default();
+        // Averaging keeps the levels exact, band-limited steps ring around them
+        apu.set_resampling(Resampling::Average);
+        apu.write(NR52, 0x80);
+        apu.write(NR50, 0x77);
+        apu
+    }
+
+    /// Runs a frame sequencer step by making DIV bit 12 fall
+    fn frame_sequencer_step(apu: &mut Apu) {
+        apu.tick(1, 0x1000, false);
+        apu.tick(1, 0x0000, false);
+    }
+
+    #[test]
+    fn registers() {
+        let mut apu = Apu::default();
+        assert_eq!(apu.read(NR52), 0x70);
+
+        // Nothing but NR52 and wave RAM can be written while it's off
+        apu.write(NR11, 0xFF);
+        apu.write(WAVE_RAM_START, 0x12);
+        assert_eq!(apu.read(NR11), 0x3F);
+        assert_eq!(apu.read(WAVE_RAM_START), 0x12);
+
+        apu.write(NR52, 0x80);
+        apu.write(NR11, 0x80);
+        apu.write(NR13, 0x12);
+        apu.write(NR50, 0x35);
+        assert_eq!(apu.read(NR11), 0xBF);
+        assert_eq!(apu.read(NR13), 0xFF);
+        assert_eq!(apu.read(NR50), 0x35);
+        assert_eq!(apu.read(0xFF15), 0xFF);
+        assert_eq!(apu.read(0xFF27), 0xFF);
+
+        apu.write(NR12, 0xF0);
+        apu.write(NR14, 0x80);
+        assert_eq!(apu.read(NR52), 0xF1);
+
+        // Turning the DAC off turns the channel off
+        apu.write(NR12, 0x00);
+        assert_eq!(apu.read(NR52), 0xF0);
+
+        apu.write(NR52, 0x00);
+        assert_eq!(apu.read(NR50), 0x00);
+        assert_eq!(apu.read(WAVE_RAM_START), 0x12);
+    }
+
+    #[test]
+    fn length_writable_while_off() {
+        // Before the CGB the length counter loads while the APU is off, the duty doesn't
+        let mut apu = Apu::default();
+        apu.write_length(NR21, 0xFF);
+        assert_eq!(apu.read(NR21), 0x3F);
+
+        // One step of length left, so it isn't reloaded on trigger
+        apu.write(NR52, 0x80);
+        apu.write(NR22, 0xF0);
+        apu.write(NR24, 0xC0);
+        assert_eq!(apu.read(NR52) & 0x0F, 0b0010);
+        frame_sequencer_step(&mut apu);
+        assert_eq!(apu.read(NR52) & 0x0F, 0b0000);
+
+        // A CGB drops the write like any other
+        let mut apu = Apu::default();
+        apu.write(NR21, 0xFF);
+        apu.write(NR52, 0x80);
+        apu.write(NR22, 0xF0);
+        apu.write(NR24, 0xC0);
+        frame_sequencer_step(&mut apu);
+        assert_eq!(apu.read(NR52) & 0x0F, 0b0010);
+    }
+
+    #[test]
+    fn length_and_sweep() {
+        let mut apu = powered_on();
+
+        // One step of length left
+        apu.write(NR22, 0xF0);
+        apu.write(NR21, 0x3F);
+        apu.write(NR24, 0xC0);
+        assert_eq!(apu.read(NR52) & 0x0F, 0b0010);
+        frame_sequencer_step(&mut apu);
+        assert_eq!(apu.read(NR52) & 0x0F, 0b0000);
+
+        // Length isn't clocked on odd steps
+        apu.write(NR21, 0x3F);
+        apu.write(NR24, 0xC0);
+        frame_sequencer_step(&mut apu);
+        assert_eq!(apu.read(NR52) & 0x0F, 0b0010);
+
+        // The overflow check on trigger turns channel 1 straight back off
+        apu.write(NR12, 0xF0);
+        apu.write(NR10, 0x01);
+        apu.write(NR13, 0xFF);
+        apu.write(NR14, 0x87);
+        assert_eq!(apu.read(NR52) & 0x01, 0);
+
+        // Sweeping up from 0x400 by half gets to 0x600, then the check after it overflows
+        apu.write(NR10, 0x11);
+        apu.write(NR13, 0x00);
+        apu.write(NR14, 0x84);
+        assert_eq!(apu.read(NR52) & 0x01, 1);
+        for _ in 0..8 {
+            frame_sequencer_step(&mut apu);
+        }
+        assert_eq!(apu.read(NR52) & 0x01, 0);
+    }
+
+    #[test]
+    fn pulse_tone() {
+        let mut apu = powered_on();
+        apu.set_sample_rate(CLOCK_RATE / 8);
+
+        // 1024 Hz at 50% duty, only on the right
+        apu.write(NR51, 0x02);
+        apu.write(NR21, 0x80);
+        apu.write(NR22, 0xF0);
+        apu.write(NR23, 0x80);
+        apu.write(NR24, 0x87);
+        apu.tick(CLOCK_RATE / 16, 0, false);
+
+        let mut samples = vec![0.0; 2 * 8192];
+        assert_eq!(apu.samples_available(), 8192);
+        assert_eq!(apu.read_samples(&mut samples), 8192);
+        assert_eq!(apu.samples_available(), 0);
+
+        let (left, right): (Vec<f32>, Vec<f32>) =
+            samples.chunks(2).map(|frame| (frame[0], frame[1])).unzip();
+        assert!(left.iter().all(|&s| s == 0.0));
+
+        let max = right.iter().copied().fold(f32::MIN, f32::max);
+        let min = right.iter().copied().fold(f32::MAX, f32::min);
+        assert_eq!((max, min), (0.25, -0.25));
+
+        // Two edges a cycle over 1/16th of a second
+        let edges = right
+            .windows(2)
+            .filter(|pair| (pair[0] > 0.0) != (pair[1] > 0.0))
+            .count();
+        assert_eq!(edges, 2 * 1024 / 16);
+    }
+
+    #[test]
+    fn wave_and_noise() {
+        let mut apu = powered_on();
+        apu.set_sample_rate(CLOCK_RATE / 2);
+        apu.write(NR51, 0x44);
+
+        for i in 0..16 {
+            apu.write(WAVE_RAM_START + i, 0xF0);
+        }
+        apu.write(NR30, 0x80);
+        apu.write(NR32, 0x20);
+        apu.write(NR33, 0x00);
+        apu.write(NR34, 0x87);
+        apu.tick(4096, 0, false);
+
+        let mut samples = vec![0i16; 2 * 2048];
+        assert_eq!(apu.read_samples_i16(&mut samples), 2048);
+        assert_eq!(samples[0], samples[1]);
+        assert!(samples.contains(&(-i16::MAX / 4)));
+        assert!(samples.contains(&(i16::MAX / 4)));
+
+        // The LFSR is deterministic, so the same settings make the same noise
+        let noise = || {
+            let mut apu = powered_on();
+            apu.write(NR51, 0x88);
+            apu.write(NR41, 0x00);
+            apu.write(NR42, 0xF0);
+            apu.write(NR43, 0x08);
+            apu.write(NR44, 0x80);
+            apu.tick(4096, 0, false);
+
+            let mut samples = vec![0.0; 2 * 4096];
+            let written = apu.read_samples(&mut samples);
+            samples.truncate(written * 2);
+            samples
+        };
+        let first = noise();
+        assert!(first.iter().any(|&s| s > 0.0) && first.iter().any(|&s| s < 0.0));
+        assert_eq!(first, noise());
+    }
+}
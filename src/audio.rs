@@ -0,0 +1,380 @@
+use std::{collections::VecDeque, f64::consts::PI, sync::LazyLock};
+
+use crate::apu::CLOCK_RATE;
+
+/// Buffered samples past this many seconds' worth are dropped, oldest first
+const MAX_BUFFERED_SECONDS: usize = 1;
+
+/// Output samples each band-limited step is spread over
+const STEP_WIDTH: usize = 32;
+/// Sub-sample positions a step can start at
+const STEP_PHASES: usize = 64;
+/// Fraction of the output Nyquist frequency that's let through
+const STEP_CUTOFF: f64 = 0.9;
+
+/// Subdivisions used to integrate the impulse over each output sample
+const STEP_SUBDIVISIONS: usize = 16;
+
+/// A windowed sinc impulse centred on 0
+fn impulse(x: f64) -> f64 {
+    let half = (STEP_WIDTH / 2) as f64;
+    if x.abs() >= half {
+        return 0.0;
+    }
+
+    let t = PI * STEP_CUTOFF * x;
+    let sinc = if t == 0.0 { 1.0 } else { t.sin() / t };
+    // Blackman window
+    let window = 0.42 + 0.5 * (PI * x / half).cos() + 0.08 * (2.0 * PI * x / half).cos();
+    STEP_CUTOFF * sinc * window
+}
+
+/// How much a band-limited step rises over each output sample, for each phase. Each tap is the
+/// impulse integrated over that sample, sampling it at a point instead would boost the highs. Each
+/// phase is normalised to sum to 1 so a step always settles at exactly its height.
+static STEP_KERNEL: LazyLock<Vec<[f32; STEP_WIDTH]>> = LazyLock::new(|| {
+    let half = (STEP_WIDTH / 2) as f64;
+    let h = 1.0 / STEP_SUBDIVISIONS as f64;
+
+    (0..STEP_PHASES)
+        .map(|phase| {
+            let offset = phase as f64 / STEP_PHASES as f64;
+            let mut taps = [0.0; STEP_WIDTH];
+            for (k, tap) in taps.iter_mut().enumerate() {
+                // Simpson's rule over the sample
+                let from = k as f64 - offset - half;
+                *tap = (0..=STEP_SUBDIVISIONS)
+                    .map(|i| {
+                        let weight = match i {
+                            0 => 1.0,
+                            i if i == STEP_SUBDIVISIONS => 1.0,
+                            i if i % 2 == 1 => 4.0,
+                            _ => 2.0,
+                        };
+                        weight * impulse(from + i as f64 * h)
+                    })
+                    .sum::<f64>()
+                    * h
+                    / 3.0;
+            }
+
+            let sum: f64 = taps.iter().sum();
+            taps.map(|tap| (tap / sum) as f32)
+        })
+        .collect()
+});
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Resampling {
+    /// Averages over each output sample period. Cheap, but harmonics past the output Nyquist
+    /// frequency alias back down.
+    Average,
+    /// Adds every change in level as a band-limited step, so nothing past the cutoff gets through
+    #[default]
+    BandLimited,
+}
+
+/// The capacitor on the audio output that removes the DC offset the DACs add
+// https://gbdev.io/pandocs/Audio_details.html#obscure-behavior
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HighPass {
+    /// Keeps the DC offset, the raw mixer output
+    #[default]
+    Off,
+    Dmg,
+    /// MGB and CGB leak charge faster
+    Cgb,
+}
+
+impl HighPass {
+    /// Charge the capacitor keeps per output sample, None if there's no filter
+    fn charge_factor(self, sample_rate: u32) -> Option<f32> {
+        let per_t_cycle: f64 = match self {
+            HighPass::Off => return None,
+            HighPass::Dmg => 0.999958,
+            HighPass::Cgb => 0.998943,
+        };
+        let t_cycles_per_sample = f64::from(CLOCK_RATE * 4) / f64::from(sample_rate);
+        Some(per_t_cycle.powf(t_cycles_per_sample) as f32)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Resampler {
+    Average {
+        phase: u32,
+        sum: [f32; 2],
+        count: u32,
+    },
+    BandLimited {
+        /// M-cycles pushed so far
+        clock: u64,
+        previous: [f32; 2],
+        /// Running total of the deltas, the current output level
+        level: [f32; 2],
+        /// Steps that have been added but not summed into output samples yet
+        deltas: VecDeque<[f32; 2]>,
+        /// Output sample the front of `deltas` is for
+        next_index: u64,
+    },
+}
+
+impl Resampler {
+    fn new(resampling: Resampling) -> Self {
+        match resampling {
+            Resampling::Average => Resampler::Average {
+                phase: 0,
+                sum: [0.0; 2],
+                count: 0,
+            },
+            Resampling::BandLimited => Resampler::BandLimited {
+                clock: 0,
+                previous: [0.0; 2],
+                level: [0.0; 2],
+                deltas: VecDeque::new(),
+                next_index: 0,
+            },
+        }
+    }
+
+    /// Takes a frame at the native rate and adds any samples it completes to `out`
+    fn push(&mut self, frame: [f32; 2], sample_rate: u32, out: &mut Vec<[f32; 2]>) {
+        match self {
+            Resampler::Average { phase, sum, count } => {
+                sum[0] += frame[0];
+                sum[1] += frame[1];
+                *count += 1;
+
+                *phase += sample_rate;
+                if *phase < CLOCK_RATE {
+                    return;
+                }
+                *phase -= CLOCK_RATE;
+
+                let n = *count as f32;
+                out.push([sum[0] / n, sum[1] / n]);
+                *sum = [0.0; 2];
+                *count = 0;
+            }
+            Resampler::BandLimited {
+                clock,
+                previous,
+                level,
+                deltas,
+                next_index,
+            } => {
+                let rate = u64::from(sample_rate);
+                let clock_rate = u64::from(CLOCK_RATE);
+
+                if frame != *previous {
+                    let position = *clock * rate;
+                    let start = (position / clock_rate - *next_index) as usize;
+                    let phase = (position % clock_rate * STEP_PHASES as u64 / clock_rate) as usize;
+
+                    if deltas.len() < start + STEP_WIDTH {
+                        deltas.resize(start + STEP_WIDTH, [0.0; 2]);
+                    }
+                    let delta = [frame[0] - previous[0], frame[1] - previous[1]];
+                    for (k, weight) in STEP_KERNEL[phase].iter().enumerate() {
+                        deltas[start + k][0] += delta[0] * weight;
+                        deltas[start + k][1] += delta[1] * weight;
+                    }
+                    *previous = frame;
+                }
+                *clock += 1;
+
+                // Later steps can only land on samples from here on, so everything before is done
+                let ready = *clock * rate / clock_rate;
+                while *next_index < ready {
+                    let delta = deltas.pop_front().unwrap_or_default();
+                    level[0] += delta[0];
+                    level[1] += delta[1];
+                    out.push(*level);
+                    *next_index += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Turns the mixer output, a stereo frame per M-cycle, into buffered samples at the host's rate
+#[derive(Debug, Clone)]
+pub struct SampleOutput {
+    sample_rate: u32,
+    resampling: Resampling,
+    high_pass: HighPass,
+    resampler: Resampler,
+    charge_factor: Option<f32>,
+    capacitors: [f32; 2],
+    /// Whether any DAC was on in the M-cycles since the last samples came out
+    dacs_latched: bool,
+    /// Samples from the resampler that haven't been filtered yet
+    resampled: Vec<[f32; 2]>,
+    samples: VecDeque<[f32; 2]>,
+}
+
+impl SampleOutput {
+    pub fn new(sample_rate: u32, resampling: Resampling, high_pass: HighPass) -> Self {
+        let sample_rate = sample_rate.clamp(1, CLOCK_RATE);
+        Self {
+            sample_rate,
+            resampling,
+            high_pass,
+            resampler: Resampler::new(resampling),
+            charge_factor: high_pass.charge_factor(sample_rate),
+            capacitors: [0.0; 2],
+            dacs_latched: false,
+            resampled: Vec::new(),
+            samples: VecDeque::new(),
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn resampling(&self) -> Resampling {
+        self.resampling
+    }
+
+    pub fn high_pass(&self) -> HighPass {
+        self.high_pass
+    }
+
+    /// Number of stereo samples waiting to be read
+    pub fn available(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn pop(&mut self) -> Option<[f32; 2]> {
+        self.samples.pop_front()
+    }
+
+    /// Adds the mixer output for an M-cycle. The capacitor only charges for samples a DAC was on
+    /// for at some point.
+    pub fn push(&mut self, frame: [f32; 2], dacs_enabled: bool) {
+        self.dacs_latched |= dacs_enabled;
+        self.resampler
+            .push(frame, self.sample_rate, &mut self.resampled);
+        if self.resampled.is_empty() {
+            return;
+        }
+
+        let dacs_enabled = std::mem::take(&mut self.dacs_latched);
+        for mut sample in self.resampled.drain(..) {
+            if let Some(factor) = self.charge_factor {
+                for (value, capacitor) in sample.iter_mut().zip(&mut self.capacitors) {
+                    if dacs_enabled {
+                        let out = *value - *capacitor;
+                        *capacitor = *value - out * factor;
+                        *value = out;
+                    } else {
+                        *value = 0.0;
+                    }
+                }
+            }
+
+            if self.samples.len() >= self.sample_rate as usize * MAX_BUFFERED_SECONDS {
+                self.samples.pop_front();
+            }
+            self.samples.push_back(sample);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+
+    use crate::{
+        apu::CLOCK_RATE,
+        audio::{HighPass, Resampling, SampleOutput},
+    };
+
+    /// Amplitude of the `frequency` component of the left channel
+    fn amplitude(samples: &[[f32; 2]], frequency: f64, sample_rate: f64) -> f64 {
+        let (mut re, mut im) = (0.0, 0.0);
+        for (n, sample) in samples.iter().enumerate() {
+            let angle = 2.0 * PI * frequency * n as f64 / sample_rate;
+            re += f64::from(sample[0]) * angle.cos();
+            im -= f64::from(sample[0]) * angle.sin();
+        }
+        2.0 * (re * re + im * im).sqrt() / samples.len() as f64
+    }
+
+    /// A second of an 8192 Hz square wave between -1.0 and 1.0
+    fn square_wave(resampling: Resampling) -> Vec<[f32; 2]> {
+        let mut output = SampleOutput::new(48_000, resampling, HighPass::Off);
+        for clock in 0..CLOCK_RATE {
+            let level = if clock % 128 < 64 { 1.0 } else { -1.0 };
+            output.push([level; 2], true);
+        }
+        std::iter::from_fn(|| output.pop()).collect()
+    }
+
+    #[test]
+    fn band_limited_square_wave() {
+        let samples = square_wave(Resampling::BandLimited);
+        assert_eq!(samples.len(), 48_000);
+
+        // The fundamental of a square wave is 4/pi times its amplitude
+        let fundamental = amplitude(&samples, 8192.0, 48_000.0);
+        assert!((fundamental - 4.0 / PI).abs() < 0.02, "{fundamental}");
+
+        // The 5th harmonic at 40960 Hz would alias to 7040 Hz
+        let alias = amplitude(&samples, 7040.0, 48_000.0);
+        assert!(alias < 1e-3 * fundamental, "{alias}");
+    }
+
+    #[test]
+    fn averaging_aliases() {
+        let samples = square_wave(Resampling::Average);
+        assert_eq!(samples.len(), 48_000);
+
+        let fundamental = amplitude(&samples, 8192.0, 48_000.0);
+        let alias = amplitude(&samples, 7040.0, 48_000.0);
+        assert!(alias > 1e-2 * fundamental, "{alias}");
+    }
+
+    #[test]
+    fn high_pass() {
+        let decay = |high_pass| {
+            let mut output = SampleOutput::new(48_000, Resampling::Average, high_pass);
+            for _ in 0..CLOCK_RATE / 10 {
+                output.push([1.0; 2], true);
+            }
+            let samples: Vec<_> = std::iter::from_fn(|| output.pop()).collect();
+            (samples[0][0], samples[100][0], samples[4700][0])
+        };
+
+        assert_eq!(decay(HighPass::Off), (1.0, 1.0, 1.0));
+
+        let (dmg_first, dmg_100, dmg_last) = decay(HighPass::Dmg);
+        let (cgb_first, cgb_100, cgb_last) = decay(HighPass::Cgb);
+        assert_eq!((dmg_first, cgb_first), (1.0, 1.0));
+        assert!(cgb_100 < dmg_100 && dmg_100 < 1.0);
+        assert!(dmg_last.abs() < 1e-3 && cgb_last.abs() < 1e-3);
+
+        // Nothing comes out while every DAC is off
+        let mut output = SampleOutput::new(48_000, Resampling::Average, HighPass::Dmg);
+        for _ in 0..100 {
+            output.push([1.0; 2], false);
+        }
+        assert_eq!(output.pop(), Some([0.0; 2]));
+    }
+
+    #[test]
+    fn high_pass_dac_toggled_mid_sample() {
+        // A DAC going on and off within each sample still counts as on for all of it
+        let mut output = SampleOutput::new(48_000, Resampling::Average, HighPass::Dmg);
+        for clock in 0..CLOCK_RATE / 10 {
+            output.push([1.0; 2], clock % 2 == 0);
+        }
+        let samples: Vec<_> = std::iter::from_fn(|| output.pop()).collect();
+
+        assert_eq!(samples[0][0], 1.0);
+        for pair in samples.windows(2) {
+            assert!(pair[1][0] > 0.0 && pair[1][0] <= pair[0][0], "{pair:?}");
+        }
+    }
+}
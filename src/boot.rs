@@ -0,0 +1,250 @@
+use crate::registers::Registers;
+
+pub const BOOT_ROM_DISABLE: u16 = 0xFF50;
+pub const DMG_BOOT_ROM_SIZE: usize = 0x100;
+pub const CGB_BOOT_ROM_SIZE: usize = 0x900;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Model {
+    #[default]
+    Dmg,
+    Mgb,
+    Sgb,
+    Cgb,
+    Agb,
+}
+
+impl Model {
+    pub fn is_cgb(&self) -> bool {
+        matches!(self, Model::Cgb | Model::Agb)
+    }
+}
+
+/// The DMG boot ROM is mapped over 0x0000-0x00FF. The CGB one is bigger and leaves a hole for
+/// the cartridge header, so it's also mapped over 0x0200-0x08FF.
+#[derive(Debug, Clone)]
+pub struct BootRom {
+    data: Vec<u8>,
+}
+
+impl BootRom {
+    pub fn new(data: Vec<u8>) -> anyhow::Result<Self> {
+        if data.len() != DMG_BOOT_ROM_SIZE && data.len() != CGB_BOOT_ROM_SIZE {
+            anyhow::bail!(
+                "Boot ROM must be {} or {} bytes, got {}",
+                DMG_BOOT_ROM_SIZE,
+                CGB_BOOT_ROM_SIZE,
+                data.len()
+            );
+        }
+
+        Ok(Self { data })
+    }
+
+    pub fn maps(&self, addr: u16) -> bool {
+        let addr = usize::from(addr);
+        addr < DMG_BOOT_ROM_SIZE || ((0x200..self.data.len()).contains(&addr))
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        self.data[usize::from(addr)]
+    }
+}
+
+// https://gbdev.io/pandocs/Power_Up_Sequence.html#cpu-registers
+pub fn post_boot_registers(model: Model, header_checksum: u8) -> Registers {
+    // The DMG boot ROM leaves H and C set unless the header checksum happens to be 0
+    let dmg_flags = if header_checksum == 0 { 0x80 } else { 0xB0 };
+
+    let (af, bc, de, hl) = match model {
+        Model::Dmg => (0x0100 | dmg_flags, 0x0013, 0x00D8, 0x014D),
+        Model::Mgb => (0xFF00 | dmg_flags, 0x0013, 0x00D8, 0x014D),
+        Model::Sgb => (0x0100, 0x0014, 0x0000, 0xC060),
+        Model::Cgb => (0x1180, 0x0000, 0xFF56, 0x000D),
+        Model::Agb => (0x1100, 0x0100, 0xFF56, 0x000D),
+    };
+
+    Registers {
+        af,
+        bc,
+        de,
+        hl,
+        sp: 0xFFFE,
+        pc: 0x0100,
+    }
+}
+
+/// The internal timer counter when the boot ROM hands over, DIV is its upper byte
+pub fn post_boot_div_counter(model: Model) -> u16 {
+    match model {
+        // The SGB's varies with how long its boot ROM spends sending the header over, so use the DMG's
+        Model::Dmg | Model::Mgb | Model::Sgb => 0xABCC,
+        Model::Cgb | Model::Agb => 0x1EA0,
+    }
+}
+
+/// The I/O registers as the boot ROM leaves them
+// https://gbdev.io/pandocs/Power_Up_Sequence.html#hardware-registers
+pub fn post_boot_io(model: Model) -> [(u16, u8); 39] {
+    // Only CGBs have the fast clock bit, which is left set
+    let sc = if model.is_cgb() { 0x7F } else { 0x7E };
+    // The SGB doesn't play the boot sound, so channel 1 was never started
+    let nr52 = if model == Model::Sgb { 0xF0 } else { 0xF1 };
+    let dma = if model.is_cgb() { 0x00 } else { 0xFF };
+
+    [
+        (0xFF00, 0xCF), // P1
+        (0xFF01, 0x00), // SB
+        (0xFF02, sc),   // SC
+        (0xFF05, 0x00), // TIMA
+        (0xFF06, 0x00), // TMA
+        (0xFF07, 0xF8), // TAC
+        (0xFF0F, 0xE1), // IF
+        (0xFF26, nr52), // NR52
+        (0xFF10, 0x80), // NR10
+        (0xFF11, 0xBF), // NR11
+        (0xFF12, 0xF3), // NR12
+        (0xFF13, 0xFF), // NR13
+        (0xFF14, 0xBF), // NR14
+        (0xFF16, 0x3F), // NR21
+        (0xFF17, 0x00), // NR22
+        (0xFF18, 0xFF), // NR23
+        (0xFF19, 0xBF), // NR24
+        (0xFF1A, 0x7F), // NR30
+        (0xFF1B, 0xFF), // NR31
+        (0xFF1C, 0x9F), // NR32
+        (0xFF1D, 0xFF), // NR33
+        (0xFF1E, 0xBF), // NR34
+        (0xFF20, 0xFF), // NR41
+        (0xFF21, 0x00), // NR42
+        (0xFF22, 0x00), // NR43
+        (0xFF23, 0xBF), // NR44
+        (0xFF24, 0x77), // NR50
+        (0xFF25, 0xF3), // NR51
+        (0xFF40, 0x91), // LCDC
+        (0xFF41, 0x85), // STAT
+        (0xFF42, 0x00), // SCY
+        (0xFF43, 0x00), // SCX
+        (0xFF45, 0x00), // LYC
+        (0xFF46, dma),  // DMA
+        (0xFF47, 0xFC), // BGP
+        (0xFF48, 0x00), // OBP0
+        (0xFF49, 0x00), // OBP1
+        (0xFF4A, 0x00), // WY
+        (0xFF4B, 0x00), // WX
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        boot::{BootRom, Model},
+        cartridge::{Cartridge, HEADER_CHECKSUM, header_checksum, test_rom},
+        cpu::Cpu,
+        memory::Memory,
+    };
+
+    #[test]
+    fn boot_rom_overlay() {
+        let mut memory = Memory::default();
+        memory.memory[0x0000] = 0x11;
+        memory.memory[0x0100] = 0x22;
+        memory.boot_rom = Some(BootRom::new(vec![0xAA; 0x100]).expect("Valid boot ROM"));
+
+        assert_eq!(memory.get_byte(0x0000).expect("Unable to get byte"), 0xAA);
+        assert_eq!(memory.get_byte(0x0100).expect("Unable to get byte"), 0x22);
+
+        memory.set_byte(0xFF50, 0x01);
+        assert!(memory.boot_rom.is_none());
+        assert_eq!(memory.get_byte(0x0000).expect("Unable to get byte"), 0x11);
+    }
+
+    #[test]
+    fn cgb_boot_rom_leaves_header_visible() {
+        let boot_rom = BootRom::new(vec![0; 0x900]).expect("Valid boot ROM");
+
+        assert!(boot_rom.maps(0x00FF));
+        assert!(!boot_rom.maps(0x0100));
+        assert!(!boot_rom.maps(0x014D));
+        assert!(boot_rom.maps(0x0200));
+        assert!(boot_rom.maps(0x08FF));
+        assert!(!boot_rom.maps(0x0900));
+        assert!(BootRom::new(vec![0; 0x200]).is_err());
+    }
+
+    #[test]
+    fn skip_boot() {
+        let mut cpu = Cpu::default();
+        cpu.memory.memory[0x014D] = 0x42;
+        cpu.skip_boot();
+
+        assert_eq!(cpu.registers.af, 0x01B0);
+        assert_eq!(cpu.registers.bc, 0x0013);
+        assert_eq!(cpu.registers.de, 0x00D8);
+        assert_eq!(cpu.registers.hl, 0x014D);
+        assert_eq!(cpu.registers.sp, 0xFFFE);
+        assert_eq!(cpu.registers.pc, 0x0100);
+        assert_eq!(
+            cpu.memory.get_byte(0xFF40).expect("Unable to get byte"),
+            0x91
+        );
+        assert_eq!(cpu.memory.timer.read(0xFF04), 0xAB);
+
+        let mut cpu = Cpu::default();
+        cpu.memory.model = Model::Cgb;
+        cpu.skip_boot();
+
+        assert_eq!(cpu.registers.af, 0x1180);
+        assert_eq!(cpu.registers.de, 0xFF56);
+        assert_eq!(cpu.registers.hl, 0x000D);
+    }
+
+    #[test]
+    fn skip_boot_runs_cartridge() {
+        let mut rom = test_rom(0x00, 0x00, 0x00);
+        // nop; jp $0150
+        rom[0x0100..0x0104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+        rom[HEADER_CHECKSUM] = header_checksum(&rom);
+        let cartridge = Cartridge::from_bytes(rom).expect("Valid ROM");
+        let mut cpu = Cpu::new(Memory::with_cartridge(cartridge));
+        cpu.skip_boot();
+
+        cpu.run_num_instructions(2)
+            .expect("Unable to process CPU instructions");
+        assert_eq!(cpu.registers.pc, 0x0150);
+        assert_eq!(
+            cpu.memory.get_byte(0xFF46).expect("Unable to get byte"),
+            0xFF
+        );
+    }
+
+    #[test]
+    fn post_boot_io_per_model() {
+        // (CGB flag, SGB flag, old licensee) -> (SC, NR52, DMA)
+        let cases = [
+            ((0x00, 0x00, 0x00), Model::Dmg, (0x7E, 0xF1, 0xFF)),
+            ((0x00, 0x03, 0x33), Model::Sgb, (0x7E, 0xF0, 0xFF)),
+            ((0xC0, 0x00, 0x00), Model::Cgb, (0x7F, 0xF1, 0x00)),
+        ];
+
+        for ((cgb, sgb, licensee), model, (sc, nr52, dma)) in cases {
+            let mut rom = test_rom(0x00, 0x00, 0x00);
+            rom[0x0143] = cgb;
+            rom[0x0146] = sgb;
+            rom[0x014B] = licensee;
+            rom[HEADER_CHECKSUM] = header_checksum(&rom);
+            let cartridge = Cartridge::from_bytes(rom).expect("Valid ROM");
+            let mut cpu = Cpu::new(Memory::with_cartridge(cartridge));
+            assert_eq!(cpu.memory.model, model);
+
+            cpu.skip_boot();
+            cpu.memory.tick(2);
+
+            let read = |addr| cpu.memory.get_byte(addr).expect("Unable to get byte");
+            assert_eq!((read(0xFF02), read(0xFF26), read(0xFF46)), (sc, nr52, dma));
+            assert_eq!(read(0xFF40), 0x91);
+            assert_eq!(read(0xFF00), 0xCF);
+            assert!(!cpu.memory.dma.active());
+        }
+    }
+}
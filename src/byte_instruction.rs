@@ -57,7 +57,7 @@ mod tests {
         assert_eq!(segs.y(), 0b110);
         assert_eq!(segs.z(), 0b010);
         assert_eq!(segs.p(), 0b11);
-        assert_eq!(segs.q(), false);
+        assert!(!segs.q());
 
         // 11_101_101
         //  x|  y|  z
@@ -68,6 +68,6 @@ mod tests {
         assert_eq!(segs.y(), 0b101);
         assert_eq!(segs.z(), 0b101);
         assert_eq!(segs.p(), 0b10);
-        assert_eq!(segs.q(), true);
+        assert!(segs.q());
     }
 }
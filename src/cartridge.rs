@@ -0,0 +1,603 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+
+use crate::{
+    mbc::{Mbc, Mbc1, Mbc2, Mbc3, Mbc5, NoMbc, ROM_BANK_SIZE},
+    rtc::{RTC_FOOTER_SIZE, RTC_FOOTER_SIZE_32, Rtc},
+};
+
+pub const HEADER_START: usize = 0x0100;
+pub const HEADER_END: usize = 0x014F;
+
+const LOGO: usize = 0x0104;
+const TITLE: usize = 0x0134;
+const CGB_FLAG: usize = 0x0143;
+const NEW_LICENSEE: usize = 0x0144;
+const SGB_FLAG: usize = 0x0146;
+const CARTRIDGE_TYPE: usize = 0x0147;
+const ROM_SIZE: usize = 0x0148;
+const RAM_SIZE: usize = 0x0149;
+const DESTINATION: usize = 0x014A;
+const OLD_LICENSEE: usize = 0x014B;
+const VERSION: usize = 0x014C;
+pub(crate) const HEADER_CHECKSUM: usize = 0x014D;
+const GLOBAL_CHECKSUM: usize = 0x014E;
+
+/// Old licensee value telling us to look at the new licensee code instead
+const USE_NEW_LICENSEE: u8 = 0x33;
+
+pub const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+// https://gbdev.io/pandocs/The_Cartridge_Header.html
+#[derive(Debug, Clone)]
+pub struct Cartridge {
+    pub header: CartridgeHeader,
+    pub rom: Vec<u8>,
+    pub mbc: Box<dyn Mbc>,
+}
+
+impl Cartridge {
+    pub fn from_bytes(rom: Vec<u8>) -> Result<Self, CartridgeError> {
+        let header = CartridgeHeader::parse(&rom)?;
+
+        let expected = header.rom_size();
+        if rom.len() < expected {
+            return Err(CartridgeError::Truncated {
+                expected,
+                actual: rom.len(),
+            });
+        }
+
+        let ram_size = header.ram_size();
+        let mbc: Box<dyn Mbc> = match header.cartridge_type.controller {
+            Controller::None => Box::new(NoMbc::new(ram_size)),
+            Controller::Mbc1 => Box::new(Mbc1::new(ram_size, is_mbc1_multicart(&rom))),
+            Controller::Mbc2 => Box::new(Mbc2::default()),
+            Controller::Mbc3 => {
+                let rtc = header.cartridge_type.timer.then(Rtc::default);
+                Box::new(Mbc3::new(ram_size, rtc))
+            }
+            Controller::Mbc5 => Box::new(Mbc5::new(ram_size, header.cartridge_type.rumble)),
+            controller => return Err(CartridgeError::UnsupportedController(controller)),
+        };
+
+        Ok(Self { header, rom, mbc })
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let rom = std::fs::read(path)
+            .with_context(|| format!("Unable to read ROM '{}'", path.display()))?;
+
+        Self::from_bytes(rom).with_context(|| format!("Invalid ROM '{}'", path.display()))
+    }
+
+    /// The global checksum isn't verified by hardware so plenty of homebrew gets it wrong. Only
+    /// use this when you want to be strict about a dump being intact.
+    pub fn verify_global_checksum(&self) -> Result<(), CartridgeError> {
+        let actual = global_checksum(&self.rom);
+        if actual != self.header.global_checksum {
+            return Err(CartridgeError::GlobalChecksum {
+                expected: self.header.global_checksum,
+                actual,
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn read_rom(&self, addr: u16) -> u8 {
+        let offset = self.mbc.rom_offset(addr) % self.rom.len();
+        self.rom[offset]
+    }
+
+    pub fn write_rom(&mut self, addr: u16, value: u8) {
+        self.mbc.write_register(addr, value);
+    }
+
+    pub fn read_ram(&self, addr: u16) -> u8 {
+        self.mbc.read_ram(addr)
+    }
+
+    pub fn write_ram(&mut self, addr: u16, value: u8) {
+        self.mbc.write_ram(addr, value);
+    }
+
+    pub fn tick(&mut self, m_cycles: u32) {
+        self.mbc.tick(m_cycles);
+    }
+
+    pub fn rtc(&self) -> Option<&Rtc> {
+        self.mbc.rtc()
+    }
+
+    pub fn rtc_mut(&mut self) -> Option<&mut Rtc> {
+        self.mbc.rtc_mut()
+    }
+
+    pub fn has_battery(&self) -> bool {
+        self.header.cartridge_type.battery
+    }
+
+    /// Contents of a .sav file: the external RAM followed by the RTC footer on carts with a clock
+    pub fn export_save(&self) -> Vec<u8> {
+        let mut save = self.mbc.ram().to_vec();
+        if let Some(rtc) = self.mbc.rtc() {
+            save.extend_from_slice(&rtc.to_footer());
+        }
+        save
+    }
+
+    /// Loads a .sav file's contents. The RTC footer is optional since some emulators don't write
+    /// one, the clock just starts from zero in that case. Both the 48 and 44 byte footers are read.
+    pub fn import_save(&mut self, save: &[u8]) -> anyhow::Result<()> {
+        let ram_size = self.mbc.ram().len();
+        if save.len() < ram_size {
+            anyhow::bail!(
+                "Save is {} bytes but the cartridge has {} bytes of RAM",
+                save.len(),
+                ram_size
+            );
+        }
+
+        let (ram, footer) = save.split_at(ram_size);
+        match (self.mbc.rtc_mut(), footer.len()) {
+            (_, 0) => {}
+            (Some(rtc), RTC_FOOTER_SIZE) => {
+                rtc.load_footer(footer.try_into().expect("Footer size checked"))
+            }
+            (Some(rtc), RTC_FOOTER_SIZE_32) => {
+                // Zero-extend the timestamp
+                let mut extended = [0; RTC_FOOTER_SIZE];
+                extended[..RTC_FOOTER_SIZE_32].copy_from_slice(footer);
+                rtc.load_footer(&extended)
+            }
+            _ => anyhow::bail!("Unexpected {} trailing bytes in save", footer.len()),
+        }
+
+        self.mbc.ram_mut().copy_from_slice(ram);
+        Ok(())
+    }
+
+    /// Loads the save at `path` if there is one. Does nothing for carts without a battery.
+    pub fn load_save(&mut self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        if !self.has_battery() || !path.exists() {
+            return Ok(());
+        }
+
+        let save = std::fs::read(path)
+            .with_context(|| format!("Unable to read save '{}'", path.display()))?;
+        self.import_save(&save)
+            .with_context(|| format!("Invalid save '{}'", path.display()))
+    }
+
+    /// Writes the battery backed RAM out to `path`. Does nothing for carts without a battery.
+    pub fn flush_save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        if !self.has_battery() {
+            return Ok(());
+        }
+
+        std::fs::write(path, self.export_save())
+            .with_context(|| format!("Unable to write save '{}'", path.display()))
+    }
+}
+
+/// Where the save for a ROM lives by convention, `game.gb` -> `game.sav`
+pub fn save_path(rom_path: impl AsRef<Path>) -> PathBuf {
+    rom_path.as_ref().with_extension("sav")
+}
+
+/// MBC1M multicarts are 1 MiB carts made of 256 KiB games, each with its own header. Look for the
+/// second game's logo to tell them apart from regular 1 MiB MBC1 carts.
+fn is_mbc1_multicart(rom: &[u8]) -> bool {
+    let second_logo = 0x10 * ROM_BANK_SIZE + LOGO;
+    rom.len() == 0x100000 && rom[second_logo..second_logo + NINTENDO_LOGO.len()] == NINTENDO_LOGO
+}
+
+/// ┌─────────────┬──────────────────────────────┐
+/// │ 0x0100-0103 │ Entry point                  │
+/// │ 0x0104-0133 │ Nintendo logo                │
+/// │ 0x0134-0143 │ Title (0x013F-0143 on CGB)   │
+/// │ 0x0143      │ CGB flag                     │
+/// │ 0x0144-0145 │ New licensee code            │
+/// │ 0x0146      │ SGB flag                     │
+/// │ 0x0147      │ Cartridge type               │
+/// │ 0x0148      │ ROM size                     │
+/// │ 0x0149      │ RAM size                     │
+/// │ 0x014A      │ Destination code             │
+/// │ 0x014B      │ Old licensee code            │
+/// │ 0x014C      │ Mask ROM version number      │
+/// │ 0x014D      │ Header checksum              │
+/// │ 0x014E-014F │ Global checksum (big endian) │
+/// └─────────────┴──────────────────────────────┘
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CartridgeHeader {
+    pub title: String,
+    pub cgb_flag: CgbFlag,
+    pub sgb: bool,
+    pub cartridge_type: CartridgeType,
+    pub rom_size_code: u8,
+    pub ram_size_code: u8,
+    pub japanese: bool,
+    pub licensee: Licensee,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+}
+
+impl CartridgeHeader {
+    pub fn parse(rom: &[u8]) -> Result<Self, CartridgeError> {
+        if rom.len() <= HEADER_END {
+            return Err(CartridgeError::Truncated {
+                expected: HEADER_END + 1,
+                actual: rom.len(),
+            });
+        }
+
+        let actual = header_checksum(rom);
+        if actual != rom[HEADER_CHECKSUM] {
+            return Err(CartridgeError::HeaderChecksum {
+                expected: rom[HEADER_CHECKSUM],
+                actual,
+            });
+        }
+
+        let cgb_flag = CgbFlag::from(rom[CGB_FLAG]);
+        let title_end = match cgb_flag {
+            CgbFlag::None => CGB_FLAG + 1,
+            CgbFlag::Enhanced | CgbFlag::Only => CGB_FLAG,
+        };
+        let title = rom[TITLE..title_end]
+            .iter()
+            .take_while(|b| **b != 0)
+            .map(|b| char::from(*b))
+            .collect();
+
+        let licensee = match rom[OLD_LICENSEE] {
+            USE_NEW_LICENSEE => Licensee::New([rom[NEW_LICENSEE], rom[NEW_LICENSEE + 1]]),
+            code => Licensee::Old(code),
+        };
+
+        let rom_size_code = rom[ROM_SIZE];
+        if rom_size_code > 0x08 {
+            return Err(CartridgeError::RomSize(rom_size_code));
+        }
+
+        let ram_size_code = rom[RAM_SIZE];
+        if ram_size_code > 0x05 {
+            return Err(CartridgeError::RamSize(ram_size_code));
+        }
+
+        Ok(Self {
+            title,
+            cgb_flag,
+            sgb: rom[SGB_FLAG] == 0x03,
+            cartridge_type: rom[CARTRIDGE_TYPE].try_into()?,
+            rom_size_code,
+            ram_size_code,
+            japanese: rom[DESTINATION] == 0x00,
+            licensee,
+            version: rom[VERSION],
+            header_checksum: rom[HEADER_CHECKSUM],
+            global_checksum: u16::from_be_bytes([rom[GLOBAL_CHECKSUM], rom[GLOBAL_CHECKSUM + 1]]),
+        })
+    }
+
+    /// 32 KiB << code
+    pub fn rom_size(&self) -> usize {
+        0x8000 << self.rom_size_code
+    }
+
+    pub fn rom_banks(&self) -> usize {
+        2 << self.rom_size_code
+    }
+
+    pub fn ram_size(&self) -> usize {
+        match self.ram_size_code {
+            0x02 => 0x2000,
+            0x03 => 0x8000,
+            0x04 => 0x20000,
+            0x05 => 0x10000,
+            // 0x01 is listed as "unused" but some homebrew sets it, treat as no RAM
+            _ => 0,
+        }
+    }
+}
+
+/// ┌──────┬───────────────────────────────────┐
+/// │ 0x80 │ Supports CGB, works on DMG        │
+/// │ 0xC0 │ CGB only                          │
+/// │ else │ DMG (value is part of the title)  │
+/// └──────┴───────────────────────────────────┘
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CgbFlag {
+    None,
+    Enhanced,
+    Only,
+}
+
+impl From<u8> for CgbFlag {
+    fn from(value: u8) -> Self {
+        match value {
+            0x80 => CgbFlag::Enhanced,
+            0xC0 => CgbFlag::Only,
+            _ => CgbFlag::None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Licensee {
+    Old(u8),
+    New([u8; 2]),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Controller {
+    None,
+    Mbc1,
+    Mbc2,
+    Mmm01,
+    Mbc3,
+    Mbc5,
+    Mbc6,
+    Mbc7,
+    PocketCamera,
+    Tama5,
+    HuC3,
+    HuC1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CartridgeType {
+    pub code: u8,
+    pub controller: Controller,
+    pub ram: bool,
+    pub battery: bool,
+    pub timer: bool,
+    pub rumble: bool,
+}
+
+impl TryFrom<u8> for CartridgeType {
+    type Error = CartridgeError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        use Controller::*;
+
+        let (controller, ram, battery, timer, rumble) = match value {
+            0x00 => (None, false, false, false, false),
+            0x01 => (Mbc1, false, false, false, false),
+            0x02 => (Mbc1, true, false, false, false),
+            0x03 => (Mbc1, true, true, false, false),
+            0x05 => (Mbc2, false, false, false, false),
+            0x06 => (Mbc2, false, true, false, false),
+            0x08 => (None, true, false, false, false),
+            0x09 => (None, true, true, false, false),
+            0x0B => (Mmm01, false, false, false, false),
+            0x0C => (Mmm01, true, false, false, false),
+            0x0D => (Mmm01, true, true, false, false),
+            0x0F => (Mbc3, false, true, true, false),
+            0x10 => (Mbc3, true, true, true, false),
+            0x11 => (Mbc3, false, false, false, false),
+            0x12 => (Mbc3, true, false, false, false),
+            0x13 => (Mbc3, true, true, false, false),
+            0x19 => (Mbc5, false, false, false, false),
+            0x1A => (Mbc5, true, false, false, false),
+            0x1B => (Mbc5, true, true, false, false),
+            0x1C => (Mbc5, false, false, false, true),
+            0x1D => (Mbc5, true, false, false, true),
+            0x1E => (Mbc5, true, true, false, true),
+            0x20 => (Mbc6, false, false, false, false),
+            0x22 => (Mbc7, true, true, false, true),
+            0xFC => (PocketCamera, false, false, false, false),
+            0xFD => (Tama5, false, false, false, false),
+            0xFE => (HuC3, false, false, false, false),
+            0xFF => (HuC1, true, true, false, false),
+            _ => return Err(CartridgeError::CartridgeType(value)),
+        };
+
+        Ok(Self {
+            code: value,
+            controller,
+            ram,
+            battery,
+            timer,
+            rumble,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum CartridgeError {
+    Truncated { expected: usize, actual: usize },
+    HeaderChecksum { expected: u8, actual: u8 },
+    GlobalChecksum { expected: u16, actual: u16 },
+    CartridgeType(u8),
+    UnsupportedController(Controller),
+    RomSize(u8),
+    RamSize(u8),
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CartridgeError::Truncated { expected, actual } => write!(
+                f,
+                "ROM is truncated: expected {expected} bytes but got {actual}"
+            ),
+            CartridgeError::HeaderChecksum { expected, actual } => write!(
+                f,
+                "Header checksum mismatch: header says 0x{expected:02x} but computed 0x{actual:02x}"
+            ),
+            CartridgeError::GlobalChecksum { expected, actual } => write!(
+                f,
+                "Global checksum mismatch: header says 0x{expected:04x} but computed 0x{actual:04x}"
+            ),
+            CartridgeError::CartridgeType(code) => {
+                write!(f, "Unknown cartridge type: 0x{code:02x}")
+            }
+            CartridgeError::UnsupportedController(controller) => {
+                write!(f, "Unsupported memory bank controller: {controller:?}")
+            }
+            CartridgeError::RomSize(code) => write!(f, "Unknown ROM size code: 0x{code:02x}"),
+            CartridgeError::RamSize(code) => write!(f, "Unknown RAM size code: 0x{code:02x}"),
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {}
+
+/// Sum of the complement of every byte in 0x0134-0x014C
+pub fn header_checksum(rom: &[u8]) -> u8 {
+    rom[TITLE..HEADER_CHECKSUM]
+        .iter()
+        .fold(0u8, |acc, b| acc.wrapping_sub(*b).wrapping_sub(1))
+}
+
+/// Sum of every byte in the ROM other than the two checksum bytes themselves
+pub fn global_checksum(rom: &[u8]) -> u16 {
+    rom.iter()
+        .enumerate()
+        .filter(|(i, _)| *i != GLOBAL_CHECKSUM && *i != GLOBAL_CHECKSUM + 1)
+        .fold(0u16, |acc, (_, b)| acc.wrapping_add(u16::from(*b)))
+}
+
+/// Builds a blank ROM image with a valid header for tests
+#[cfg(test)]
+pub(crate) fn test_rom(cartridge_type: u8, rom_size_code: u8, ram_size_code: u8) -> Vec<u8> {
+    let mut rom = vec![0; 0x8000 << rom_size_code];
+    rom[LOGO..LOGO + NINTENDO_LOGO.len()].copy_from_slice(&NINTENDO_LOGO);
+    rom[TITLE..TITLE + 4].copy_from_slice(b"TEST");
+    rom[CARTRIDGE_TYPE] = cartridge_type;
+    rom[ROM_SIZE] = rom_size_code;
+    rom[RAM_SIZE] = ram_size_code;
+    rom[HEADER_CHECKSUM] = header_checksum(&rom);
+
+    let [high, low] = global_checksum(&rom).to_be_bytes();
+    rom[GLOBAL_CHECKSUM] = high;
+    rom[GLOBAL_CHECKSUM + 1] = low;
+    rom
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        cartridge::{
+            CARTRIDGE_TYPE, Cartridge, CartridgeError, CgbFlag, Controller, HEADER_CHECKSUM,
+            Licensee, save_path, test_rom,
+        },
+        rtc::{RTC_FOOTER_SIZE, RTC_FOOTER_SIZE_32},
+    };
+
+    #[test]
+    fn parse_header() {
+        let mut rom = test_rom(0x13, 0x01, 0x03);
+        rom[0x0143] = 0xC0;
+        rom[0x014B] = 0x33;
+        rom[0x0144..0x0146].copy_from_slice(b"01");
+        rom[HEADER_CHECKSUM] = crate::cartridge::header_checksum(&rom);
+
+        let cart = Cartridge::from_bytes(rom).expect("Valid ROM");
+        let header = &cart.header;
+
+        assert_eq!(header.title, "TEST");
+        assert_eq!(header.cgb_flag, CgbFlag::Only);
+        assert_eq!(header.licensee, Licensee::New(*b"01"));
+        assert_eq!(header.cartridge_type.controller, Controller::Mbc3);
+        assert!(header.cartridge_type.ram);
+        assert!(header.cartridge_type.battery);
+        assert!(!header.cartridge_type.timer);
+        assert_eq!(header.rom_size(), 0x10000);
+        assert_eq!(header.rom_banks(), 4);
+        assert_eq!(header.ram_size(), 0x8000);
+    }
+
+    #[test]
+    fn reject_bad_images() {
+        let rom = test_rom(0x00, 0x00, 0x00);
+        assert!(matches!(
+            Cartridge::from_bytes(rom[..0x100].to_vec()),
+            Err(CartridgeError::Truncated { .. })
+        ));
+        assert!(matches!(
+            Cartridge::from_bytes(rom[..0x4000].to_vec()),
+            Err(CartridgeError::Truncated {
+                expected: 0x8000,
+                actual: 0x4000
+            })
+        ));
+
+        let mut bad_checksum = rom.clone();
+        bad_checksum[HEADER_CHECKSUM] ^= 0xFF;
+        assert!(matches!(
+            Cartridge::from_bytes(bad_checksum),
+            Err(CartridgeError::HeaderChecksum { .. })
+        ));
+
+        let mut bad_type = rom.clone();
+        bad_type[CARTRIDGE_TYPE] = 0x42;
+        bad_type[HEADER_CHECKSUM] = crate::cartridge::header_checksum(&bad_type);
+        assert!(matches!(
+            Cartridge::from_bytes(bad_type),
+            Err(CartridgeError::CartridgeType(0x42))
+        ));
+
+        let mut corrupt = Cartridge::from_bytes(rom).expect("Valid ROM");
+        assert!(corrupt.verify_global_checksum().is_ok());
+        corrupt.rom[0x2000] = 0xAA;
+        assert!(matches!(
+            corrupt.verify_global_checksum(),
+            Err(CartridgeError::GlobalChecksum { .. })
+        ));
+    }
+
+    #[test]
+    fn save_round_trip() {
+        // MBC3 + TIMER + RAM + BATTERY with 8 KiB of RAM
+        let mut cart = Cartridge::from_bytes(test_rom(0x10, 0x00, 0x02)).expect("Valid ROM");
+        assert!(cart.has_battery());
+
+        cart.write_rom(0x0000, 0x0A);
+        cart.write_ram(0xA123, 0x5A);
+        cart.write_rom(0x4000, 0x0A);
+        cart.write_ram(0xA000, 13);
+
+        let save = cart.export_save();
+        assert_eq!(save.len(), 0x2000 + RTC_FOOTER_SIZE);
+        assert_eq!(save[0x123], 0x5A);
+
+        // Unique to this process so test runs running side by side don't share a file
+        let name = format!("mobulator_save_round_trip_{}.gb", std::process::id());
+        let path = save_path(std::env::temp_dir().join(name));
+        cart.flush_save(&path).expect("Unable to write save");
+
+        let mut restored = Cartridge::from_bytes(test_rom(0x10, 0x00, 0x02)).expect("Valid ROM");
+        restored.load_save(&path).expect("Unable to load save");
+        std::fs::remove_file(&path).expect("Unable to clean up save");
+
+        restored.write_rom(0x0000, 0x0A);
+        assert_eq!(restored.read_ram(0xA123), 0x5A);
+        assert_eq!(restored.rtc().expect("Cart has RTC").registers.hours, 13);
+
+        // The 44 byte footer has the timestamp's low half only
+        let mut short = Cartridge::from_bytes(test_rom(0x10, 0x00, 0x02)).expect("Valid ROM");
+        short
+            .import_save(&save[..0x2000 + RTC_FOOTER_SIZE_32])
+            .expect("Unable to import save");
+        short.write_rom(0x0000, 0x0A);
+        assert_eq!(short.read_ram(0xA123), 0x5A);
+        assert_eq!(short.rtc().expect("Cart has RTC").registers.hours, 13);
+
+        assert!(restored.import_save(&[0; 16]).is_err());
+    }
+}
@@ -1,8 +1,9 @@
 use anyhow::Context;
 
 use crate::{
+    boot::{post_boot_div_counter, post_boot_io, post_boot_registers},
     instruction::{Instruction, PrefixedInstruction},
-    memory::{InterruptType, Memory},
+    memory::Memory,
     registers::{Cond, R8, Registers},
     utils::{
         BitExt, RegisterU16Ext, carry_u16_i8, half_carry_add_u8, half_carry_add_u16,
@@ -14,7 +15,27 @@ use crate::{
 pub struct Cpu {
     pub registers: Registers,
     pub memory: Memory,
-    interrupt_master_enable: bool,
+    ime: Ime,
+    halted: bool,
+    /// HALT with IME off and an interrupt already pending doesn't halt, instead the next opcode
+    /// fetch fails to increment PC
+    halt_bug: bool,
+    /// STOP without a speed switch, only a joypad line going low wakes us up
+    stopped: bool,
+    speed_switch_delay: u16,
+}
+
+/// The CPU sits idle for this many M-cycles after a CGB speed switch
+// https://gbdev.io/pandocs/CGB_Registers.html#ff4d--key1-cgb-mode-only-prepare-speed-switch
+const SPEED_SWITCH_CYCLES: u16 = 2050;
+
+/// The interrupt master enable flag. EI only takes effect after the instruction following it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Ime {
+    #[default]
+    Disabled,
+    EnablePending,
+    Enabled,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -24,6 +45,30 @@ pub enum Status {
 }
 
 impl Cpu {
+    pub fn new(memory: Memory) -> Self {
+        Self {
+            memory,
+            ..Default::default()
+        }
+    }
+
+    /// Puts the registers and I/O into the state the boot ROM leaves them in, ready to run the
+    /// cartridge from 0x0100
+    pub fn skip_boot(&mut self) {
+        self.memory.boot_rom = None;
+
+        let header_checksum = self.memory.get_byte(0x014D).unwrap_or(0);
+        self.registers = post_boot_registers(self.memory.model, header_checksum);
+
+        // Loaded rather than written, so nothing like a DMA or an SGB packet gets started
+        for (addr, value) in post_boot_io(self.memory.model) {
+            self.memory.load_io(addr, value);
+        }
+        self.memory
+            .timer
+            .set_counter(post_boot_div_counter(self.memory.model));
+    }
+
     pub fn run_num_instructions(&mut self, num: u8) -> anyhow::Result<()> {
         for _ in 0..num {
             self.run_next_instruction()?;
@@ -32,6 +77,40 @@ impl Cpu {
     }
 
     pub fn run_next_instruction(&mut self) -> anyhow::Result<Status> {
+        if self.stopped {
+            // The system clock is stopped so nothing else on the bus moves either
+            if !self.memory.joypad_line_low() {
+                return Ok(Status::Cycles(1));
+            }
+            self.stopped = false;
+        }
+
+        if self.speed_switch_delay > 0 {
+            self.speed_switch_delay -= 1;
+            self.memory.tick(1);
+            return Ok(Status::Cycles(1));
+        }
+
+        // The CPU sits out VRAM DMA, everything else keeps running
+        if self.memory.vram_dma_stalls() {
+            self.memory.tick(1);
+            return Ok(Status::Cycles(1));
+        }
+
+        if self.halted {
+            // Idle until an interrupt is pending, whether or not IME lets us service it
+            if !self.memory.interrupt_pending() {
+                self.memory.tick(1);
+                return Ok(Status::Cycles(1));
+            }
+
+            self.halted = false;
+            let cycles = 1 + self.handle_interrupts()?;
+            self.memory.tick(cycles);
+            return Ok(Status::Cycles(cycles));
+        }
+
+        let enable_pending = self.ime == Ime::EnablePending;
         let result = self.run_8bit_opcode()?;
 
         let op_cycles = match result {
@@ -39,20 +118,52 @@ impl Cpu {
             Status::Prefix => self.run_16bit_opcode()?,
         };
 
+        // Only promote if the instruction we just ran wasn't DI
+        if enable_pending && self.ime == Ime::EnablePending {
+            self.ime = Ime::Enabled;
+        }
+
         let int_cycles = self.handle_interrupts()?;
 
-        Ok(Status::Cycles(op_cycles + int_cycles))
+        let cycles = op_cycles + int_cycles;
+        self.memory.tick(cycles);
+
+        Ok(Status::Cycles(cycles))
     }
 
     pub fn run_8bit_opcode(&mut self) -> anyhow::Result<Status> {
         let instruction_byte = self.next().ok_or(anyhow::anyhow!("No more memory"))?;
+        if self.halt_bug {
+            self.halt_bug = false;
+            self.registers.pc = self.registers.pc.wrapping_sub(1);
+        }
         let instruction = Instruction::try_from(instruction_byte)?;
+        let mut branch_taken = false;
 
         use Instruction::*;
 
         match instruction {
             Nop => (),
 
+            Stop => {
+                self.imm8()?;
+                self.memory.reset_div();
+
+                if self.memory.switch_speed() {
+                    self.speed_switch_delay = SPEED_SWITCH_CYCLES;
+                } else {
+                    self.stopped = true;
+                }
+            }
+
+            Halt => {
+                if self.ime != Ime::Enabled && self.memory.interrupt_pending() {
+                    self.halt_bug = true;
+                } else {
+                    self.halted = true;
+                }
+            }
+
             LdR16Imm16 { reg } => {
                 let data = self.imm16()?;
                 self.registers.set_r16(reg, data);
@@ -229,7 +340,7 @@ impl Cpu {
                         .pc
                         .wrapping_add_signed(i16::from(imm8_signed));
 
-                    return Ok(Status::Cycles(3));
+                    branch_taken = true;
                 }
             }
 
@@ -437,7 +548,7 @@ impl Cpu {
                 if self.cond_met(cond) {
                     let val = self.pop()?;
                     self.registers.pc = val;
-                    return Ok(Status::Cycles(5));
+                    branch_taken = true;
                 }
             }
 
@@ -447,7 +558,8 @@ impl Cpu {
             }
 
             Reti => {
-                self.interrupt_master_enable = true;
+                // Unlike EI there's no delay
+                self.ime = Ime::Enabled;
 
                 let val = self.pop()?;
                 self.registers.pc = val;
@@ -458,7 +570,7 @@ impl Cpu {
 
                 if self.cond_met(cond) {
                     self.registers.pc = imm16;
-                    return Ok(Status::Cycles(4));
+                    branch_taken = true;
                 }
             }
 
@@ -477,7 +589,7 @@ impl Cpu {
                     self.push_stack_pc();
                     self.registers.pc = imm16;
 
-                    return Ok(Status::Cycles(6));
+                    branch_taken = true;
                 }
             }
 
@@ -578,23 +690,19 @@ impl Cpu {
             }
 
             Di => {
-                self.interrupt_master_enable = false;
+                self.ime = Ime::Disabled;
             }
 
-            // TODO: The effect of ei is delayed by one instruction. This means that ei followed immediately by di
-            // does not allow any interrupts between them. This interacts with the halt bug in an interesting way.
+            // The effect of ei is delayed by one instruction. This means that ei followed immediately by di
+            // does not allow any interrupts between them.
             Ei => {
-                self.interrupt_master_enable = true;
+                if self.ime == Ime::Disabled {
+                    self.ime = Ime::EnablePending;
+                }
             }
-
-            _ => anyhow::bail!(
-                "Haven't implented instruction: {:08b} (0x{:x})",
-                instruction_byte,
-                instruction_byte
-            ),
         };
 
-        Ok(Status::Cycles(instruction.cycles()))
+        Ok(Status::Cycles(instruction.cycles(branch_taken)))
     }
 
     pub fn run_16bit_opcode(&mut self) -> anyhow::Result<u8> {
@@ -723,32 +831,42 @@ impl Cpu {
     }
 
     pub fn handle_interrupts(&mut self) -> anyhow::Result<u8> {
-        if !self.interrupt_master_enable {
+        if self.ime != Ime::Enabled || !self.memory.interrupt_pending() {
             return Ok(0);
         }
 
-        let cycles = if let Some(interrupt) = self.memory.interrupt_to_run()? {
-            self.run_interrupt_routine(interrupt)
-        } else {
-            0
-        };
-
-        self.interrupt_master_enable = false;
-        Ok(cycles)
-    }
+        self.ime = Ime::Disabled;
+        self.halted = false;
 
-    pub fn run_interrupt_routine(
-        &mut self,
-        interrupt_type: InterruptType,
-    ) -> u8 {
-        self.push_stack_pc();
+        // ei; halt with an interrupt pending hits the halt bug, the handler returns to the halt
+        if self.halt_bug {
+            self.halt_bug = false;
+            self.registers.pc = self.registers.pc.wrapping_sub(1);
+        }
 
-        self.registers.pc = interrupt_type.addr();
+        self.run_interrupt_routine()
+    }
 
+    // https://gbdev.io/pandocs/Interrupts.html#interrupt-handling
+    pub fn run_interrupt_routine(&mut self) -> anyhow::Result<u8> {
         // Two wait states are executed (2 M-cycles pass while nothing happens; presumably the CPU is executing nops during this time).
         // The current value of the PC register is pushed onto the stack, consuming 2 more M-cycles.
         // The PC register is set to the address of the handler (one of: $40, $48, $50, $58, $60). This consumes one last M-cycle.
-        return 5
+        let [high, low] = self.registers.pc.to_be_bytes();
+
+        self.registers.sp = self.registers.sp.wrapping_sub(1);
+        self.memory.set_byte(self.registers.sp, high);
+
+        // The interrupt is only picked after the high byte is pushed. If that push overwrote IE and
+        // nothing is left to service, dispatch is cancelled and PC ends up at 0x0000.
+        let interrupt = self.memory.interrupt_to_run()?;
+
+        self.registers.sp = self.registers.sp.wrapping_sub(1);
+        self.memory.set_byte(self.registers.sp, low);
+
+        self.registers.pc = interrupt.map_or(0x0000, |i| i.addr());
+
+        Ok(5)
     }
 
     fn push_stack_pc(&mut self) {
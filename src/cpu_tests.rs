@@ -1,12 +1,13 @@
-use std::u16;
-
 use crate::{
+    boot::Model,
     byte_instruction::ByteInstruction,
-    cpu::Cpu,
+    cartridge::{Cartridge, test_rom},
+    cpu::{Cpu, Status},
     instructions::*,
+    memory::{INTERRUPT_ENABLE, INTERRUPT_FLAG, KEY1, Memory},
     registers::{Cond, R8, R16},
 };
-use mobulator_macros::opcode_list;
+use mobulator_macros::{asm, opcode_list};
 
 #[test]
 fn ld_r16_imm16() {
@@ -138,9 +139,9 @@ fn add_hl_r16() {
             _ => 1337 + 2424,
         };
         assert_eq!(cpu.registers.hl, target);
-        assert_eq!(cpu.registers.n_flg(), false);
-        assert_eq!(cpu.registers.c_flg(), false);
-        assert_eq!(cpu.registers.c_flg(), false);
+        assert!(!cpu.registers.n_flg());
+        assert!(!cpu.registers.c_flg());
+        assert!(!cpu.registers.c_flg());
     }
 }
 
@@ -156,8 +157,8 @@ fn add_hl_r16_flags() {
         .expect("Unable to process CPU instructions");
 
     assert_eq!(cpu.registers.hl, 1);
-    assert_eq!(cpu.registers.c_flg(), true);
-    assert_eq!(cpu.registers.h_flg(), true);
+    assert!(cpu.registers.c_flg());
+    assert!(cpu.registers.h_flg());
 
     let mut cpu = Cpu::default();
     cpu.memory.load_instructions(&[0b00001001]);
@@ -167,7 +168,7 @@ fn add_hl_r16_flags() {
     cpu.run_next_instruction()
         .expect("Unable to process CPU instructions");
 
-    assert_eq!(cpu.registers.h_flg(), true);
+    assert!(cpu.registers.h_flg());
 
     let mut cpu = Cpu::default();
     cpu.memory.load_instructions(&[0b00001001]);
@@ -177,7 +178,7 @@ fn add_hl_r16_flags() {
     cpu.run_next_instruction()
         .expect("Unable to process CPU instructions");
 
-    assert_eq!(cpu.registers.h_flg(), false);
+    assert!(!cpu.registers.h_flg());
 }
 
 #[test]
@@ -231,8 +232,8 @@ fn inc_dec_r8_flags() {
     cpu.run_next_instruction()
         .expect("Unable to process CPU instructions");
 
-    assert_eq!(cpu.registers.h_flg(), true);
-    assert_eq!(cpu.registers.n_flg(), false);
+    assert!(cpu.registers.h_flg());
+    assert!(!cpu.registers.n_flg());
 
     // dec
     let mut cpu = Cpu::default();
@@ -243,8 +244,8 @@ fn inc_dec_r8_flags() {
     cpu.run_next_instruction()
         .expect("Unable to process CPU instructions");
 
-    assert_eq!(cpu.registers.h_flg(), true);
-    assert_eq!(cpu.registers.n_flg(), true);
+    assert!(cpu.registers.h_flg());
+    assert!(cpu.registers.n_flg());
 }
 
 #[test]
@@ -282,10 +283,10 @@ fn rlca() {
         .expect("Unable to process CPU instructions");
 
     assert_eq!(cpu.registers.a(), 0b00110001);
-    assert_eq!(cpu.registers.z_flg(), false);
-    assert_eq!(cpu.registers.n_flg(), false);
-    assert_eq!(cpu.registers.h_flg(), false);
-    assert_eq!(cpu.registers.c_flg(), true);
+    assert!(!cpu.registers.z_flg());
+    assert!(!cpu.registers.n_flg());
+    assert!(!cpu.registers.h_flg());
+    assert!(cpu.registers.c_flg());
 
     let mut cpu = Cpu::default();
     cpu.memory.load_instructions(&[RLCA]);
@@ -296,7 +297,7 @@ fn rlca() {
         .expect("Unable to process CPU instructions");
 
     assert_eq!(cpu.registers.a(), 0b00110000);
-    assert_eq!(cpu.registers.c_flg(), false);
+    assert!(!cpu.registers.c_flg());
 }
 
 #[test]
@@ -311,10 +312,10 @@ fn rrca() {
         .expect("Unable to process CPU instructions");
 
     assert_eq!(cpu.registers.a(), 0b01001100);
-    assert_eq!(cpu.registers.z_flg(), false);
-    assert_eq!(cpu.registers.n_flg(), false);
-    assert_eq!(cpu.registers.h_flg(), false);
-    assert_eq!(cpu.registers.c_flg(), false);
+    assert!(!cpu.registers.z_flg());
+    assert!(!cpu.registers.n_flg());
+    assert!(!cpu.registers.h_flg());
+    assert!(!cpu.registers.c_flg());
 
     let mut cpu = Cpu::default();
     cpu.memory.load_instructions(&[RRCA]);
@@ -325,7 +326,7 @@ fn rrca() {
         .expect("Unable to process CPU instructions");
 
     assert_eq!(cpu.registers.a(), 0b10001100);
-    assert_eq!(cpu.registers.c_flg(), true);
+    assert!(cpu.registers.c_flg());
 }
 
 #[test]
@@ -340,10 +341,10 @@ fn rla() {
         .expect("Unable to process CPU instructions");
 
     assert_eq!(cpu.registers.a(), 0b00110000);
-    assert_eq!(cpu.registers.z_flg(), false);
-    assert_eq!(cpu.registers.n_flg(), false);
-    assert_eq!(cpu.registers.h_flg(), false);
-    assert_eq!(cpu.registers.c_flg(), true);
+    assert!(!cpu.registers.z_flg());
+    assert!(!cpu.registers.n_flg());
+    assert!(!cpu.registers.h_flg());
+    assert!(cpu.registers.c_flg());
 
     let mut cpu = Cpu::default();
     cpu.memory.load_instructions(&[RLA]);
@@ -355,10 +356,10 @@ fn rla() {
         .expect("Unable to process CPU instructions");
 
     assert_eq!(cpu.registers.a(), 0b00110001);
-    assert_eq!(cpu.registers.z_flg(), false);
-    assert_eq!(cpu.registers.n_flg(), false);
-    assert_eq!(cpu.registers.h_flg(), false);
-    assert_eq!(cpu.registers.c_flg(), false);
+    assert!(!cpu.registers.z_flg());
+    assert!(!cpu.registers.n_flg());
+    assert!(!cpu.registers.h_flg());
+    assert!(!cpu.registers.c_flg());
 }
 
 #[test]
@@ -373,10 +374,10 @@ fn rra() {
         .expect("Unable to process CPU instructions");
 
     assert_eq!(cpu.registers.a(), 0b01001100);
-    assert_eq!(cpu.registers.z_flg(), false);
-    assert_eq!(cpu.registers.n_flg(), false);
-    assert_eq!(cpu.registers.h_flg(), false);
-    assert_eq!(cpu.registers.c_flg(), true);
+    assert!(!cpu.registers.z_flg());
+    assert!(!cpu.registers.n_flg());
+    assert!(!cpu.registers.h_flg());
+    assert!(cpu.registers.c_flg());
 
     let mut cpu = Cpu::default();
     cpu.memory.load_instructions(&[RRA]);
@@ -388,10 +389,10 @@ fn rra() {
         .expect("Unable to process CPU instructions");
 
     assert_eq!(cpu.registers.a(), 0b10001100);
-    assert_eq!(cpu.registers.z_flg(), false);
-    assert_eq!(cpu.registers.n_flg(), false);
-    assert_eq!(cpu.registers.h_flg(), false);
-    assert_eq!(cpu.registers.c_flg(), false);
+    assert!(!cpu.registers.z_flg());
+    assert!(!cpu.registers.n_flg());
+    assert!(!cpu.registers.h_flg());
+    assert!(!cpu.registers.c_flg());
 }
 
 #[test]
@@ -407,10 +408,10 @@ fn daa() {
         .expect("Unable to process CPU instructions");
 
     assert_eq!(cpu.registers.a(), 0x82);
-    assert_eq!(cpu.registers.z_flg(), false);
-    assert_eq!(cpu.registers.n_flg(), false);
-    assert_eq!(cpu.registers.h_flg(), false);
-    assert_eq!(cpu.registers.c_flg(), false);
+    assert!(!cpu.registers.z_flg());
+    assert!(!cpu.registers.n_flg());
+    assert!(!cpu.registers.h_flg());
+    assert!(!cpu.registers.c_flg());
 
     let mut cpu = Cpu::default();
     cpu.memory.load_instructions(&[DAA]);
@@ -422,10 +423,10 @@ fn daa() {
         .expect("Unable to process CPU instructions");
 
     assert_eq!(cpu.registers.a(), 0x02);
-    assert_eq!(cpu.registers.z_flg(), false);
-    assert_eq!(cpu.registers.n_flg(), false);
-    assert_eq!(cpu.registers.h_flg(), false);
-    assert_eq!(cpu.registers.c_flg(), true);
+    assert!(!cpu.registers.z_flg());
+    assert!(!cpu.registers.n_flg());
+    assert!(!cpu.registers.h_flg());
+    assert!(cpu.registers.c_flg());
 }
 
 #[test]
@@ -440,10 +441,10 @@ fn cpl() {
         .expect("Unable to process CPU instructions");
 
     assert_eq!(cpu.registers.a(), 0b01000101);
-    assert_eq!(cpu.registers.z_flg(), false);
-    assert_eq!(cpu.registers.n_flg(), true);
-    assert_eq!(cpu.registers.h_flg(), true);
-    assert_eq!(cpu.registers.c_flg(), false);
+    assert!(!cpu.registers.z_flg());
+    assert!(cpu.registers.n_flg());
+    assert!(cpu.registers.h_flg());
+    assert!(!cpu.registers.c_flg());
 }
 
 #[test]
@@ -457,10 +458,10 @@ fn scf() {
     cpu.run_next_instruction()
         .expect("Unable to process CPU instructions");
 
-    assert_eq!(cpu.registers.z_flg(), true);
-    assert_eq!(cpu.registers.n_flg(), false);
-    assert_eq!(cpu.registers.h_flg(), false);
-    assert_eq!(cpu.registers.c_flg(), true);
+    assert!(cpu.registers.z_flg());
+    assert!(!cpu.registers.n_flg());
+    assert!(!cpu.registers.h_flg());
+    assert!(cpu.registers.c_flg());
 }
 
 #[test]
@@ -474,10 +475,10 @@ fn ccf() {
     cpu.run_next_instruction()
         .expect("Unable to process CPU instructions");
 
-    assert_eq!(cpu.registers.z_flg(), true);
-    assert_eq!(cpu.registers.n_flg(), false);
-    assert_eq!(cpu.registers.h_flg(), false);
-    assert_eq!(cpu.registers.c_flg(), false);
+    assert!(cpu.registers.z_flg());
+    assert!(!cpu.registers.n_flg());
+    assert!(!cpu.registers.h_flg());
+    assert!(!cpu.registers.c_flg());
 }
 
 #[test]
@@ -492,6 +493,39 @@ fn jr_imm8() {
     assert_eq!(cpu.registers.pc, 39);
 }
 
+#[test]
+fn assembled_program() {
+    // Sums 10 down to 1 through a subroutine
+    let program = asm! {
+        ld sp, 0xFFFE;
+        ld hl, 0xC000;
+        xor a;
+        ld b, 10;
+    Loop:
+        call Add;
+        dec b;
+        jr nz, Loop;
+        ld [hl+], a;
+        halt;
+    Add:
+        add a, b;
+        ret;
+    };
+    let mut cpu = Cpu::default();
+    cpu.memory.load_instructions(&program);
+
+    // The 4 to set up, 5 for each time round and the store
+    for _ in 0..4 + 10 * 5 + 1 {
+        cpu.run_next_instruction()
+            .expect("Unable to process CPU instructions");
+    }
+
+    assert_eq!(program[usize::from(cpu.registers.pc)], HALT);
+    assert_eq!(cpu.memory.memory[0xC000], 55);
+    assert_eq!(cpu.registers.hl, 0xC001);
+    assert_eq!(cpu.registers.sp, 0xFFFE);
+}
+
 #[test]
 fn jr_cond_imm8() {
     // jr cond, imm8
@@ -520,6 +554,269 @@ fn jr_cond_imm8() {
     }
 }
 
+#[test]
+fn cond_branch_cycles() {
+    // jr nz, jp nz, call nz and ret nz take longer when the branch is taken
+    for (instruction, taken, not_taken) in [
+        (0b00100000, 3, 2),
+        (0b11000010, 4, 3),
+        (0b11000100, 6, 3),
+        (0b11000000, 5, 2),
+    ] {
+        for (z_flg, cycles) in [(false, taken), (true, not_taken)] {
+            let mut cpu = Cpu::default();
+            cpu.memory.load_instructions(&[instruction, 0x10, 0x00]);
+            cpu.registers.sp = 0x100;
+            cpu.registers.set_z_flg(z_flg);
+
+            let status = cpu
+                .run_next_instruction()
+                .expect("Unable to process CPU instructions");
+            assert_eq!(status, Status::Cycles(cycles), "{instruction:08b}");
+        }
+    }
+}
+
+#[test]
+fn stop() {
+    // stop
+    let mut cpu = Cpu::default();
+    cpu.memory.load_instructions(&[STOP, 0x00, NOOP]);
+    cpu.memory.memory[0xFF00] = 0xCF;
+    cpu.memory.timer.set_counter(0x4200);
+
+    cpu.run_next_instruction()
+        .expect("Unable to process CPU instructions");
+    assert_eq!(cpu.registers.pc, 2);
+    assert_eq!(cpu.memory.timer.read(0xFF04), 0);
+
+    cpu.run_next_instruction()
+        .expect("Unable to process CPU instructions");
+    assert_eq!(cpu.registers.pc, 2);
+
+    // Pressing a button wakes it back up
+    cpu.memory.memory[0xFF00] = 0xCE;
+    cpu.run_next_instruction()
+        .expect("Unable to process CPU instructions");
+    assert_eq!(cpu.registers.pc, 3);
+}
+
+#[test]
+fn stop_speed_switch() {
+    let mut cpu = Cpu::default();
+    cpu.memory.model = Model::Cgb;
+    cpu.memory.cartridge =
+        Some(Cartridge::from_bytes(test_rom(0x00, 0x00, 0x00)).expect("Valid ROM"));
+    cpu.registers.pc = 0xC000;
+    cpu.memory.set_byte(0xC000, STOP);
+    cpu.memory.set_byte(KEY1, 0x01);
+
+    cpu.run_next_instruction()
+        .expect("Unable to process CPU instructions");
+    assert!(cpu.memory.double_speed);
+    assert_eq!(cpu.memory.get_byte(KEY1).unwrap(), 0xFE);
+
+    // Doesn't enter stop mode, just stalls for a while
+    cpu.memory.set_byte(0xC002, NOOP);
+    let mut cycles = 0;
+    while cpu.registers.pc != 0xC003 {
+        cycles += 1;
+        cpu.run_next_instruction()
+            .expect("Unable to process CPU instructions");
+    }
+    assert_eq!(cycles, 2051);
+}
+
+#[test]
+fn halt() {
+    // halt
+    let mut cpu = Cpu::default();
+    cpu.memory.load_instructions(&[HALT, NOOP]);
+
+    cpu.run_next_instruction()
+        .expect("Unable to process CPU instructions");
+    assert_eq!(cpu.registers.pc, 1);
+
+    // Stays put until an interrupt is pending
+    for _ in 0..10 {
+        let status = cpu
+            .run_next_instruction()
+            .expect("Unable to process CPU instructions");
+        assert_eq!(status, Status::Cycles(1));
+        assert_eq!(cpu.registers.pc, 1);
+    }
+
+    cpu.memory.memory[INTERRUPT_ENABLE] = 0b00001;
+    cpu.memory.memory[INTERRUPT_FLAG] = 0b00001;
+
+    // IME is off so it wakes without servicing the interrupt
+    cpu.run_next_instruction()
+        .expect("Unable to process CPU instructions");
+    cpu.run_next_instruction()
+        .expect("Unable to process CPU instructions");
+    assert_eq!(cpu.registers.pc, 2);
+    assert_eq!(cpu.memory.memory[INTERRUPT_FLAG], 0b00001);
+}
+
+#[test]
+fn serial_interrupt_vector() {
+    // ei, then the serial interrupt is serviced after the next instruction
+    let mut cpu = Cpu::default();
+    cpu.memory.load_instructions(&[EI, NOOP, NOOP]);
+    cpu.registers.sp = 0x100;
+    cpu.memory.memory[INTERRUPT_ENABLE] = 0b01000;
+    cpu.memory.memory[INTERRUPT_FLAG] = 0b01000;
+
+    cpu.run_num_instructions(2)
+        .expect("Unable to process CPU instructions");
+    assert_eq!(cpu.registers.pc, 0x0058);
+    assert_eq!(cpu.memory.memory[0xFF], 0x00);
+    assert_eq!(cpu.memory.memory[0xFE], 0x02);
+    assert_eq!(cpu.memory.memory[INTERRUPT_FLAG], 0);
+}
+
+#[test]
+fn halt_bug() {
+    // halt with IME off and an interrupt pending
+    let mut cpu = Cpu::default();
+    cpu.memory.load_instructions(&[HALT, 0b00111100, NOOP]);
+    cpu.memory.memory[INTERRUPT_ENABLE] = 0b00100;
+    cpu.memory.memory[INTERRUPT_FLAG] = 0b00100;
+
+    cpu.run_next_instruction()
+        .expect("Unable to process CPU instructions");
+    assert_eq!(cpu.registers.pc, 1);
+
+    // inc a is read twice since PC fails to increment
+    cpu.run_next_instruction()
+        .expect("Unable to process CPU instructions");
+    assert_eq!(cpu.registers.pc, 1);
+    cpu.run_next_instruction()
+        .expect("Unable to process CPU instructions");
+    assert_eq!(cpu.registers.pc, 2);
+    assert_eq!(cpu.registers.a(), 2);
+}
+
+#[test]
+fn halt_ime() {
+    // ei, halt then the interrupt is serviced on wake up
+    let mut cpu = Cpu::default();
+    cpu.memory.load_instructions(&[EI, HALT, NOOP]);
+    cpu.registers.sp = 0x100;
+    cpu.memory.memory[INTERRUPT_ENABLE] = 0b00001;
+
+    cpu.run_num_instructions(3)
+        .expect("Unable to process CPU instructions");
+    assert_eq!(cpu.registers.pc, 2);
+
+    cpu.memory.memory[INTERRUPT_FLAG] = 0b00001;
+    let status = cpu
+        .run_next_instruction()
+        .expect("Unable to process CPU instructions");
+    assert_eq!(status, Status::Cycles(6));
+    assert_eq!(cpu.registers.pc, 0x40);
+    assert_eq!(cpu.memory.memory[INTERRUPT_FLAG], 0);
+    assert_eq!(cpu.memory.memory[0xFE], 2);
+}
+
+#[test]
+fn ei_delay() {
+    let mut cpu = Cpu::default();
+    cpu.memory.load_instructions(&[EI, NOOP, NOOP]);
+    cpu.registers.sp = 0x100;
+    cpu.memory.memory[INTERRUPT_ENABLE] = 0b00100;
+    cpu.memory.memory[INTERRUPT_FLAG] = 0b00100;
+
+    // Nothing is serviced straight after ei
+    cpu.run_next_instruction()
+        .expect("Unable to process CPU instructions");
+    assert_eq!(cpu.registers.pc, 1);
+
+    let status = cpu
+        .run_next_instruction()
+        .expect("Unable to process CPU instructions");
+    assert_eq!(status, Status::Cycles(6));
+    assert_eq!(cpu.registers.pc, 0x50);
+
+    // ei, di never lets an interrupt through
+    let mut cpu = Cpu::default();
+    cpu.memory.load_instructions(&[EI, DI, NOOP]);
+    cpu.memory.memory[INTERRUPT_ENABLE] = 0b00100;
+    cpu.memory.memory[INTERRUPT_FLAG] = 0b00100;
+
+    cpu.run_num_instructions(3)
+        .expect("Unable to process CPU instructions");
+    assert_eq!(cpu.registers.pc, 3);
+}
+
+#[test]
+fn reti() {
+    // reti enables interrupts without a delay
+    let mut cpu = Cpu::default();
+    cpu.memory.load_instructions(&[RETI]);
+    cpu.registers.sp = 0x100;
+    cpu.memory.memory[0x100] = 0x20;
+    cpu.memory.memory[INTERRUPT_ENABLE] = 0b00010;
+    cpu.memory.memory[INTERRUPT_FLAG] = 0b00010;
+
+    cpu.run_next_instruction()
+        .expect("Unable to process CPU instructions");
+    assert_eq!(cpu.registers.pc, 0x48);
+    // Returned to 0x20 before the interrupt pushed it again
+    assert_eq!(cpu.memory.memory[0x100], 0x20);
+}
+
+#[test]
+fn interrupt_dispatch_cancelled() {
+    // Pushing PC's high byte over IE cancels the dispatch
+    let mut cpu = Cpu::default();
+    cpu.memory.load_instructions(&[EI, NOOP]);
+    cpu.registers.sp = 0x0000;
+    cpu.memory.memory[INTERRUPT_ENABLE] = 0b00001;
+    cpu.memory.memory[INTERRUPT_FLAG] = 0b00001;
+
+    cpu.run_num_instructions(2)
+        .expect("Unable to process CPU instructions");
+    assert_eq!(cpu.registers.pc, 0x0000);
+    assert_eq!(cpu.memory.memory[INTERRUPT_ENABLE], 0x00);
+    assert_eq!(cpu.memory.memory[INTERRUPT_FLAG], 0b00001);
+
+    // Running from 0xE000 pushes 0xE0 over IE, which only matches the unused upper bits of IF
+    let mut cpu = Cpu::default();
+    cpu.memory.memory[0xE000] = EI;
+    cpu.memory.memory[0xE001] = NOOP;
+    cpu.registers.pc = 0xE000;
+    cpu.registers.sp = 0x0000;
+    cpu.memory.memory[INTERRUPT_ENABLE] = 0b00001;
+    cpu.memory.memory[INTERRUPT_FLAG] = 0b1110_0001;
+
+    cpu.run_num_instructions(2)
+        .expect("Unable to process CPU instructions");
+    assert_eq!(cpu.registers.pc, 0x0000);
+    assert_eq!(cpu.memory.memory[INTERRUPT_ENABLE], 0xE0);
+    assert_eq!(cpu.memory.memory[INTERRUPT_FLAG], 0b1110_0001);
+}
+
+#[test]
+fn cloned_cpu_runs_independently() {
+    // MBC1 with 8 KiB of RAM, so the cartridge RAM is copied too
+    let cartridge = Cartridge::from_bytes(test_rom(0x03, 0x00, 0x02)).expect("Valid ROM");
+    let mut cpu = Cpu::new(Memory::with_cartridge(cartridge));
+    cpu.memory.set_byte(0x0000, 0x0A);
+    cpu.memory.set_byte(0xA000, 0x12);
+
+    let mut clone = cpu.clone();
+    clone.memory.set_byte(0xA000, 0x34);
+    clone
+        .run_num_instructions(1)
+        .expect("Unable to process CPU instructions");
+
+    assert_eq!(cpu.registers.pc, 0x0000);
+    assert_eq!(clone.registers.pc, 0x0001);
+    assert_eq!(cpu.memory.get_byte(0xA000).expect("Unable to get byte"), 0x12);
+    assert_eq!(clone.memory.get_byte(0xA000).expect("Unable to get byte"), 0x34);
+}
+
 #[test]
 fn ld_r8_r8() {
     // ld r8, r8
@@ -0,0 +1,350 @@
+use std::fmt;
+
+use crate::{
+    instruction::{Instruction, PrefixedInstruction},
+    registers::{Cond, R8, R16, R16Mem, R16Stk},
+};
+
+/// An instruction decoded back into RGBDS syntax
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Disassembled {
+    pub address: u16,
+    /// The opcode and its operands as they are in memory
+    pub bytes: Vec<u8>,
+    pub text: String,
+}
+
+/// `0150  C3 50 01  jp $0150`
+impl fmt::Display for Disassembled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{b:02X}")).collect();
+        write!(
+            f,
+            "{:04X}  {:<8}  {}",
+            self.address,
+            bytes.join(" "),
+            self.text
+        )
+    }
+}
+
+/// Decodes the instruction at the start of `bytes`, which were read from `address`. Opcodes that
+/// don't exist and instructions cut off by the end of `bytes` come out as a `db` of one byte.
+pub fn disassemble_one(bytes: &[u8], address: u16) -> Disassembled {
+    let db = || Disassembled {
+        address,
+        bytes: bytes[..1].to_vec(),
+        text: format!("db ${:02X}", bytes[0]),
+    };
+
+    let Ok(instruction) = Instruction::try_from(bytes[0]) else {
+        return db();
+    };
+    let size = usize::from(instruction.size());
+    if bytes.len() < size {
+        return db();
+    }
+
+    let operands = &bytes[1..size];
+    let imm8 = || format!("${:02X}", operands[0]);
+    let imm16 = || format!("${:04X}", u16::from_le_bytes([operands[0], operands[1]]));
+    let offset = || operands[0] as i8;
+    // Relative jumps are shown with the address they land on
+    let target = || {
+        let next = address.wrapping_add(size as u16);
+        format!("${:04X}", next.wrapping_add_signed(i16::from(offset())))
+    };
+
+    use Instruction::*;
+
+    let text = match instruction {
+        Nop => "nop".to_string(),
+        Halt => "halt".to_string(),
+        Stop => "stop".to_string(),
+        LdR16Imm16 { reg } => format!("ld {}, {}", r16(reg), imm16()),
+        LdR16memA { reg } => format!("ld {}, a", r16mem(reg)),
+        LdAR16mem { reg } => format!("ld a, {}", r16mem(reg)),
+        LdImm16Sp => format!("ld [{}], sp", imm16()),
+        IncR16 { reg } => format!("inc {}", r16(reg)),
+        DecR16 { reg } => format!("dec {}", r16(reg)),
+        AddHlR16 { reg } => format!("add hl, {}", r16(reg)),
+        IncR8 { reg } => format!("inc {}", r8(reg)),
+        DecR8 { reg } => format!("dec {}", r8(reg)),
+        LdR8Imm8 { reg } => format!("ld {}, {}", r8(reg), imm8()),
+        Rlca => "rlca".to_string(),
+        Rrca => "rrca".to_string(),
+        Rla => "rla".to_string(),
+        Rra => "rra".to_string(),
+        Daa => "daa".to_string(),
+        Cpl => "cpl".to_string(),
+        Scf => "scf".to_string(),
+        Ccf => "ccf".to_string(),
+        JrImm8 => format!("jr {}", target()),
+        JrCondImm8 { cond } => format!("jr {}, {}", condition(cond), target()),
+        LdR8R8 { src, dst } => format!("ld {}, {}", r8(dst), r8(src)),
+        AddAR8 { reg, carry } => format!("{} a, {}", if carry { "adc" } else { "add" }, r8(reg)),
+        SubAR8 { reg, carry } => format!("{} a, {}", if carry { "sbc" } else { "sub" }, r8(reg)),
+        AndAR8 { reg } => format!("and a, {}", r8(reg)),
+        XorAR8 { reg } => format!("xor a, {}", r8(reg)),
+        OrAR8 { reg } => format!("or a, {}", r8(reg)),
+        CpAR8 { reg } => format!("cp a, {}", r8(reg)),
+        AddAImm8 { carry } => format!("{} a, {}", if carry { "adc" } else { "add" }, imm8()),
+        SubAImm8 { carry } => format!("{} a, {}", if carry { "sbc" } else { "sub" }, imm8()),
+        AndAImm8 => format!("and a, {}", imm8()),
+        XorAImm8 => format!("xor a, {}", imm8()),
+        OrAImm8 => format!("or a, {}", imm8()),
+        CpAImm8 => format!("cp a, {}", imm8()),
+        RetCond { cond } => format!("ret {}", condition(cond)),
+        Ret => "ret".to_string(),
+        Reti => "reti".to_string(),
+        JpCondImm16 { cond } => format!("jp {}, {}", condition(cond), imm16()),
+        JpImm16 => format!("jp {}", imm16()),
+        JpHl => "jp hl".to_string(),
+        CallCondImm16 { cond } => format!("call {}, {}", condition(cond), imm16()),
+        CallImm16 => format!("call {}", imm16()),
+        RstTgt3 { tgt3 } => format!("rst ${:02X}", tgt3 * 8),
+        PopR16stk { reg } => format!("pop {}", r16stk(reg)),
+        PushR16stk { reg } => format!("push {}", r16stk(reg)),
+        Prefix => match PrefixedInstruction::try_from(operands[0]) {
+            Ok(prefixed) => prefixed_text(prefixed),
+            Err(_) => return db(),
+        },
+        LdhCA => "ldh [c], a".to_string(),
+        LdhImm8A => format!("ldh [$FF{:02X}], a", operands[0]),
+        LdImm16A => format!("ld [{}], a", imm16()),
+        LdhAC => "ldh a, [c]".to_string(),
+        LdhAImm8 => format!("ldh a, [$FF{:02X}]", operands[0]),
+        LdAImm16 => format!("ld a, [{}]", imm16()),
+        AddSpImm8 => format!("add sp, {}", offset()),
+        LdHlSpImm8 => format!("ld hl, sp{:+}", offset()),
+        LdSpHl => "ld sp, hl".to_string(),
+        Di => "di".to_string(),
+        Ei => "ei".to_string(),
+    };
+
+    Disassembled {
+        address,
+        bytes: bytes[..size].to_vec(),
+        text,
+    }
+}
+
+/// Decodes everything in `bytes`, which start at `address`
+pub fn disassemble(bytes: &[u8], address: u16) -> Vec<Disassembled> {
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+
+    while offset < bytes.len() {
+        let instruction = disassemble_one(&bytes[offset..], address.wrapping_add(offset as u16));
+        offset += instruction.bytes.len();
+        instructions.push(instruction);
+    }
+    instructions
+}
+
+fn prefixed_text(instruction: PrefixedInstruction) -> String {
+    use PrefixedInstruction::*;
+
+    match instruction {
+        RlcR8 { reg } => format!("rlc {}", r8(reg)),
+        RrcR8 { reg } => format!("rrc {}", r8(reg)),
+        RlR8 { reg } => format!("rl {}", r8(reg)),
+        RrR8 { reg } => format!("rr {}", r8(reg)),
+        SlaR8 { reg } => format!("sla {}", r8(reg)),
+        SraR8 { reg } => format!("sra {}", r8(reg)),
+        SwapR8 { reg } => format!("swap {}", r8(reg)),
+        SrlR8 { reg } => format!("srl {}", r8(reg)),
+        BitB3R8 { bit, reg } => format!("bit {bit}, {}", r8(reg)),
+        ResB3R8 { bit, reg } => format!("res {bit}, {}", r8(reg)),
+        SetB3R8 { bit, reg } => format!("set {bit}, {}", r8(reg)),
+    }
+}
+
+fn r8(reg: R8) -> &'static str {
+    match reg {
+        R8::B => "b",
+        R8::C => "c",
+        R8::D => "d",
+        R8::E => "e",
+        R8::H => "h",
+        R8::L => "l",
+        R8::HL => "[hl]",
+        R8::A => "a",
+    }
+}
+
+fn r16(reg: R16) -> &'static str {
+    match reg {
+        R16::BC => "bc",
+        R16::DE => "de",
+        R16::HL => "hl",
+        R16::SP => "sp",
+    }
+}
+
+fn r16mem(reg: R16Mem) -> &'static str {
+    match reg {
+        R16Mem::BC => "[bc]",
+        R16Mem::DE => "[de]",
+        R16Mem::HLI => "[hl+]",
+        R16Mem::HLD => "[hl-]",
+    }
+}
+
+fn r16stk(reg: R16Stk) -> &'static str {
+    match reg {
+        R16Stk::BC => "bc",
+        R16Stk::DE => "de",
+        R16Stk::HL => "hl",
+        R16Stk::AF => "af",
+    }
+}
+
+fn condition(cond: Cond) -> &'static str {
+    match cond {
+        Cond::NZ => "nz",
+        Cond::Z => "z",
+        Cond::NC => "nc",
+        Cond::C => "c",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        disassembler::{disassemble, disassemble_one},
+        instructions::STOP,
+    };
+
+    #[test]
+    fn operands() {
+        #[rustfmt::skip]
+        let program = [
+            0x00,
+            0x3E, 0x12,
+            0x21, 0x34, 0x12,
+            0x18, 0xFE,
+            0x20, 0x05,
+            0xE0, 0x40,
+            0xF0, 0x44,
+            0xE2,
+            0xEA, 0x00, 0xC0,
+            0x08, 0x00, 0xC0,
+            0xE8, 0xFE,
+            0xF8, 0x02,
+            0x22,
+            0x3A,
+            0x86,
+            0x9F,
+            0xCE, 0x01,
+            0xDA, 0x50, 0x01,
+            0xCD, 0x00, 0x40,
+            0xFF,
+            0xF5,
+            0xCB, 0x37,
+            0xCB, 0x7E,
+            0xCB, 0xC1,
+            0x10, 0x00,
+            0x40,
+            0x76,
+        ];
+
+        let text: Vec<_> = disassemble(&program, 0x0150)
+            .into_iter()
+            .map(|i| i.text)
+            .collect();
+        assert_eq!(
+            text,
+            [
+                "nop",
+                "ld a, $12",
+                "ld hl, $1234",
+                "jr $0156",
+                "jr nz, $015F",
+                "ldh [$FF40], a",
+                "ldh a, [$FF44]",
+                "ldh [c], a",
+                "ld [$C000], a",
+                "ld [$C000], sp",
+                "add sp, -2",
+                "ld hl, sp+2",
+                "ld [hl+], a",
+                "ld a, [hl-]",
+                "add a, [hl]",
+                "sbc a, a",
+                "adc a, $01",
+                "jp c, $0150",
+                "call $4000",
+                "rst $38",
+                "push af",
+                "swap a",
+                "bit 7, [hl]",
+                "set 0, c",
+                "stop",
+                "ld b, b",
+                "halt",
+            ]
+        );
+    }
+
+    #[test]
+    fn raw_bytes() {
+        let instruction = disassemble_one(&[0xC3, 0x50, 0x01, 0x00], 0x0100);
+        assert_eq!(instruction.bytes, [0xC3, 0x50, 0x01]);
+        assert_eq!(instruction.to_string(), "0100  C3 50 01  jp $0150");
+
+        let instruction = disassemble_one(&[0xAF], 0xFFFF);
+        assert_eq!(instruction.to_string(), "FFFF  AF        xor a, a");
+
+        // Opcodes that don't exist and cut off operands are left as data
+        let instructions = disassemble(&[0xD3, 0xCD, 0x00], 0x4000);
+        assert_eq!(instructions[0].to_string(), "4000  D3        db $D3");
+        assert_eq!(instructions[1].text, "db $CD");
+        assert_eq!(instructions[2].text, "nop");
+    }
+
+    #[test]
+    fn every_opcode() {
+        let mut lengths = 0;
+        for opcode in 0..=0xFF {
+            let instruction = disassemble_one(&[opcode, 0x00, 0x00], 0x0000);
+            lengths += instruction.bytes.len();
+
+            // The 11 holes in the opcode table
+            let invalid = [
+                0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD,
+            ];
+            assert_eq!(
+                instruction.text.starts_with("db"),
+                invalid.contains(&opcode),
+                "{opcode:#04x}"
+            );
+        }
+        // 27 opcodes take an 8 bit operand and 17 a 16 bit one
+        assert_eq!(lengths, 256 + 27 + 17 * 2);
+    }
+
+    #[test]
+    fn assembles_back() {
+        // Every opcode, including the prefixed ones, with operands that are negative as offsets.
+        // The byte after stop isn't shown, so it has to be the 0x00 that gets assembled.
+        let mut bytes = Vec::new();
+        let mut source = String::new();
+        let unprefixed = (0..=0xFF).map(|opcode| match opcode {
+            STOP => [opcode, 0x00, 0x00],
+            _ => [opcode, 0xFE, 0xC0],
+        });
+        let prefixed = (0..=0xFF).map(|opcode| [0xCB, opcode, 0x00]);
+        for instruction in unprefixed.chain(prefixed) {
+            let instruction = disassemble_one(&instruction, bytes.len() as u16);
+            bytes.extend(&instruction.bytes);
+            source.push_str(&instruction.text);
+            source.push('\n');
+        }
+
+        assert_eq!(
+            mobulator_asm::assemble(&source).expect("Unable to assemble the disassembly"),
+            bytes
+        );
+    }
+}
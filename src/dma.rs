@@ -0,0 +1,356 @@
+use crate::{
+    memory::Region,
+    ppu::{OAM_SIZE, VRAM_SIZE},
+    utils::BitExt,
+};
+
+pub const DMA: u16 = 0xFF46;
+pub const HDMA1: u16 = 0xFF51;
+pub const HDMA2: u16 = 0xFF52;
+pub const HDMA3: u16 = 0xFF53;
+pub const HDMA4: u16 = 0xFF54;
+pub const HDMA5: u16 = 0xFF55;
+
+/// VRAM DMA copies in blocks of 16 bytes
+const BLOCK_SIZE: u16 = 0x10;
+
+/// The CPU shares the external bus with the cartridge and WRAM, and has a separate one for VRAM.
+/// While a DMA is reading from one of them the CPU can't use it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bus {
+    External,
+    Video,
+}
+
+impl Bus {
+    pub fn of(addr: u16) -> Option<Bus> {
+        match Region::from(addr) {
+            Region::Rom | Region::CartridgeRam | Region::Wram | Region::EchoRam => {
+                Some(Bus::External)
+            }
+            Region::Vram => Some(Bus::Video),
+            _ => None,
+        }
+    }
+}
+
+/// Writing XX to 0xFF46 copies 0xXX00 - 0xXX9F into OAM, a byte per M-cycle, starting a cycle
+/// after the write.
+// https://gbdev.io/pandocs/OAM_DMA_Transfer.html
+#[derive(Debug, Clone, Default)]
+pub struct OamDma {
+    register: u8,
+    /// Source of a transfer that starts on the next cycle
+    pending: Option<u16>,
+    source: u16,
+    /// Bytes copied so far, None when idle
+    progress: Option<u8>,
+    /// The byte last put on the bus, which is what conflicting reads see
+    pub last_byte: u8,
+}
+
+impl OamDma {
+    pub fn read(&self) -> u8 {
+        self.register
+    }
+
+    /// Sets the register without starting a transfer
+    pub fn set_register(&mut self, value: u8) {
+        self.register = value;
+    }
+
+    pub fn start(&mut self, value: u8) {
+        self.register = value;
+        self.pending = Some(u16::from(value) << 8);
+    }
+
+    pub fn active(&self) -> bool {
+        self.progress.is_some()
+    }
+
+    pub fn source_bus(&self) -> Option<Bus> {
+        self.progress.and(Bus::of(self.source))
+    }
+
+    /// Advances by an M-cycle, returning the source address and OAM index of the byte to copy
+    pub fn step(&mut self) -> Option<(u16, usize)> {
+        let transfer = self
+            .progress
+            .map(|i| (self.source + u16::from(i), usize::from(i)));
+
+        self.progress = self
+            .progress
+            .map(|i| i + 1)
+            .filter(|&i| usize::from(i) < OAM_SIZE);
+
+        // A write during a transfer restarts it
+        if let Some(source) = self.pending.take() {
+            // Sources past WRAM read from WRAM instead
+            self.source = if source >= 0xE000 {
+                source - 0x2000
+            } else {
+                source
+            };
+            self.progress = Some(0);
+        }
+
+        transfer
+    }
+}
+
+/// ┌───────┬───────┬─────────────────────────────┐
+/// │ HDMA5 │   7   │           6 - 0             │
+/// ├───────┼───────┼─────────────────────────────┤
+/// │ Write │ Mode  │ Blocks to copy - 1          │
+/// │ Read  │ Idle  │ Blocks left - 1             │
+/// └───────┴───────┴─────────────────────────────┘
+///
+/// CGB only. Copies from ROM, cartridge RAM or WRAM to the selected VRAM bank. General purpose
+/// DMA copies everything at once while the CPU waits, HBlank DMA copies a block at the start of
+/// each HBlank and the CPU only waits on that block. Either way a block takes 8 M-cycles at normal
+/// speed.
+// https://gbdev.io/pandocs/CGB_Registers.html#lcd-vram-dma-transfers
+#[derive(Debug, Clone)]
+pub struct VramDma {
+    source: u16,
+    /// Offset into VRAM
+    destination: u16,
+    /// Blocks left minus 1, as read back from HDMA5
+    remaining: u8,
+    /// Set for HBlank DMA, cleared when it's done or cancelled
+    hblank_mode: bool,
+    /// Bytes copied of the current block, None between blocks
+    block: Option<u16>,
+    in_hblank: bool,
+}
+
+impl Default for VramDma {
+    fn default() -> Self {
+        Self {
+            source: 0,
+            destination: 0,
+            // Reads 0xFF until the first transfer
+            remaining: 0x7F,
+            hblank_mode: false,
+            block: None,
+            in_hblank: false,
+        }
+    }
+}
+
+impl VramDma {
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            // Bit 7 reads 0 while HBlank DMA still has blocks to go
+            HDMA5 => (u8::from(!self.hblank_mode) << 7) | self.remaining,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            HDMA1 => self.source = (self.source & 0x00FF) | (u16::from(value) << 8),
+            HDMA2 => self.source = (self.source & 0xFF00) | u16::from(value & 0xF0),
+            HDMA3 => {
+                self.destination = (self.destination & 0x00FF) | (u16::from(value & 0x1F) << 8);
+            }
+            HDMA4 => self.destination = (self.destination & 0xFF00) | u16::from(value & 0xF0),
+            // Clearing bit 7 during HBlank DMA cancels it, whatever is left stays readable
+            HDMA5 if self.hblank_mode && !value.is_bit_set(7) => self.hblank_mode = false,
+            HDMA5 => {
+                self.remaining = value & 0x7F;
+                self.hblank_mode = value.is_bit_set(7);
+                // Starting HBlank DMA partway through an HBlank copies a block straight away
+                if !self.hblank_mode || self.in_hblank {
+                    self.block = Some(0);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether the CPU is held up while a block is copied
+    pub fn copying(&self) -> bool {
+        self.block.is_some()
+    }
+
+    /// Tells HBlank DMA whether the PPU is in HBlank, a block is copied each time one starts
+    pub fn set_hblank(&mut self, in_hblank: bool) {
+        if in_hblank && !self.in_hblank && self.hblank_mode && self.block.is_none() {
+            self.block = Some(0);
+        }
+        self.in_hblank = in_hblank;
+    }
+
+    /// Advances by half an M-cycle at normal speed, returning the source address and the VRAM
+    /// offset of the byte to copy
+    pub fn step(&mut self) -> Option<(u16, u16)> {
+        let copied = self.block?;
+        let transfer = (self.source, self.destination);
+        self.source = self.source.wrapping_add(1);
+        self.destination += 1;
+
+        if copied + 1 < BLOCK_SIZE {
+            self.block = Some(copied + 1);
+            return Some(transfer);
+        }
+
+        self.block = None;
+        // Running off the end of VRAM stops the transfer early
+        let overflowed = usize::from(self.destination) >= VRAM_SIZE;
+        self.destination %= VRAM_SIZE as u16;
+
+        if self.remaining == 0 || overflowed {
+            self.remaining = 0x7F;
+            self.hblank_mode = false;
+        } else {
+            self.remaining -= 1;
+            // General purpose DMA carries straight on with the next block
+            if !self.hblank_mode {
+                self.block = Some(0);
+            }
+        }
+        Some(transfer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        cartridge::{Cartridge, HEADER_CHECKSUM, header_checksum, test_rom},
+        dma::{DMA, HDMA1, HDMA2, HDMA3, HDMA4, HDMA5},
+        memory::Memory,
+        ppu::{LCDC, LY, Mode, VBK},
+    };
+
+    fn cgb_memory() -> Memory {
+        let mut rom = test_rom(0x00, 0x00, 0x00);
+        rom[0x0143] = 0xC0;
+        rom[HEADER_CHECKSUM] = header_checksum(&rom);
+        let mut mem = Memory::with_cartridge(Cartridge::from_bytes(rom).expect("Valid ROM"));
+        for i in 0..0x100 {
+            mem.set_byte(0xC000 + i, i as u8);
+        }
+        // From 0xC000 to 0x8800, the low nibbles are ignored
+        for (addr, value) in [(HDMA1, 0xC0), (HDMA2, 0x0F), (HDMA3, 0xE8), (HDMA4, 0x0F)] {
+            mem.set_byte(addr, value);
+        }
+        mem
+    }
+
+    #[test]
+    fn oam_dma() {
+        let mut mem = Memory::with_cartridge(
+            Cartridge::from_bytes(test_rom(0x00, 0x00, 0x00)).expect("Valid ROM"),
+        );
+        for i in 0..0xA0 {
+            mem.set_byte(0xC100 + i, i as u8);
+        }
+        mem.set_byte(0xFF80, 0x42);
+
+        mem.set_byte(DMA, 0xC1);
+        assert_eq!(mem.get_byte(DMA).expect("Unable to get byte"), 0xC1);
+        mem.tick(2);
+
+        // Only HRAM and I/O are usable while the transfer is going
+        assert_eq!(mem.get_byte(0xFF80).expect("Unable to get byte"), 0x42);
+        assert_eq!(mem.get_byte(0xFE00).expect("Unable to get byte"), 0xFF);
+        // WRAM is on the same bus so reads see whatever DMA is reading
+        assert_eq!(mem.get_byte(0xC000).expect("Unable to get byte"), 0x00);
+        mem.tick(1);
+        assert_eq!(mem.get_byte(0xC000).expect("Unable to get byte"), 0x01);
+        mem.set_byte(0xC000, 0x99);
+        // VRAM is on the other bus
+        mem.set_byte(0x8000, 0x77);
+        assert_eq!(mem.get_byte(0x8000).expect("Unable to get byte"), 0x77);
+
+        mem.tick(157);
+        assert_eq!(mem.get_byte(0xFE9F).expect("Unable to get byte"), 0xFF);
+        mem.tick(1);
+        assert_eq!(mem.get_byte(0xFE9F).expect("Unable to get byte"), 0x9F);
+        assert_eq!(mem.ppu.oam[0x10], 0x10);
+        assert_eq!(mem.get_byte(0xC000).expect("Unable to get byte"), 0x00);
+    }
+
+    #[test]
+    fn general_purpose_dma() {
+        let mut mem = cgb_memory();
+        assert_eq!(mem.get_byte(HDMA5).expect("Unable to get byte"), 0xFF);
+        mem.set_byte(VBK, 0x01);
+
+        // 3 blocks take 24 M-cycles, the CPU can't do anything until they're done
+        mem.set_byte(HDMA5, 0x02);
+        assert!(mem.vram_dma_stalls());
+        mem.tick(23);
+        assert!(mem.vram_dma_stalls());
+        assert_eq!(mem.ppu.vram[0x282E], 0x00);
+        mem.tick(1);
+        assert!(!mem.vram_dma_stalls());
+        assert_eq!(mem.get_byte(HDMA5).expect("Unable to get byte"), 0xFF);
+
+        assert_eq!(&mem.ppu.vram[0x2800..0x2803], &[0x00, 0x01, 0x02]);
+        assert_eq!(mem.ppu.vram[0x282F], 0x2F);
+        assert_eq!(mem.ppu.vram[0x2830], 0x00);
+        assert_eq!(mem.ppu.vram[0x0800], 0x00);
+
+        // Twice the cycles in double speed, it's the same length of time
+        mem.double_speed = true;
+        mem.set_byte(HDMA5, 0x00);
+        mem.tick(15);
+        assert!(mem.vram_dma_stalls());
+        mem.tick(1);
+        assert!(!mem.vram_dma_stalls());
+        // Carries on from where the last one stopped
+        assert_eq!(mem.ppu.vram[0x283F], 0x3F);
+    }
+
+    #[test]
+    fn hblank_dma() {
+        let mut mem = cgb_memory();
+        mem.set_byte(LCDC, 0x80);
+        mem.set_byte(HDMA5, 0x83);
+        assert_eq!(mem.get_byte(HDMA5).expect("Unable to get byte"), 0x03);
+        assert!(!mem.vram_dma_stalls());
+
+        // A block per HBlank
+        for line in 0..2 {
+            while mem.ppu.mode() != Mode::HBlank {
+                mem.tick(1);
+            }
+            assert!(mem.vram_dma_stalls());
+            mem.tick(8);
+            assert!(!mem.vram_dma_stalls());
+            assert_eq!(
+                mem.get_byte(HDMA5).expect("Unable to get byte"),
+                0x02 - line
+            );
+            while mem.ppu.mode() == Mode::HBlank {
+                mem.tick(1);
+            }
+        }
+        assert_eq!(mem.get_byte(LY).expect("Unable to get byte"), 2);
+        assert_eq!(mem.ppu.vram[0x081F], 0x1F);
+        assert_eq!(mem.ppu.vram[0x0820], 0x00);
+
+        // Cancelling leaves what's left readable with bit 7 set
+        mem.set_byte(HDMA5, 0x00);
+        assert_eq!(mem.get_byte(HDMA5).expect("Unable to get byte"), 0x81);
+        while mem.get_byte(LY).expect("Unable to get byte") < 4 {
+            mem.tick(1);
+        }
+        assert!(!mem.vram_dma_stalls());
+        assert_eq!(mem.ppu.vram[0x0820], 0x00);
+
+        // Starting during HBlank doesn't wait for the next one
+        while mem.ppu.mode() != Mode::HBlank {
+            mem.tick(1);
+        }
+        mem.tick(1);
+        mem.set_byte(HDMA5, 0x81);
+        assert!(mem.vram_dma_stalls());
+        mem.tick(8);
+        assert!(!mem.vram_dma_stalls());
+        assert_eq!(mem.get_byte(HDMA5).expect("Unable to get byte"), 0x00);
+        assert_eq!(mem.ppu.vram[0x082F], 0x2F);
+    }
+}
@@ -10,6 +10,7 @@ use crate::{
 pub enum Instruction {
     Nop,
     Halt,
+    Stop,
     LdR16Imm16 { reg: R16 },
     LdR16memA { reg: R16Mem },
     LdAR16mem { reg: R16Mem },
@@ -77,6 +78,9 @@ impl TryFrom<u8> for Instruction {
             NOOP => Instruction::Nop,
             HALT => Instruction::Halt,
 
+            // stop is followed by a byte that's ignored
+            STOP => Instruction::Stop,
+
             // ld r16, imm16
             opcode_match!(00__0001) => Instruction::LdR16Imm16 {
                 reg: instruction.p().try_into()?,
@@ -295,10 +299,12 @@ impl TryFrom<u8> for Instruction {
 }
 
 impl Instruction {
-    pub fn cycles(&self) -> u8 {
+    /// M-cycles spent, conditional branches take longer when the condition is met
+    pub fn cycles(&self, branch_taken: bool) -> u8 {
         match self {
             Instruction::Nop => 1,
             Instruction::Halt => 1,
+            Instruction::Stop => 1,
             Instruction::LdR16Imm16 { .. } => 3,
             Instruction::LdR16memA { .. } => 2,
             Instruction::LdAR16mem { .. } => 2,
@@ -321,6 +327,7 @@ impl Instruction {
             Instruction::Scf => 1,
             Instruction::Ccf => 1,
             Instruction::JrImm8 => 3,
+            Instruction::JrCondImm8 { .. } if branch_taken => 3,
             Instruction::JrCondImm8 { .. } => 2,
             Instruction::LdR8R8 { src: R8::HL, .. } => 2,
             Instruction::LdR8R8 { dst: R8::HL, .. } => 2,
@@ -343,12 +350,15 @@ impl Instruction {
             Instruction::XorAImm8 => 2,
             Instruction::OrAImm8 => 2,
             Instruction::CpAImm8 => 2,
+            Instruction::RetCond { .. } if branch_taken => 5,
             Instruction::RetCond { .. } => 2,
             Instruction::Ret => 4,
             Instruction::Reti => 4,
+            Instruction::JpCondImm16 { .. } if branch_taken => 4,
             Instruction::JpCondImm16 { .. } => 3,
             Instruction::JpImm16 => 4,
             Instruction::JpHl => 1,
+            Instruction::CallCondImm16 { .. } if branch_taken => 6,
             Instruction::CallCondImm16 { .. } => 3,
             Instruction::CallImm16 => 6,
             Instruction::RstTgt3 { .. } => 4,
@@ -368,6 +378,36 @@ impl Instruction {
             Instruction::Ei => 1,
         }
     }
+
+    /// Bytes taken up by the opcode and its operands, a prefixed instruction is always 2
+    pub fn size(&self) -> u16 {
+        match self {
+            Instruction::LdR8Imm8 { .. }
+            | Instruction::JrImm8
+            | Instruction::JrCondImm8 { .. }
+            | Instruction::AddAImm8 { .. }
+            | Instruction::SubAImm8 { .. }
+            | Instruction::AndAImm8
+            | Instruction::XorAImm8
+            | Instruction::OrAImm8
+            | Instruction::CpAImm8
+            | Instruction::LdhImm8A
+            | Instruction::LdhAImm8
+            | Instruction::AddSpImm8
+            | Instruction::LdHlSpImm8
+            | Instruction::Stop
+            | Instruction::Prefix => 2,
+            Instruction::LdR16Imm16 { .. }
+            | Instruction::LdImm16Sp
+            | Instruction::JpCondImm16 { .. }
+            | Instruction::JpImm16
+            | Instruction::CallCondImm16 { .. }
+            | Instruction::CallImm16
+            | Instruction::LdImm16A
+            | Instruction::LdAImm16 => 3,
+            _ => 1,
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
@@ -1,5 +1,6 @@
 pub const NOOP: u8 = 0b00000000;
 pub const HALT: u8 = 0b01110110;
+pub const STOP: u8 = 0b00010000;
 pub const LD_IMM16_SP: u8 = 0b00001000;
 pub const LD_HL_IMM8: u8 = 0b00110110;
 pub const RLCA: u8 = 0b00000111;
@@ -0,0 +1,235 @@
+use crate::{
+    memory::{InterruptByte, InterruptType},
+    utils::BitExt,
+};
+
+pub const P1: u16 = 0xFF00;
+/// An SGB in multiplayer mode can read up to 4 controllers
+pub const MAX_PLAYERS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    /// Line in P1 the button pulls low when its group is selected
+    fn bit(&self) -> u32 {
+        match self {
+            Button::Right | Button::A => 0,
+            Button::Left | Button::B => 1,
+            Button::Up | Button::Select => 2,
+            Button::Down | Button::Start => 3,
+        }
+    }
+
+    fn is_direction(&self) -> bool {
+        matches!(
+            self,
+            Button::Right | Button::Left | Button::Up | Button::Down
+        )
+    }
+}
+
+/// ┌────┬───┬───┬──────┬──────┬──────────┬────────┬───────┬────────┐
+/// │ P1 │ 7 │ 6 │  5   │  4   │    3     │   2    │   1   │   0    │
+/// ├────┼───┼───┼──────┼──────┼──────────┼────────┼───────┼────────┤
+/// │    │   │   │Select│Select│Start/Down│Select/ │ B/Left│A/Right │
+/// │    │   │   │action│d-pad │          │  Up    │       │        │
+/// └────┴───┴───┴──────┴──────┴──────────┴────────┴───────┴────────┘
+///
+/// Everything is active low, a group is selected by writing 0 to its bit and pressed buttons
+/// in selected groups read as 0.
+// https://gbdev.io/pandocs/Joypad_Input.html
+#[derive(Debug, Clone)]
+pub struct Joypad {
+    select: u8,
+    /// Pressed buttons as set bits, in P1 order, for each controller
+    directions: [u8; MAX_PLAYERS],
+    actions: [u8; MAX_PLAYERS],
+    /// Controllers being read, only ever more than 1 on an SGB
+    players: usize,
+    /// Controller P1 is currently reading
+    player: usize,
+    /// Input lines as of the last tick, to catch them going low
+    lines: u8,
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Self {
+            select: 0x30,
+            directions: [0; MAX_PLAYERS],
+            actions: [0; MAX_PLAYERS],
+            players: 1,
+            player: 0,
+            lines: 0x0F,
+        }
+    }
+}
+
+impl Joypad {
+    pub fn press(&mut self, button: Button) {
+        self.press_player(0, button);
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.release_player(0, button);
+    }
+
+    /// Presses a button on one of the extra controllers an SGB can read, players count from 0
+    pub fn press_player(&mut self, player: usize, button: Button) {
+        self.set_button(player, button, true);
+    }
+
+    pub fn release_player(&mut self, player: usize, button: Button) {
+        self.set_button(player, button, false);
+    }
+
+    fn set_button(&mut self, player: usize, button: Button, pressed: bool) {
+        let player = player.min(MAX_PLAYERS - 1);
+        let group = if button.is_direction() {
+            &mut self.directions[player]
+        } else {
+            &mut self.actions[player]
+        };
+        group.set_bit(button.bit(), pressed);
+    }
+
+    pub fn players(&self) -> usize {
+        self.players
+    }
+
+    /// Set by the SGB's MLT_REQ command, changing it starts again from the first controller
+    pub fn set_players(&mut self, players: usize) {
+        let players = players.clamp(1, MAX_PLAYERS);
+        if players != self.players {
+            self.players = players;
+            self.player = 0;
+        }
+    }
+
+    fn input_lines(&self) -> u8 {
+        // With neither group selected the lines give the controller ID, 0xF for the first
+        if self.players > 1 && self.select == 0x30 {
+            return 0x0F - self.player as u8;
+        }
+
+        let mut lines = 0x0F;
+        if !self.select.is_bit_set(4) {
+            lines &= !self.directions[self.player];
+        }
+        if !self.select.is_bit_set(5) {
+            lines &= !self.actions[self.player];
+        }
+        lines & 0x0F
+    }
+
+    pub fn read(&self) -> u8 {
+        0xC0 | self.select | self.input_lines()
+    }
+
+    pub fn write(&mut self, value: u8) {
+        let value = value & 0x30;
+        // In multiplayer mode the SGB moves on to the next controller when P15 goes high
+        if self.players > 1 && !self.select.is_bit_set(5) && value.is_bit_set(5) {
+            self.player = (self.player + 1) % self.players;
+        }
+        self.select = value;
+    }
+
+    /// Selects lines without moving on to the next player
+    pub fn set_register(&mut self, value: u8) {
+        self.select = value & 0x30;
+    }
+
+    /// Raises the joypad interrupt when any of the input lines goes from high to low
+    pub fn tick(&mut self, interrupt_flag: &mut u8) {
+        let lines = self.input_lines();
+        if self.lines & !lines != 0 {
+            InterruptByte(interrupt_flag).set_flag(InterruptType::Joypad, true);
+        }
+        self.lines = lines;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        cartridge::{Cartridge, test_rom},
+        cpu::Cpu,
+        instructions::{NOOP, STOP},
+        joypad::{Button, Joypad, P1},
+        memory::{INTERRUPT_FLAG, Memory},
+    };
+
+    #[test]
+    fn select_lines() {
+        let mut joypad = Joypad::default();
+        joypad.press(Button::Start);
+        joypad.press(Button::Left);
+        assert_eq!(joypad.read(), 0xFF);
+
+        joypad.write(0x20);
+        assert_eq!(joypad.read(), 0b1110_1101);
+        joypad.write(0x10);
+        assert_eq!(joypad.read(), 0b1101_0111);
+        joypad.write(0x00);
+        assert_eq!(joypad.read(), 0b1100_0101);
+
+        joypad.release(Button::Start);
+        assert_eq!(joypad.read(), 0b1100_1101);
+    }
+
+    #[test]
+    fn interrupt_on_press() {
+        let mut joypad = Joypad::default();
+        let mut iflag = 0;
+
+        // Buttons in unselected groups can't pull anything low
+        joypad.press(Button::A);
+        joypad.tick(&mut iflag);
+        assert_eq!(iflag, 0);
+
+        // Selecting the group with A held is a high to low transition too
+        joypad.write(0x10);
+        joypad.tick(&mut iflag);
+        assert_eq!(iflag, 0b10000);
+
+        // Holding doesn't fire it again
+        let mut iflag = 0;
+        joypad.tick(&mut iflag);
+        assert_eq!(iflag, 0);
+
+        joypad.press(Button::B);
+        joypad.tick(&mut iflag);
+        assert_eq!(iflag, 0b10000);
+    }
+
+    #[test]
+    fn wakes_from_stop() {
+        let cartridge = Cartridge::from_bytes(test_rom(0x00, 0x00, 0x00)).expect("Valid ROM");
+        let mut cpu = Cpu::new(Memory::with_cartridge(cartridge));
+        cpu.registers.pc = 0xC000;
+        cpu.memory.set_byte(0xC000, STOP);
+        cpu.memory.set_byte(0xC002, NOOP);
+        cpu.memory.set_byte(P1, 0x10);
+
+        cpu.run_num_instructions(3)
+            .expect("Unable to process CPU instructions");
+        assert_eq!(cpu.registers.pc, 0xC002);
+
+        cpu.memory.joypad.press(Button::Start);
+        cpu.run_num_instructions(1)
+            .expect("Unable to process CPU instructions");
+        assert_eq!(cpu.registers.pc, 0xC003);
+        assert_eq!(cpu.memory.memory[INTERRUPT_FLAG] & 0b10000, 0b10000);
+    }
+}
@@ -1,9 +1,23 @@
+pub mod apu;
+pub mod audio;
+pub mod boot;
 pub mod byte_instruction;
+pub mod cartridge;
 pub mod cpu;
+pub mod disassembler;
+pub mod dma;
 pub mod instruction;
 pub mod instructions;
+pub mod joypad;
+pub mod link;
+pub mod mbc;
 pub mod memory;
+pub mod ppu;
 pub mod registers;
+pub mod rtc;
+pub mod serial;
+pub mod sgb;
+pub mod timer;
 pub mod utils;
 
 #[cfg(test)]
@@ -0,0 +1,253 @@
+use std::{
+    fmt::Debug,
+    io::{self, Read, Write},
+    net::TcpStream,
+};
+
+use crate::serial::SerialDevice;
+
+/// M-cycles each end runs between synchronising, a scanline
+pub const DEFAULT_QUANTUM: u32 = 114;
+
+const SYNC: u8 = 0;
+const TRANSFER: u8 = 1;
+const REPLY: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Message {
+    /// The sender has reached the end of its next quantum
+    Sync,
+    /// The sender drove the clock and shifted out a byte
+    Transfer(u8),
+    /// What was shifted back in answer to a transfer
+    Reply(u8),
+}
+
+/// Connects two machines over any byte stream, usually a `TcpStream` or `UnixStream`.
+///
+/// Both ends exchange a sync message every quantum and wait for the other's before carrying on,
+/// so neither can get more than a quantum ahead. Transfers from the end driving the clock are
+/// only answered by the other end at its quantum boundaries, which keeps the outcome the same no
+/// matter how the two are scheduled on the host.
+#[derive(Debug)]
+pub struct LinkCable<S> {
+    stream: S,
+    quantum: u32,
+    cycles: u32,
+    sent_syncs: u64,
+    received_syncs: u64,
+    /// Our byte while we're waiting on the other end to drive the clock
+    armed: Option<u8>,
+    /// Byte the other end sent us, waiting to be picked up
+    received: Option<u8>,
+    /// Cleared on the first I/O error, after that it behaves like an unplugged cable
+    connected: bool,
+}
+
+impl LinkCable<TcpStream> {
+    pub fn tcp(stream: TcpStream) -> io::Result<Self> {
+        // Every message is tiny and waited on, so Nagle would only add latency
+        stream.set_nodelay(true)?;
+        Ok(Self::new(stream))
+    }
+}
+
+impl<S: Read + Write> LinkCable<S> {
+    pub fn new(stream: S) -> Self {
+        Self::with_quantum(stream, DEFAULT_QUANTUM)
+    }
+
+    pub fn with_quantum(stream: S, quantum: u32) -> Self {
+        Self {
+            stream,
+            quantum: quantum.max(1),
+            cycles: 0,
+            sent_syncs: 0,
+            received_syncs: 0,
+            armed: None,
+            received: None,
+            connected: true,
+        }
+    }
+
+    pub fn connected(&self) -> bool {
+        self.connected
+    }
+
+    fn send(&mut self, message: Message) -> io::Result<()> {
+        let frame = match message {
+            Message::Sync => [SYNC, 0],
+            Message::Transfer(byte) => [TRANSFER, byte],
+            Message::Reply(byte) => [REPLY, byte],
+        };
+        self.stream.write_all(&frame)?;
+        self.stream.flush()
+    }
+
+    fn receive(&mut self) -> io::Result<Message> {
+        let mut frame = [0; 2];
+        self.stream.read_exact(&mut frame)?;
+
+        match frame {
+            [SYNC, _] => Ok(Message::Sync),
+            [TRANSFER, byte] => Ok(Message::Transfer(byte)),
+            [REPLY, byte] => Ok(Message::Reply(byte)),
+            [tag, _] => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown link message {tag:#04x}"),
+            )),
+        }
+    }
+
+    /// Reads a message and deals with it, returning the byte if it was a reply
+    fn handle_next(&mut self) -> io::Result<Option<u8>> {
+        match self.receive()? {
+            Message::Sync => self.received_syncs += 1,
+            Message::Transfer(byte) => {
+                // Only answer if our side set up a transfer, otherwise the line stays high
+                let reply = match self.armed.take() {
+                    Some(ours) => {
+                        self.received = Some(byte);
+                        ours
+                    }
+                    None => 0xFF,
+                };
+                self.send(Message::Reply(reply))?;
+            }
+            Message::Reply(byte) => return Ok(Some(byte)),
+        }
+        Ok(None)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.send(Message::Sync)?;
+        self.sent_syncs += 1;
+
+        while self.received_syncs < self.sent_syncs {
+            self.handle_next()?;
+        }
+        Ok(())
+    }
+
+    fn exchange(&mut self, byte: u8) -> io::Result<u8> {
+        self.send(Message::Transfer(byte))?;
+        loop {
+            if let Some(reply) = self.handle_next()? {
+                return Ok(reply);
+            }
+        }
+    }
+}
+
+impl<S: Read + Write + Debug + Send> SerialDevice for LinkCable<S> {
+    fn transfer(&mut self, byte: u8) -> Option<u8> {
+        if !self.connected {
+            return None;
+        }
+
+        let reply = self.exchange(byte);
+        self.connected = reply.is_ok();
+        reply.ok()
+    }
+
+    fn external_transfer(&mut self, byte: u8) -> Option<u8> {
+        if let Some(received) = self.received.take() {
+            return Some(received);
+        }
+
+        self.armed = Some(byte);
+        None
+    }
+
+    fn tick(&mut self, m_cycles: u32) {
+        if self.connected {
+            self.cycles += m_cycles;
+            while self.cycles >= self.quantum {
+                self.cycles -= self.quantum;
+                if self.sync().is_err() {
+                    self.connected = false;
+                    break;
+                }
+            }
+        }
+
+        // The serial port polls again every tick it's still waiting
+        self.armed = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{TcpListener, TcpStream},
+        thread,
+    };
+
+    use crate::{
+        cartridge::{Cartridge, test_rom},
+        cpu::Cpu,
+        link::LinkCable,
+        memory::Memory,
+        serial::{SB, SerialDevice},
+    };
+
+    fn run(program: &[u8], link: LinkCable<TcpStream>) -> u8 {
+        let cartridge = Cartridge::from_bytes(test_rom(0x00, 0x00, 0x00)).expect("Valid ROM");
+        let mut cpu = Cpu::new(Memory::with_cartridge(cartridge));
+        cpu.memory.serial.connect(Box::new(link));
+
+        for (i, &byte) in program.iter().enumerate() {
+            cpu.memory.set_byte(0xC000 + i as u16, byte);
+        }
+        cpu.registers.pc = 0xC000;
+
+        for _ in 0..3000 {
+            cpu.run_next_instruction()
+                .expect("Unable to process CPU instructions");
+        }
+        cpu.memory.get_byte(SB).expect("Unable to get byte")
+    }
+
+    #[test]
+    fn loopback_trade() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Unable to bind");
+        let addr = listener.local_addr().expect("No local address");
+
+        // ld a, 0x99; ldh [SB], a; ld a, 0x80; ldh [SC], a; jr -2
+        let slave = thread::spawn(move || {
+            let (stream, _) = listener.accept().expect("Unable to accept");
+            let link = LinkCable::tcp(stream).expect("Unable to set up link");
+            run(
+                &[0x3E, 0x99, 0xE0, 0x01, 0x3E, 0x80, 0xE0, 0x02, 0x18, 0xFE],
+                link,
+            )
+        });
+
+        // Waits a while so the other end is ready, then sends 0x42 on the internal clock
+        // ld b, 0; dec b; jr nz, -3; ld a, 0x42; ldh [SB], a; ld a, 0x81; ldh [SC], a; jr -2
+        let stream = TcpStream::connect(addr).expect("Unable to connect");
+        let link = LinkCable::tcp(stream).expect("Unable to set up link");
+        let master = run(
+            &[
+                0x06, 0x00, 0x05, 0x20, 0xFD, 0x3E, 0x42, 0xE0, 0x01, 0x3E, 0x81, 0xE0, 0x02, 0x18,
+                0xFE,
+            ],
+            link,
+        );
+
+        assert_eq!(master, 0x99);
+        assert_eq!(slave.join().expect("Slave panicked"), 0x42);
+    }
+
+    #[test]
+    fn unplugged() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Unable to bind");
+        let stream = TcpStream::connect(listener.local_addr().expect("No local address"))
+            .expect("Unable to connect");
+        drop(listener.accept().expect("Unable to accept"));
+
+        let mut link = LinkCable::tcp(stream).expect("Unable to set up link");
+        assert_eq!(link.transfer(0x12), None);
+        assert!(!link.connected());
+    }
+}
@@ -1,13 +1,77 @@
-use mobulator_macros::opcode_match;
+use std::{env, fs, process::ExitCode};
 
-fn main() {
-    let instruction = 1;
-    match instruction {
-        opcode_match!(00__0001) => {
-            println!("It matches");
-        }
+use anyhow::Context;
+use mobulator::disassembler::disassemble;
+
+const USAGE: &str = "Usage: mobulator disasm <rom> [start] [end]
+
+Disassembles the ROM from start up to but not including end, both offsets into the file in hex.
+Lines are prefixed with the bank, as in 01:4000.";
+
+/// ROM banks are mapped at 0x4000 - 0x7FFF, except bank 0 which is always at 0x0000
+const ROM_BANK_SIZE: usize = 0x4000;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let result = match args.first().map(String::as_str) {
+        Some("disasm") => disasm(&args[1..]),
         _ => {
-            println!("naw b");
+            eprintln!("{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn disasm(args: &[String]) -> anyhow::Result<()> {
+    let [path, range @ ..] = args else {
+        anyhow::bail!("{USAGE}");
+    };
+    let rom = fs::read(path).with_context(|| format!("Unable to read {path}"))?;
+
+    let start = range.first().map(|s| parse_offset(s)).transpose()?.unwrap_or(0);
+    let end = range
+        .get(1)
+        .map(|s| parse_offset(s))
+        .transpose()?
+        .unwrap_or(rom.len())
+        .min(rom.len());
+    if start >= end {
+        anyhow::bail!("Nothing to disassemble between {start:#x} and {end:#x}");
+    }
+
+    // Each bank is done on its own so addresses are where the CPU would see them
+    let mut offset = start;
+    while offset < end {
+        let bank = offset / ROM_BANK_SIZE;
+        let bank_end = ((bank + 1) * ROM_BANK_SIZE).min(end);
+        let address = if bank == 0 {
+            offset
+        } else {
+            ROM_BANK_SIZE + offset % ROM_BANK_SIZE
+        };
+
+        for instruction in disassemble(&rom[offset..bank_end], address as u16) {
+            println!("{bank:02X}:{instruction}");
         }
+        offset = bank_end;
     }
+    Ok(())
+}
+
+/// Hex, with or without a leading 0x or $
+fn parse_offset(s: &str) -> anyhow::Result<usize> {
+    let digits = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix('$'))
+        .unwrap_or(s);
+    usize::from_str_radix(digits, 16).with_context(|| format!("Invalid offset: '{s}'"))
 }
@@ -0,0 +1,564 @@
+use std::fmt::Debug;
+
+use crate::rtc::Rtc;
+
+pub const ROM_BANK_SIZE: usize = 0x4000;
+pub const RAM_BANK_SIZE: usize = 0x2000;
+pub const MBC2_RAM_SIZE: usize = 0x200;
+
+/// Memory bank controller sitting between the bus and the cartridge ROM/RAM.
+///
+/// Reads from 0x0000-0x7FFF are translated to an offset in the ROM image, writes to that range
+/// configure the controller. 0xA000-0xBFFF is routed to whatever the controller exposes there
+/// (usually external RAM).
+// https://gbdev.io/pandocs/MBCs.html
+pub trait Mbc: Debug + Send {
+    /// Offset into the ROM image for an address in 0x0000-0x7FFF. May be larger than the image,
+    /// the cartridge wraps it around.
+    fn rom_offset(&self, addr: u16) -> usize;
+
+    fn write_register(&mut self, addr: u16, value: u8);
+
+    fn read_ram(&self, addr: u16) -> u8;
+
+    fn write_ram(&mut self, addr: u16, value: u8);
+
+    /// External RAM as it's laid out in a save file
+    fn ram(&self) -> &[u8];
+
+    fn ram_mut(&mut self) -> &mut [u8];
+
+    fn tick(&mut self, _m_cycles: u32) {}
+
+    fn rtc(&self) -> Option<&Rtc> {
+        None
+    }
+
+    fn rtc_mut(&mut self) -> Option<&mut Rtc> {
+        None
+    }
+
+    /// Copies the controller and its RAM, so a `Cartridge` can be cloned
+    fn box_clone(&self) -> Box<dyn Mbc>;
+}
+
+impl Clone for Box<dyn Mbc> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+fn ram_offset(bank: usize, addr: u16) -> usize {
+    bank * RAM_BANK_SIZE + usize::from(addr - 0xA000)
+}
+
+/// 32 KiB ROM with an optional single bank of RAM
+#[derive(Debug, Clone)]
+pub struct NoMbc {
+    ram: Vec<u8>,
+}
+
+impl NoMbc {
+    pub fn new(ram_size: usize) -> Self {
+        Self {
+            ram: vec![0; ram_size],
+        }
+    }
+}
+
+impl Mbc for NoMbc {
+    fn box_clone(&self) -> Box<dyn Mbc> {
+        Box::new(self.clone())
+    }
+
+    fn rom_offset(&self, addr: u16) -> usize {
+        usize::from(addr)
+    }
+
+    fn write_register(&mut self, _addr: u16, _value: u8) {}
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        self.ram.get(ram_offset(0, addr)).copied().unwrap_or(0xFF)
+    }
+
+    fn write_ram(&mut self, addr: u16, value: u8) {
+        if let Some(byte) = self.ram.get_mut(ram_offset(0, addr)) {
+            *byte = value;
+        }
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+}
+
+/// ┌───────────┬──────────────────────────────────────────┐
+/// │ 0000-1FFF │ RAM enable (0x_A)                        │
+/// │ 2000-3FFF │ ROM bank, lower 5 bits (0 reads as 1)    │
+/// │ 4000-5FFF │ RAM bank or upper 2 ROM bank bits        │
+/// │ 6000-7FFF │ Banking mode select                      │
+/// └───────────┴──────────────────────────────────────────┘
+#[derive(Debug, Clone)]
+pub struct Mbc1 {
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    bank1: u8,
+    bank2: u8,
+    advanced_mode: bool,
+    /// MBC1M multicarts only wire 4 bits of BANK1, so BANK2 shifts in at bit 4 instead of 5
+    multicart: bool,
+}
+
+impl Mbc1 {
+    pub fn new(ram_size: usize, multicart: bool) -> Self {
+        Self {
+            ram: vec![0; ram_size],
+            ram_enabled: false,
+            bank1: 1,
+            bank2: 0,
+            advanced_mode: false,
+            multicart,
+        }
+    }
+
+    fn bank2_shift(&self) -> u32 {
+        if self.multicart { 4 } else { 5 }
+    }
+
+    fn ram_bank(&self) -> usize {
+        if self.advanced_mode {
+            usize::from(self.bank2)
+        } else {
+            0
+        }
+    }
+}
+
+impl Mbc for Mbc1 {
+    fn box_clone(&self) -> Box<dyn Mbc> {
+        Box::new(self.clone())
+    }
+
+    fn rom_offset(&self, addr: u16) -> usize {
+        let bank = if addr < 0x4000 {
+            if self.advanced_mode {
+                usize::from(self.bank2) << self.bank2_shift()
+            } else {
+                0
+            }
+        } else {
+            let bank1 = if self.multicart {
+                self.bank1 & 0x0F
+            } else {
+                self.bank1
+            };
+            (usize::from(self.bank2) << self.bank2_shift()) | usize::from(bank1)
+        };
+
+        bank * ROM_BANK_SIZE + usize::from(addr & 0x3FFF)
+    }
+
+    fn write_register(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+            0x2000..=0x3FFF => self.bank1 = (value & 0x1F).max(1),
+            0x4000..=0x5FFF => self.bank2 = value & 0x03,
+            0x6000..=0x7FFF => self.advanced_mode = value & 0x01 == 1,
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return 0xFF;
+        }
+
+        let offset = ram_offset(self.ram_bank(), addr) % self.ram.len();
+        self.ram[offset]
+    }
+
+    fn write_ram(&mut self, addr: u16, value: u8) {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return;
+        }
+
+        let offset = ram_offset(self.ram_bank(), addr) % self.ram.len();
+        self.ram[offset] = value;
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+}
+
+/// ┌───────────┬──────────────────────────────────────────────────────┐
+/// │ 0000-3FFF │ Address bit 8 clear: RAM enable, set: ROM bank (4b)  │
+/// │ A000-A1FF │ 512 x 4-bit built-in RAM, echoed up to BFFF          │
+/// └───────────┴──────────────────────────────────────────────────────┘
+#[derive(Debug, Clone)]
+pub struct Mbc2 {
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    rom_bank: u8,
+}
+
+impl Default for Mbc2 {
+    fn default() -> Self {
+        Self {
+            ram: vec![0; MBC2_RAM_SIZE],
+            ram_enabled: false,
+            rom_bank: 1,
+        }
+    }
+}
+
+impl Mbc for Mbc2 {
+    fn box_clone(&self) -> Box<dyn Mbc> {
+        Box::new(self.clone())
+    }
+
+    fn rom_offset(&self, addr: u16) -> usize {
+        let bank = if addr < 0x4000 {
+            0
+        } else {
+            usize::from(self.rom_bank)
+        };
+
+        bank * ROM_BANK_SIZE + usize::from(addr & 0x3FFF)
+    }
+
+    fn write_register(&mut self, addr: u16, value: u8) {
+        if addr >= 0x4000 {
+            return;
+        }
+
+        if addr & 0x0100 == 0 {
+            self.ram_enabled = value & 0x0F == 0x0A;
+        } else {
+            self.rom_bank = (value & 0x0F).max(1);
+        }
+    }
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+
+        // Only the low nibble is wired up, the upper bits float high
+        0xF0 | self.ram[usize::from(addr & 0x01FF)]
+    }
+
+    fn write_ram(&mut self, addr: u16, value: u8) {
+        if self.ram_enabled {
+            self.ram[usize::from(addr & 0x01FF)] = value & 0x0F;
+        }
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+}
+
+/// ┌───────────┬───────────────────────────────────┐
+/// │ 0000-1FFF │ RAM enable (0x_A)                 │
+/// │ 2000-3FFF │ ROM bank, 7 bits (0 reads as 1)   │
+/// │ 4000-5FFF │ RAM bank, or RTC register 08-0C   │
+/// │ 6000-7FFF │ Clock latch                       │
+/// └───────────┴───────────────────────────────────┘
+#[derive(Debug, Clone)]
+pub struct Mbc3 {
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    rom_bank: u8,
+    ram_bank: u8,
+    rtc: Option<Rtc>,
+}
+
+impl Mbc3 {
+    pub fn new(ram_size: usize, rtc: Option<Rtc>) -> Self {
+        Self {
+            ram: vec![0; ram_size],
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            rtc,
+        }
+    }
+}
+
+impl Mbc for Mbc3 {
+    fn box_clone(&self) -> Box<dyn Mbc> {
+        Box::new(self.clone())
+    }
+
+    fn rom_offset(&self, addr: u16) -> usize {
+        let bank = if addr < 0x4000 {
+            0
+        } else {
+            usize::from(self.rom_bank)
+        };
+
+        bank * ROM_BANK_SIZE + usize::from(addr & 0x3FFF)
+    }
+
+    fn write_register(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+            0x2000..=0x3FFF => self.rom_bank = (value & 0x7F).max(1),
+            0x4000..=0x5FFF => self.ram_bank = value,
+            0x6000..=0x7FFF => {
+                if let Some(rtc) = &mut self.rtc {
+                    rtc.write_latch(value);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+
+        match (self.ram_bank, &self.rtc) {
+            (0x08..=0x0C, Some(rtc)) => rtc.read(self.ram_bank),
+            (0x00..=0x03, _) if !self.ram.is_empty() => {
+                let offset = ram_offset(usize::from(self.ram_bank), addr) % self.ram.len();
+                self.ram[offset]
+            }
+            _ => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, addr: u16, value: u8) {
+        if !self.ram_enabled {
+            return;
+        }
+
+        match (self.ram_bank, &mut self.rtc) {
+            (0x08..=0x0C, Some(rtc)) => rtc.write(self.ram_bank, value),
+            (0x00..=0x03, _) if !self.ram.is_empty() => {
+                let offset = ram_offset(usize::from(self.ram_bank), addr) % self.ram.len();
+                self.ram[offset] = value;
+            }
+            _ => {}
+        }
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn tick(&mut self, m_cycles: u32) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.tick(m_cycles);
+        }
+    }
+
+    fn rtc(&self) -> Option<&Rtc> {
+        self.rtc.as_ref()
+    }
+
+    fn rtc_mut(&mut self) -> Option<&mut Rtc> {
+        self.rtc.as_mut()
+    }
+}
+
+/// ┌───────────┬──────────────────────────────────────┐
+/// │ 0000-1FFF │ RAM enable (0x0A)                    │
+/// │ 2000-2FFF │ ROM bank, lower 8 bits               │
+/// │ 3000-3FFF │ ROM bank, bit 8                      │
+/// │ 4000-5FFF │ RAM bank (bit 3 drives rumble)       │
+/// └───────────┴──────────────────────────────────────┘
+#[derive(Debug, Clone)]
+pub struct Mbc5 {
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    rom_bank: u16,
+    ram_bank: u8,
+    rumble: bool,
+}
+
+impl Mbc5 {
+    pub fn new(ram_size: usize, rumble: bool) -> Self {
+        Self {
+            ram: vec![0; ram_size],
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            rumble,
+        }
+    }
+
+    fn ram_bank(&self) -> usize {
+        // Rumble carts use bit 3 for the motor so only have 3 bits of RAM bank
+        let mask = if self.rumble { 0x07 } else { 0x0F };
+        usize::from(self.ram_bank & mask)
+    }
+}
+
+impl Mbc for Mbc5 {
+    fn box_clone(&self) -> Box<dyn Mbc> {
+        Box::new(self.clone())
+    }
+
+    fn rom_offset(&self, addr: u16) -> usize {
+        // Unlike the other MBCs bank 0 can be mapped into 0x4000-0x7FFF
+        let bank = if addr < 0x4000 {
+            0
+        } else {
+            usize::from(self.rom_bank)
+        };
+
+        bank * ROM_BANK_SIZE + usize::from(addr & 0x3FFF)
+    }
+
+    fn write_register(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enabled = value == 0x0A,
+            0x2000..=0x2FFF => self.rom_bank = (self.rom_bank & 0x100) | u16::from(value),
+            0x3000..=0x3FFF => {
+                self.rom_bank = (self.rom_bank & 0xFF) | (u16::from(value & 0x01) << 8)
+            }
+            0x4000..=0x5FFF => self.ram_bank = value,
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return 0xFF;
+        }
+
+        let offset = ram_offset(self.ram_bank(), addr) % self.ram.len();
+        self.ram[offset]
+    }
+
+    fn write_ram(&mut self, addr: u16, value: u8) {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return;
+        }
+
+        let offset = ram_offset(self.ram_bank(), addr) % self.ram.len();
+        self.ram[offset] = value;
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        mbc::{Mbc, Mbc1, Mbc2, Mbc3, Mbc5, ROM_BANK_SIZE},
+        rtc::{CYCLES_PER_SECOND, Rtc},
+    };
+
+    #[test]
+    fn mbc1_banking() {
+        let mut mbc = Mbc1::new(0x8000, false);
+
+        // Bank 0 is remapped to 1
+        mbc.write_register(0x2000, 0x00);
+        assert_eq!(mbc.rom_offset(0x4000), ROM_BANK_SIZE);
+
+        // 0x20 can't be selected in the upper area, it becomes 0x21
+        mbc.write_register(0x4000, 0x01);
+        mbc.write_register(0x2000, 0x00);
+        assert_eq!(mbc.rom_offset(0x4000), 0x21 * ROM_BANK_SIZE);
+        assert_eq!(mbc.rom_offset(0x0000), 0);
+
+        // Mode 1 maps BANK2 into the lower area and switches RAM banks
+        mbc.write_register(0x6000, 0x01);
+        assert_eq!(mbc.rom_offset(0x0000), 0x20 * ROM_BANK_SIZE);
+
+        mbc.write_register(0x0000, 0x0A);
+        mbc.write_ram(0xA000, 0x42);
+        mbc.write_register(0x4000, 0x00);
+        assert_eq!(mbc.read_ram(0xA000), 0x00);
+        mbc.write_register(0x4000, 0x01);
+        assert_eq!(mbc.read_ram(0xA000), 0x42);
+
+        mbc.write_register(0x0000, 0x00);
+        assert_eq!(mbc.read_ram(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn mbc1_multicart() {
+        let mut mbc = Mbc1::new(0, true);
+
+        mbc.write_register(0x2000, 0x13);
+        mbc.write_register(0x4000, 0x02);
+        assert_eq!(mbc.rom_offset(0x4000), 0x23 * ROM_BANK_SIZE);
+
+        mbc.write_register(0x6000, 0x01);
+        assert_eq!(mbc.rom_offset(0x0000), 0x20 * ROM_BANK_SIZE);
+    }
+
+    #[test]
+    fn mbc2_ram_and_banking() {
+        let mut mbc = Mbc2::default();
+
+        // Address bit 8 picks which register is written
+        mbc.write_register(0x0100, 0x05);
+        assert_eq!(mbc.rom_offset(0x4000), 5 * ROM_BANK_SIZE);
+        mbc.write_register(0x0000, 0x0A);
+
+        mbc.write_ram(0xA001, 0xAB);
+        assert_eq!(mbc.read_ram(0xA001), 0xFB);
+        // Echoed every 512 bytes
+        assert_eq!(mbc.read_ram(0xA201), 0xFB);
+    }
+
+    #[test]
+    fn mbc3_rtc_registers() {
+        let mut mbc = Mbc3::new(0x8000, Some(Rtc::default()));
+        mbc.write_register(0x0000, 0x0A);
+
+        mbc.write_register(0x4000, 0x09);
+        mbc.write_ram(0xA000, 42);
+        mbc.tick(CYCLES_PER_SECOND * 60);
+
+        mbc.write_register(0x6000, 0x00);
+        mbc.write_register(0x6000, 0x01);
+        assert_eq!(mbc.read_ram(0xA000), 43);
+
+        // Switching back to a RAM bank
+        mbc.write_register(0x4000, 0x00);
+        mbc.write_ram(0xA000, 7);
+        assert_eq!(mbc.read_ram(0xA000), 7);
+    }
+
+    #[test]
+    fn mbc5_nine_bit_bank() {
+        let mut mbc = Mbc5::new(0, false);
+
+        mbc.write_register(0x2000, 0x00);
+        assert_eq!(mbc.rom_offset(0x4000), 0);
+
+        mbc.write_register(0x2000, 0x34);
+        mbc.write_register(0x3000, 0x01);
+        assert_eq!(mbc.rom_offset(0x4000), 0x134 * ROM_BANK_SIZE);
+    }
+}
@@ -1,10 +1,28 @@
 use anyhow::Context;
 
-use crate::utils::{BitExt, to_lowest_bit_set};
+use crate::{
+    apu::{Apu, NR10, NR11, NR21, NR31, NR41, WAVE_RAM_END},
+    boot::{BOOT_ROM_DISABLE, BootRom, Model},
+    cartridge::{Cartridge, CgbFlag, Licensee},
+    dma::{Bus, DMA, HDMA1, HDMA5, OamDma, VramDma},
+    joypad::{Joypad, P1},
+    ppu::{BCPS, BGP, LCDC, LYC, Mode, OAM_START, OCPD, Ppu, VBK, VRAM_START, WX},
+    serial::{SB, SC, Serial},
+    sgb::Sgb,
+    timer::{DIV, TAC, Timer},
+    utils::{BitExt, to_lowest_bit_set},
+};
 
 pub const MEM_SIZE: usize = 0xFFFF + 1;
 pub const INTERRUPT_ENABLE: usize = 0xFFFF;
 pub const INTERRUPT_FLAG: usize = 0xFF0F;
+pub const KEY1: u16 = 0xFF4D;
+pub const SVBK: u16 = 0xFF70;
+
+const WRAM_START: u16 = 0xC000;
+const WRAM_BANK_SIZE: usize = 0x1000;
+/// CGBs have 8 banks, the first is always at 0xC000 and the one in SVBK at 0xD000
+const WRAM_BANKS: usize = 8;
 
 /// 0x0000 - 0x00FF: Boot ROM
 /// 0x0000 - 0x3FFF: Game ROM Bank 0
@@ -19,41 +37,415 @@ pub const INTERRUPT_FLAG: usize = 0xFF0F;
 /// 0xFF00 - 0xFF7F: I/O Registers
 /// 0xFF80 - 0xFFFE: High RAM Area
 /// 0xFFFF: Interrupt Enabled Register
+///
+/// Without a cartridge inserted the whole address space is a flat 64 KiB of RAM, which is what
+/// the CPU tests and the single step test suite expect. Once a cartridge is inserted every access
+/// is routed to the component that owns the region.
 #[derive(Debug, Clone)]
 pub struct Memory {
     pub memory: [u8; MEM_SIZE],
+    pub cartridge: Option<Cartridge>,
+    /// Mapped over the cartridge until something is written to 0xFF50
+    pub boot_rom: Option<BootRom>,
+    pub model: Model,
+    pub timer: Timer,
+    pub ppu: Ppu,
+    pub dma: OamDma,
+    pub hdma: VramDma,
+    pub joypad: Joypad,
+    pub serial: Serial,
+    pub apu: Apu,
+    /// Only there when running as an SGB
+    pub sgb: Option<Sgb>,
+    /// Every WRAM bank, only 0 and 1 are used outside of CGB mode
+    wram: [u8; WRAM_BANK_SIZE * WRAM_BANKS],
+    svbk: u8,
+    /// CGB only, the CPU and timer run twice as fast while everything else stays put
+    pub double_speed: bool,
+    speed_switch_armed: bool,
+    odd_cycle: bool,
 }
 
 impl Default for Memory {
     fn default() -> Self {
         Self {
             memory: [0; MEM_SIZE],
+            cartridge: None,
+            boot_rom: None,
+            model: Model::default(),
+            timer: Timer::default(),
+            ppu: Ppu::default(),
+            dma: OamDma::default(),
+            hdma: VramDma::default(),
+            joypad: Joypad::default(),
+            serial: Serial::default(),
+            apu: Apu::default(),
+            sgb: None,
+            wram: [0; WRAM_BANK_SIZE * WRAM_BANKS],
+            svbk: 0,
+            double_speed: false,
+            speed_switch_armed: false,
+            odd_cycle: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    Rom,
+    Vram,
+    CartridgeRam,
+    Wram,
+    EchoRam,
+    Oam,
+    Unusable,
+    Io,
+    Hram,
+    InterruptEnable,
+}
+
+impl From<u16> for Region {
+    fn from(addr: u16) -> Self {
+        match addr {
+            0x0000..=0x7FFF => Region::Rom,
+            0x8000..=0x9FFF => Region::Vram,
+            0xA000..=0xBFFF => Region::CartridgeRam,
+            0xC000..=0xDFFF => Region::Wram,
+            0xE000..=0xFDFF => Region::EchoRam,
+            0xFE00..=0xFE9F => Region::Oam,
+            0xFEA0..=0xFEFF => Region::Unusable,
+            0xFF00..=0xFF7F => Region::Io,
+            0xFF80..=0xFFFE => Region::Hram,
+            0xFFFF => Region::InterruptEnable,
         }
     }
 }
 
+/// Echo RAM mirrors 0xC000 - 0xDDFF
+const ECHO_OFFSET: u16 = 0x2000;
+
 impl Memory {
+    /// Runs the cartridge on a CGB if its header says it supports one, then an SGB, otherwise a
+    /// DMG
+    pub fn with_cartridge(cartridge: Cartridge) -> Self {
+        let header = &cartridge.header;
+        let cgb = header.cgb_flag != CgbFlag::None;
+        // The SGB flag only counts alongside the new licensee code
+        let sgb = header.sgb && matches!(header.licensee, Licensee::New(_));
+
+        let model = match (cgb, sgb) {
+            (true, _) => Model::Cgb,
+            (false, true) => Model::Sgb,
+            (false, false) => Model::Dmg,
+        };
+        let mut memory = Self {
+            cartridge: Some(cartridge),
+            model,
+            sgb: (model == Model::Sgb).then(Sgb::default),
+            ..Default::default()
+        };
+        memory.set_cgb_mode(cgb);
+        memory
+    }
+
+    /// CGB mode turns on the colour palettes and the extra VRAM and WRAM banks. A CGB running a
+    /// DMG only cartridge stays out of it.
+    pub fn cgb_mode(&self) -> bool {
+        self.ppu.cgb_mode()
+    }
+
+    pub fn set_cgb_mode(&mut self, cgb: bool) {
+        self.ppu.set_cgb_mode(cgb);
+        self.svbk = 0;
+    }
+
+    /// Index into `wram` of an address in 0xC000 - 0xDFFF
+    fn wram_index(&self, addr: u16) -> usize {
+        let offset = usize::from(addr - WRAM_START);
+        if offset < WRAM_BANK_SIZE {
+            return offset;
+        }
+
+        // Bank 0 can't be mapped at 0xD000, selecting it gives bank 1
+        let bank = usize::from(self.svbk & 0x07).max(1);
+        bank * WRAM_BANK_SIZE + offset - WRAM_BANK_SIZE
+    }
+
     pub fn get_byte(&self, addr: u16) -> anyhow::Result<u8> {
-        self.memory
-            .get(usize::from(addr))
-            .copied()
-            .ok_or_else(|| anyhow::anyhow!("Out of bounds memory access at {:x}", addr))
+        if let Some(boot_rom) = &self.boot_rom
+            && boot_rom.maps(addr)
+        {
+            return Ok(boot_rom.read(addr));
+        }
+
+        let Some(cartridge) = &self.cartridge else {
+            return self
+                .memory
+                .get(usize::from(addr))
+                .copied()
+                .ok_or_else(|| anyhow::anyhow!("Out of bounds memory access at {:x}", addr));
+        };
+
+        if self.dma_blocks(addr) {
+            return Ok(match Region::from(addr) {
+                Region::Oam => 0xFF,
+                _ => self.dma.last_byte,
+            });
+        }
+
+        Ok(match Region::from(addr) {
+            Region::Rom => cartridge.read_rom(addr),
+            Region::CartridgeRam => cartridge.read_ram(addr),
+            Region::Wram => self.wram[self.wram_index(addr)],
+            Region::EchoRam => self.wram[self.wram_index(addr - ECHO_OFFSET)],
+            // DMG returns 0x00 here, CGB revisions all do something different
+            Region::Unusable => 0x00,
+            Region::Io => self.read_io(addr),
+            // Locked regions read as open bus
+            Region::Vram if !self.ppu.vram_accessible() => 0xFF,
+            Region::Oam if !self.ppu.oam_accessible() => 0xFF,
+            Region::Vram => self.ppu.vram[self.ppu.vram_index(addr)],
+            Region::Oam => self.ppu.oam[usize::from(addr - OAM_START)],
+            Region::Hram | Region::InterruptEnable => self.memory[usize::from(addr)],
+        })
     }
 
     pub fn set_byte(&mut self, addr: u16, value: u8) {
-        self.memory[usize::from(addr)] = value;
+        if addr == BOOT_ROM_DISABLE && value != 0 {
+            self.boot_rom = None;
+        }
+
+        if self.dma_blocks(addr) {
+            return;
+        }
+
+        let Some(cartridge) = &mut self.cartridge else {
+            self.memory[usize::from(addr)] = value;
+            return;
+        };
+
+        match Region::from(addr) {
+            // Writes to ROM configure the MBC, the ROM itself never changes
+            Region::Rom => cartridge.write_rom(addr, value),
+            Region::CartridgeRam => cartridge.write_ram(addr, value),
+            Region::Wram => {
+                let index = self.wram_index(addr);
+                self.wram[index] = value;
+            }
+            Region::EchoRam => {
+                let index = self.wram_index(addr - ECHO_OFFSET);
+                self.wram[index] = value;
+            }
+            Region::Unusable => {}
+            Region::Io => self.write_io(addr, value),
+            Region::Vram if !self.ppu.vram_accessible() => {}
+            Region::Oam if !self.ppu.oam_accessible() => {}
+            Region::Vram => {
+                let index = self.ppu.vram_index(addr);
+                self.ppu.vram[index] = value;
+            }
+            Region::Oam => self.ppu.oam[usize::from(addr - OAM_START)] = value,
+            Region::Hram | Region::InterruptEnable => self.memory[usize::from(addr)] = value,
+        }
     }
 
     pub fn set_u16(&mut self, addr: u16, value: u16) {
         let [high, low] = value.to_be_bytes();
-        self.memory[usize::from(addr)] = low;
-        self.memory[usize::from(addr + 1)] = high;
+        self.set_byte(addr, low);
+        self.set_byte(addr.wrapping_add(1), high);
+    }
+
+    /// Whether the CPU is locked out of `addr` by an OAM DMA in progress
+    fn dma_blocks(&self, addr: u16) -> bool {
+        self.dma.active()
+            && (Region::from(addr) == Region::Oam || Bus::of(addr) == self.dma.source_bus())
+    }
+
+    fn read_io(&self, addr: u16) -> u8 {
+        match addr {
+            // Only the bottom 5 bits of IF exist
+            0xFF0F => self.memory[INTERRUPT_FLAG] | 0xE0,
+            DIV..=TAC => self.timer.read(addr),
+            LCDC..=LYC | BGP..=WX | VBK | BCPS..=OCPD => self.ppu.read(addr),
+            DMA => self.dma.read(),
+            P1 => self.joypad.read(),
+            SB => self.serial.read(addr),
+            // Only CGBs have the fast clock bit
+            SC if !self.model.is_cgb() => self.serial.read(addr) | 0x02,
+            SC => self.serial.read(addr),
+            KEY1 if self.model.is_cgb() => {
+                0x7E | (u8::from(self.double_speed) << 7) | u8::from(self.speed_switch_armed)
+            }
+            NR10..=WAVE_RAM_END => self.apu.read(addr),
+            SVBK if self.cgb_mode() => 0xF8 | self.svbk,
+            HDMA1..=HDMA5 if self.cgb_mode() => self.hdma.read(addr),
+            // Nothing is listening on the rest of the I/O range so the bus floats high
+            _ => 0xFF,
+        }
+    }
+
+    fn write_io(&mut self, addr: u16, value: u8) {
+        match addr {
+            DIV..=TAC => self.timer.write(addr, value),
+            LCDC..=LYC | BGP..=WX | VBK | BCPS..=OCPD => self.ppu.write(addr, value),
+            DMA => self.dma.start(value),
+            P1 => {
+                self.joypad.write(value);
+                if let Some(sgb) = &mut self.sgb {
+                    sgb.write_p1(value);
+                    self.joypad.set_players(sgb.players());
+                }
+            }
+            SB => self.serial.write(addr, value),
+            SC if !self.model.is_cgb() => self.serial.write(addr, value & !0x02),
+            SC => self.serial.write(addr, value),
+            KEY1 if self.model.is_cgb() => self.speed_switch_armed = value & 0x01 == 1,
+            NR11 | NR21 | NR31 | NR41 if !self.model.is_cgb() => self.apu.write_length(addr, value),
+            NR10..=WAVE_RAM_END => self.apu.write(addr, value),
+            SVBK if self.cgb_mode() => self.svbk = value & 0x07,
+            HDMA1..=HDMA5 if self.cgb_mode() => self.hdma.write(addr, value),
+            _ => self.memory[usize::from(addr)] = value,
+        }
+    }
+
+    /// Puts `value` straight into the I/O register at `addr`, without the side effects a write from
+    /// the CPU would have
+    pub fn load_io(&mut self, addr: u16, value: u8) {
+        if self.cartridge.is_none() {
+            self.memory[usize::from(addr)] = value;
+            return;
+        }
+
+        match addr {
+            0xFF0F => self.memory[INTERRUPT_FLAG] = value,
+            DIV..=TAC => self.timer.set_register(addr, value),
+            LCDC..=LYC | BGP..=WX | VBK | BCPS..=OCPD => self.ppu.set_register(addr, value),
+            DMA => self.dma.set_register(value),
+            P1 => self.joypad.set_register(value),
+            SB => self.serial.set_register(addr, value),
+            SC if !self.model.is_cgb() => self.serial.set_register(addr, value & !0x02),
+            SC => self.serial.set_register(addr, value),
+            NR10..=WAVE_RAM_END => self.apu.set_register(addr, value),
+            _ => self.set_byte(addr, value),
+        }
+    }
+
+    /// Advances everything on the bus that keeps time by the cycles the CPU just spent
+    pub fn tick(&mut self, m_cycles: u8) {
+        // The timer runs off the CPU clock so it speeds up in double speed mode
+        self.timer.tick(m_cycles, &mut self.memory[INTERRUPT_FLAG]);
+        self.joypad.tick(&mut self.memory[INTERRUPT_FLAG]);
+        self.serial
+            .tick(u32::from(m_cycles), &mut self.memory[INTERRUPT_FLAG]);
+
+        // OAM DMA also runs off the CPU clock
+        for _ in 0..m_cycles {
+            self.step_dma();
+        }
+
+        let normal_cycles = self.normal_speed_cycles(m_cycles);
+
+        self.ppu
+            .tick(normal_cycles, &mut self.memory[INTERRUPT_FLAG]);
+        if let Some(sgb) = &mut self.sgb {
+            sgb.tick(&self.ppu);
+        }
+        self.hdma
+            .set_hblank(self.ppu.lcd_enabled() && self.ppu.mode() == Mode::HBlank);
+        // VRAM DMA copies 2 bytes per M-cycle at normal speed
+        for _ in 0..normal_cycles * 2 {
+            self.step_vram_dma();
+        }
+        // The frame sequencer is clocked off DIV, which does speed up
+        self.apu
+            .tick(normal_cycles, self.timer.counter(), self.double_speed);
+
+        if let Some(cartridge) = &mut self.cartridge {
+            cartridge.tick(normal_cycles);
+        }
+    }
+
+    /// What a DMA sees at `source`, the CPU's view of it being blocked doesn't matter
+    fn dma_source_byte(&self, source: u16) -> u8 {
+        match Region::from(source) {
+            Region::Vram => self.ppu.vram[self.ppu.vram_index(source)],
+            Region::Rom => self.cartridge.as_ref().map_or(0xFF, |c| c.read_rom(source)),
+            Region::CartridgeRam => self.cartridge.as_ref().map_or(0xFF, |c| c.read_ram(source)),
+            Region::Wram => self.wram[self.wram_index(source)],
+            _ => self.memory[usize::from(source)],
+        }
+    }
+
+    fn step_dma(&mut self) {
+        let Some((source, index)) = self.dma.step() else {
+            return;
+        };
+
+        let value = self.dma_source_byte(source);
+        self.dma.last_byte = value;
+        self.ppu.oam[index] = value;
+    }
+
+    fn step_vram_dma(&mut self) {
+        let Some((source, offset)) = self.hdma.step() else {
+            return;
+        };
+
+        // Sources in echo RAM read WRAM
+        let source = if source >= 0xE000 {
+            source - ECHO_OFFSET
+        } else {
+            source
+        };
+        let value = self.dma_source_byte(source);
+        let index = self.ppu.vram_index(VRAM_START + offset);
+        self.ppu.vram[index] = value;
+    }
+
+    /// Whether the CPU is waiting on a VRAM DMA block
+    pub fn vram_dma_stalls(&self) -> bool {
+        self.hdma.copying()
+    }
+
+    /// Converts CPU cycles into cycles of the normal speed clock the PPU, APU and cartridge run on
+    fn normal_speed_cycles(&mut self, m_cycles: u8) -> u32 {
+        if !self.double_speed {
+            return u32::from(m_cycles);
+        }
+
+        let total = u32::from(m_cycles) + u32::from(self.odd_cycle);
+        self.odd_cycle = total % 2 == 1;
+        total / 2
+    }
+
+    pub fn reset_div(&mut self) {
+        self.timer.write(DIV, 0);
+    }
+
+    /// Performs the CGB speed switch if it was armed through KEY1. Returns whether it happened.
+    pub fn switch_speed(&mut self) -> bool {
+        if !self.model.is_cgb() || !self.speed_switch_armed {
+            return false;
+        }
+
+        self.speed_switch_armed = false;
+        self.double_speed = !self.double_speed;
+        true
+    }
+
+    /// Whether any of the selected joypad lines in P1 are being pulled low
+    pub fn joypad_line_low(&self) -> bool {
+        self.get_byte(P1).unwrap_or(0xFF) & 0x0F != 0x0F
     }
 
     pub fn load_instructions(&mut self, instructions: &[u8]) {
         self.memory[..instructions.len()].copy_from_slice(instructions);
     }
 
+    pub fn interrupt_pending(&self) -> bool {
+        self.memory[INTERRUPT_ENABLE] & self.memory[INTERRUPT_FLAG] & 0x1F != 0
+    }
+
     pub fn interrupt_to_run(&mut self) -> anyhow::Result<Option<InterruptType>> {
         let ienable = self.memory[INTERRUPT_ENABLE];
         let iflag = self
@@ -61,7 +453,8 @@ impl Memory {
             .get_mut(INTERRUPT_FLAG)
             .context("IF out of bounds")?;
 
-        let priority = to_lowest_bit_set(ienable & *iflag);
+        // Bits 5-7 can be set in both but don't belong to any interrupt
+        let priority = to_lowest_bit_set(ienable & *iflag & 0x1F);
 
         if priority == 0 {
             return Ok(None);
@@ -115,7 +508,7 @@ impl InterruptType {
     pub fn addr(&self) -> u16 {
         match self {
             InterruptType::Joypad => 0x60,
-            InterruptType::Serial => 0x56,
+            InterruptType::Serial => 0x58,
             InterruptType::Timer => 0x50,
             InterruptType::LCD => 0x48,
             InterruptType::VBlank => 0x40,
@@ -144,7 +537,12 @@ impl TryFrom<u8> for InterruptType {
 
 #[cfg(test)]
 mod tests {
-    use crate::memory::Memory;
+    use crate::{
+        boot::Model,
+        cartridge::{Cartridge, HEADER_CHECKSUM, header_checksum, test_rom},
+        memory::{KEY1, Memory, SVBK},
+        ppu::{LCDC, VBK},
+    };
 
     #[test]
     fn memory_get_set_bytes() {
@@ -157,4 +555,131 @@ mod tests {
         assert_eq!(mem.get_byte(addr).expect("Unable to get byte"), val);
         assert_eq!(mem.memory[usize::from(addr)], val);
     }
+
+    #[test]
+    fn region_dispatch() {
+        let rom = test_rom(0x00, 0x00, 0x00);
+        let mut mem = Memory::with_cartridge(Cartridge::from_bytes(rom).expect("Valid ROM"));
+
+        // ROM is read only
+        mem.set_byte(0x0134, 0xAA);
+        assert_eq!(mem.get_byte(0x0134).expect("Unable to get byte"), b'T');
+
+        // Echo RAM mirrors working RAM both ways
+        mem.set_byte(0xC010, 0x12);
+        assert_eq!(mem.get_byte(0xE010).expect("Unable to get byte"), 0x12);
+        mem.set_byte(0xFDFF, 0x34);
+        assert_eq!(mem.get_byte(0xDDFF).expect("Unable to get byte"), 0x34);
+
+        mem.set_byte(0xFEA0, 0x56);
+        assert_eq!(mem.get_byte(0xFEA0).expect("Unable to get byte"), 0x00);
+
+        mem.set_byte(0xFF03, 0x78);
+        assert_eq!(mem.get_byte(0xFF03).expect("Unable to get byte"), 0xFF);
+        assert_eq!(mem.get_byte(0xFF0F).expect("Unable to get byte"), 0xE0);
+    }
+
+    #[test]
+    fn ppu_access_blocking() {
+        let mut mem = Memory::with_cartridge(
+            Cartridge::from_bytes(test_rom(0x00, 0x00, 0x00)).expect("Valid ROM"),
+        );
+        mem.set_byte(0x8000, 0x11);
+        mem.set_byte(0xFE00, 0x22);
+
+        // Mode 2 locks OAM
+        mem.set_byte(LCDC, 0x80);
+        assert_eq!(mem.get_byte(0x8000).expect("Unable to get byte"), 0x11);
+        assert_eq!(mem.get_byte(0xFE00).expect("Unable to get byte"), 0xFF);
+        mem.set_byte(0xFE00, 0x33);
+
+        // Mode 3 locks both
+        mem.tick(20);
+        assert_eq!(mem.get_byte(0x8000).expect("Unable to get byte"), 0xFF);
+        mem.set_byte(0x8000, 0x44);
+
+        // Turning the LCD off unlocks everything
+        mem.set_byte(LCDC, 0x00);
+        assert_eq!(mem.get_byte(0x8000).expect("Unable to get byte"), 0x11);
+        assert_eq!(mem.get_byte(0xFE00).expect("Unable to get byte"), 0x22);
+    }
+
+    #[test]
+    fn speed_switch() {
+        let mut mem = Memory::with_cartridge(
+            Cartridge::from_bytes(test_rom(0x00, 0x00, 0x00)).expect("Valid ROM"),
+        );
+
+        // Only CGBs have KEY1
+        mem.set_byte(KEY1, 0x01);
+        assert_eq!(mem.get_byte(KEY1).expect("Unable to get byte"), 0xFF);
+        assert!(!mem.switch_speed());
+
+        mem.model = Model::Cgb;
+        mem.set_byte(KEY1, 0x01);
+        assert_eq!(mem.get_byte(KEY1).expect("Unable to get byte"), 0x7F);
+        assert!(mem.switch_speed());
+        assert!(mem.double_speed);
+        assert_eq!(mem.get_byte(KEY1).expect("Unable to get byte"), 0xFE);
+
+        assert_eq!(mem.normal_speed_cycles(3), 1);
+        assert_eq!(mem.normal_speed_cycles(3), 2);
+    }
+
+    #[test]
+    fn cartridge_banking() {
+        // MBC1 with 128 KiB of ROM and 8 KiB of RAM
+        let mut rom = test_rom(0x02, 0x02, 0x02);
+        rom[0x4000 * 3] = 0x33;
+        let mut mem = Memory::with_cartridge(Cartridge::from_bytes(rom).expect("Valid ROM"));
+
+        // Writes to ROM configure the MBC rather than changing the ROM
+        mem.set_byte(0x2000, 0x03);
+        assert_eq!(mem.get_byte(0x4000).expect("Unable to get byte"), 0x33);
+
+        assert_eq!(mem.get_byte(0xA000).expect("Unable to get byte"), 0xFF);
+        mem.set_byte(0x0000, 0x0A);
+        mem.set_byte(0xA000, 0x12);
+        assert_eq!(mem.get_byte(0xA000).expect("Unable to get byte"), 0x12);
+    }
+
+    #[test]
+    fn cgb_banking() {
+        // DMG carts leave the banks alone
+        let mut mem = Memory::with_cartridge(
+            Cartridge::from_bytes(test_rom(0x00, 0x00, 0x00)).expect("Valid ROM"),
+        );
+        assert!(!mem.cgb_mode());
+        mem.set_byte(SVBK, 0x02);
+        assert_eq!(mem.get_byte(SVBK).expect("Unable to get byte"), 0xFF);
+        assert_eq!(mem.get_byte(VBK).expect("Unable to get byte"), 0xFF);
+
+        let mut rom = test_rom(0x00, 0x00, 0x00);
+        rom[0x0143] = 0xC0;
+        rom[HEADER_CHECKSUM] = header_checksum(&rom);
+        let mut mem = Memory::with_cartridge(Cartridge::from_bytes(rom).expect("Valid ROM"));
+        assert!(mem.cgb_mode());
+        assert_eq!(mem.model, Model::Cgb);
+
+        mem.set_byte(0xC000, 0x10);
+        mem.set_byte(0xD000, 0x11);
+        mem.set_byte(SVBK, 0x02);
+        assert_eq!(mem.get_byte(SVBK).expect("Unable to get byte"), 0xFA);
+        assert_eq!(mem.get_byte(0xD000).expect("Unable to get byte"), 0x00);
+        mem.set_byte(0xD000, 0x22);
+        // Bank 0 always stays at 0xC000, and selecting it at 0xD000 gives bank 1
+        assert_eq!(mem.get_byte(0xC000).expect("Unable to get byte"), 0x10);
+        mem.set_byte(SVBK, 0x00);
+        assert_eq!(mem.get_byte(0xD000).expect("Unable to get byte"), 0x11);
+        mem.set_byte(SVBK, 0x02);
+        assert_eq!(mem.get_byte(0xF000).expect("Unable to get byte"), 0x22);
+
+        mem.set_byte(0x8000, 0x33);
+        mem.set_byte(VBK, 0x01);
+        assert_eq!(mem.get_byte(VBK).expect("Unable to get byte"), 0xFF);
+        assert_eq!(mem.get_byte(0x8000).expect("Unable to get byte"), 0x00);
+        mem.set_byte(0x8000, 0x44);
+        assert_eq!(mem.ppu.vram[0x0000], 0x33);
+        assert_eq!(mem.ppu.vram[0x2000], 0x44);
+    }
 }
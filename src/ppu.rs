@@ -0,0 +1,1304 @@
+use std::collections::VecDeque;
+
+use crate::{
+    memory::{InterruptByte, InterruptType},
+    utils::BitExt,
+};
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+pub const LCDC: u16 = 0xFF40;
+pub const STAT: u16 = 0xFF41;
+pub const SCY: u16 = 0xFF42;
+pub const SCX: u16 = 0xFF43;
+pub const LY: u16 = 0xFF44;
+pub const LYC: u16 = 0xFF45;
+pub const BGP: u16 = 0xFF47;
+pub const OBP0: u16 = 0xFF48;
+pub const OBP1: u16 = 0xFF49;
+pub const WY: u16 = 0xFF4A;
+pub const WX: u16 = 0xFF4B;
+pub const VBK: u16 = 0xFF4F;
+pub const BCPS: u16 = 0xFF68;
+pub const BCPD: u16 = 0xFF69;
+pub const OCPS: u16 = 0xFF6A;
+pub const OCPD: u16 = 0xFF6B;
+
+pub const VRAM_START: u16 = 0x8000;
+/// Size of a VRAM bank, CGBs have two
+pub const VRAM_SIZE: usize = 0x2000;
+pub const OAM_START: u16 = 0xFE00;
+pub const OAM_SIZE: usize = 0xA0;
+
+/// 0x00RRGGBB colours the four DMG shades are drawn with
+pub const DMG_PALETTE: [u32; 4] = [0xFFFFFF, 0xAAAAAA, 0x555555, 0x000000];
+
+const DOTS_PER_LINE: u32 = 456;
+const OAM_SCAN_DOTS: u32 = 80;
+/// Mode 3 without any of the penalties from scrolling, the window or objects
+const DRAWING_DOTS: u32 = 172;
+const VBLANK_START: u8 = 144;
+const LINES_PER_FRAME: u8 = 154;
+const SPRITES_PER_LINE: usize = 10;
+
+/// Values read from the bottom two bits of STAT
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Mode {
+    #[default]
+    HBlank = 0,
+    VBlank = 1,
+    OamScan = 2,
+    Drawing = 3,
+}
+
+/// How mode 3 is emulated. The scanline renderer draws the whole line in one go when mode 3
+/// starts, the pixel FIFO draws a pixel at a time so mid-line register writes show up and mode 3
+/// takes as long as it would on hardware.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RenderMode {
+    #[default]
+    Scanline,
+    PixelFifo,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sprite {
+    y: u8,
+    x: u8,
+    tile: u8,
+    attributes: u8,
+    /// Position in OAM, which decides priority in CGB mode
+    index: u8,
+}
+
+/// ┌────────┬────────┬─────┬─────┬───────┬────┬───┬───┬───┐
+/// │ Attrib │   7    │  6  │  5  │   4   │ 3  │ 2 │ 1 │ 0 │
+/// ├────────┼────────┼─────┼─────┼───────┼────┼───┴───┴───┤
+/// │ Object │Priority│Y    │X    │DMG    │Bank│CGB palette│
+/// │ BG map │        │flip │flip │palette│    │           │
+/// └────────┴────────┴─────┴─────┴───────┴────┴───────────┘
+///
+/// Background map attributes are in VRAM bank 1 at the same address as the tile index, and only
+/// exist on CGB. The DMG palette bit isn't used for the background.
+const PRIORITY: u32 = 7;
+const Y_FLIP: u32 = 6;
+const X_FLIP: u32 = 5;
+const DMG_PALETTE_BIT: u32 = 4;
+const BANK: u32 = 3;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct BgPixel {
+    color: u8,
+    /// CGB only
+    palette: u8,
+    priority: bool,
+}
+
+impl BgPixel {
+    fn new(attributes: u8, color: u8) -> Self {
+        Self {
+            color,
+            palette: attributes & 0x07,
+            priority: attributes.is_bit_set(PRIORITY),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct SpritePixel {
+    color: u8,
+    obp1: bool,
+    /// CGB only
+    palette: u8,
+    behind_bg: bool,
+    index: u8,
+}
+
+impl SpritePixel {
+    fn new(sprite: &Sprite, color: u8) -> Self {
+        Self {
+            color,
+            obp1: sprite.attributes.is_bit_set(DMG_PALETTE_BIT),
+            palette: sprite.attributes & 0x07,
+            behind_bg: sprite.attributes.is_bit_set(PRIORITY),
+            index: sprite.index,
+        }
+    }
+}
+
+/// ┌──────┬─────────┬───┬───┬───┬───┬───┬───┬───┐
+/// │ BCPS │    7    │ 6 │ 5 │ 4 │ 3 │ 2 │ 1 │ 0 │
+/// ├──────┼─────────┼───┼───┴───┴───┴───┴───┴───┤
+/// │      │Auto     │   │         Address       │
+/// │      │increment│   │                       │
+/// └──────┴─────────┴───┴───────────────────────┘
+///
+/// 8 palettes of 4 little endian RGB555 colours, accessed a byte at a time through BCPD/OCPD
+// https://gbdev.io/pandocs/Palettes.html#lcd-color-palettes-cgb-only
+#[derive(Debug, Clone)]
+struct PaletteRam {
+    spec: u8,
+    data: [u8; 64],
+}
+
+impl Default for PaletteRam {
+    fn default() -> Self {
+        // What the boot ROM leaves behind, everything white
+        Self {
+            spec: 0,
+            data: [0xFF; 64],
+        }
+    }
+}
+
+impl PaletteRam {
+    fn read_spec(&self) -> u8 {
+        self.spec | 0x40
+    }
+
+    fn write_spec(&mut self, value: u8) {
+        self.spec = value & 0xBF;
+    }
+
+    fn read_data(&self) -> u8 {
+        self.data[usize::from(self.spec & 0x3F)]
+    }
+
+    /// The address still increments when the write itself is blocked
+    fn write_data(&mut self, value: u8, accessible: bool) {
+        if accessible {
+            self.data[usize::from(self.spec & 0x3F)] = value;
+        }
+        if self.spec.is_bit_set(7) {
+            self.spec = 0x80 | ((self.spec + 1) & 0x3F);
+        }
+    }
+
+    /// 0x00RRGGBB of a colour in one of the 8 palettes
+    fn rgb(&self, palette: u8, color: u8) -> u32 {
+        let i = usize::from(palette * 8 + color * 2);
+        rgb555_to_rgb(u16::from_le_bytes([self.data[i], self.data[i + 1]]))
+    }
+}
+
+/// 0x00RRGGBB of an RGB555 colour with red in the low bits, each channel is scaled up to 8 bits
+pub fn rgb555_to_rgb(color: u16) -> u32 {
+    let channel = |shift: u16| {
+        let value = u32::from((color >> shift) & 0x1F);
+        (value << 3) | (value >> 2)
+    };
+    (channel(0) << 16) | (channel(5) << 8) | channel(10)
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum FetcherStep {
+    #[default]
+    Tile,
+    DataLow,
+    DataHigh,
+    Push,
+}
+
+/// Background/window tile fetcher. Every step but push takes 2 dots, push is retried every dot
+/// until the FIFO is empty.
+#[derive(Debug, Clone, Copy, Default)]
+struct Fetcher {
+    step: FetcherStep,
+    /// Odd dots are spent waiting
+    waiting: bool,
+    /// Tile column relative to where the fetch started
+    x: u8,
+    window: bool,
+    tile: u8,
+    /// CGB only, from VRAM bank 1
+    attributes: u8,
+    low: u8,
+    high: u8,
+}
+
+// https://gbdev.io/pandocs/pixel_fifo.html
+#[derive(Debug, Clone, Default)]
+struct PixelFifo {
+    bg: VecDeque<BgPixel>,
+    sprites: VecDeque<SpritePixel>,
+    fetcher: Fetcher,
+    /// The first tile fetched on every line is thrown away
+    warmup: u8,
+    /// Pixels shifted out without being drawn to apply the fine SCX scroll
+    discard: u8,
+    /// Next screen column to be drawn
+    lx: u8,
+    /// Objects on this line that haven't been fetched yet, ordered by X
+    pending_sprites: VecDeque<Sprite>,
+    /// Dots left on the object currently being fetched, the FIFO is stalled while it's going
+    sprite_fetch: Option<(Sprite, u8)>,
+    /// Background tile that already paid for waiting on the fetcher, later objects in the same
+    /// tile only pay for their own fetch
+    penalized_tile: Option<u8>,
+    window_drawn: bool,
+}
+
+/// ┌──────┬──────┬──────┬──────┬──────┬──────┬──────┬──────┬──────┐
+/// │ LCDC │  7   │  6   │  5   │  4   │  3   │  2   │  1   │  0   │
+/// ├──────┼──────┼──────┼──────┼──────┼──────┼──────┼──────┼──────┤
+/// │      │ LCD  │Window│Window│ Tile │  BG  │ OBJ  │ OBJ  │  BG  │
+/// │      │enable│ map  │enable│ data │ map  │ size │enable│enable│
+/// └──────┴──────┴──────┴──────┴──────┴──────┴──────┴──────┴──────┘
+///
+/// In CGB mode bit 0 doesn't hide the background and window, it just takes away their priority
+/// over objects.
+// https://gbdev.io/pandocs/Rendering.html
+#[derive(Debug, Clone)]
+pub struct Ppu {
+    /// Both banks, bank 1 starts at `VRAM_SIZE`
+    pub vram: [u8; VRAM_SIZE * 2],
+    pub oam: [u8; OAM_SIZE],
+    lcdc: u8,
+    /// Only the interrupt select bits 3-6, the rest is computed on read
+    stat: u8,
+    scy: u8,
+    scx: u8,
+    ly: u8,
+    lyc: u8,
+    bgp: u8,
+    obp0: u8,
+    obp1: u8,
+    wy: u8,
+    wx: u8,
+    /// Colour palettes, the background attribute map, the second VRAM bank and OAM order object
+    /// priority are only there in CGB mode
+    cgb: bool,
+    vram_bank: u8,
+    bg_palettes: PaletteRam,
+    obj_palettes: PaletteRam,
+    mode: Mode,
+    /// Dot within the current line
+    dot: u32,
+    /// The window keeps its own line counter that only moves on lines it was drawn on
+    window_line: u8,
+    /// Set once LY has matched WY this frame
+    window_y_triggered: bool,
+    /// STAT interrupts fire on the rising edge of all the selected sources ORed together
+    stat_line: bool,
+    render_mode: RenderMode,
+    /// Dots left in mode 3 for the scanline renderer
+    drawing_dots: u32,
+    fifo: PixelFifo,
+    framebuffer: Vec<u32>,
+    shades: Vec<u8>,
+    frame_ready: bool,
+}
+
+impl Default for Ppu {
+    fn default() -> Self {
+        Self {
+            vram: [0; VRAM_SIZE * 2],
+            oam: [0; OAM_SIZE],
+            lcdc: 0,
+            stat: 0,
+            scy: 0,
+            scx: 0,
+            ly: 0,
+            lyc: 0,
+            bgp: 0,
+            obp0: 0,
+            obp1: 0,
+            wy: 0,
+            wx: 0,
+            cgb: false,
+            vram_bank: 0,
+            bg_palettes: PaletteRam::default(),
+            obj_palettes: PaletteRam::default(),
+            mode: Mode::default(),
+            dot: 0,
+            window_line: 0,
+            window_y_triggered: false,
+            stat_line: false,
+            render_mode: RenderMode::default(),
+            drawing_dots: 0,
+            fifo: PixelFifo::default(),
+            framebuffer: vec![DMG_PALETTE[0]; SCREEN_WIDTH * SCREEN_HEIGHT],
+            shades: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            frame_ready: false,
+        }
+    }
+}
+
+impl Ppu {
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn render_mode(&self) -> RenderMode {
+        self.render_mode
+    }
+
+    pub fn set_render_mode(&mut self, render_mode: RenderMode) {
+        self.render_mode = render_mode;
+    }
+
+    pub fn cgb_mode(&self) -> bool {
+        self.cgb
+    }
+
+    pub fn set_cgb_mode(&mut self, cgb: bool) {
+        self.cgb = cgb;
+        if !cgb {
+            self.vram_bank = 0;
+        }
+    }
+
+    /// Index into `vram` of a CPU address in the bank selected by VBK
+    pub fn vram_index(&self, addr: u16) -> usize {
+        usize::from(self.vram_bank) * VRAM_SIZE + usize::from(addr - VRAM_START)
+    }
+
+    pub fn lcd_enabled(&self) -> bool {
+        self.lcdc.is_bit_set(7)
+    }
+
+    /// VRAM is locked while the PPU is drawing from it
+    pub fn vram_accessible(&self) -> bool {
+        !self.lcd_enabled() || self.mode != Mode::Drawing
+    }
+
+    /// OAM is locked while the PPU is scanning it and drawing objects
+    pub fn oam_accessible(&self) -> bool {
+        !self.lcd_enabled() || !matches!(self.mode, Mode::OamScan | Mode::Drawing)
+    }
+
+    /// 160x144 0x00RRGGBB pixels, row by row
+    pub fn framebuffer(&self) -> &[u32] {
+        &self.framebuffer
+    }
+
+    /// The same pixels as shades 0-3 after the palettes have been applied. In CGB mode these are
+    /// colour indexes into whichever palette the pixel used.
+    pub fn shades(&self) -> &[u8] {
+        &self.shades
+    }
+
+    /// Whether a full frame has been drawn since the last call
+    pub fn take_frame(&mut self) -> bool {
+        std::mem::take(&mut self.frame_ready)
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            LCDC => self.lcdc,
+            STAT => {
+                let mode = if self.lcd_enabled() {
+                    self.mode as u8
+                } else {
+                    0
+                };
+                0x80 | self.stat | (u8::from(self.ly == self.lyc) << 2) | mode
+            }
+            SCY => self.scy,
+            SCX => self.scx,
+            LY => self.ly,
+            LYC => self.lyc,
+            BGP => self.bgp,
+            OBP0 => self.obp0,
+            OBP1 => self.obp1,
+            WY => self.wy,
+            WX => self.wx,
+            VBK if self.cgb => 0xFE | self.vram_bank,
+            BCPS if self.cgb => self.bg_palettes.read_spec(),
+            OCPS if self.cgb => self.obj_palettes.read_spec(),
+            // Palette RAM is locked along with VRAM
+            BCPD | OCPD if self.cgb && !self.vram_accessible() => 0xFF,
+            BCPD if self.cgb => self.bg_palettes.read_data(),
+            OCPD if self.cgb => self.obj_palettes.read_data(),
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            LCDC => {
+                let was_enabled = self.lcd_enabled();
+                self.lcdc = value;
+
+                match (was_enabled, self.lcd_enabled()) {
+                    (true, false) => {
+                        self.ly = 0;
+                        self.dot = 0;
+                        self.mode = Mode::HBlank;
+                        self.stat_line = false;
+                    }
+                    (false, true) => {
+                        self.window_line = 0;
+                        self.window_y_triggered = false;
+                        self.set_mode(Mode::OamScan);
+                    }
+                    _ => {}
+                }
+            }
+            STAT => self.stat = value & 0b0111_1000,
+            SCY => self.scy = value,
+            SCX => self.scx = value,
+            // LY is read only
+            LY => {}
+            LYC => self.lyc = value,
+            BGP => self.bgp = value,
+            OBP0 => self.obp0 = value,
+            OBP1 => self.obp1 = value,
+            WY => self.wy = value,
+            WX => self.wx = value,
+            VBK if self.cgb => self.vram_bank = value & 0x01,
+            BCPS if self.cgb => self.bg_palettes.write_spec(value),
+            OCPS if self.cgb => self.obj_palettes.write_spec(value),
+            BCPD if self.cgb => {
+                let accessible = self.vram_accessible();
+                self.bg_palettes.write_data(value, accessible);
+            }
+            OCPD if self.cgb => {
+                let accessible = self.vram_accessible();
+                self.obj_palettes.write_data(value, accessible);
+            }
+            _ => {}
+        }
+    }
+
+    /// Sets a register without the side effects of a CPU write. The LCD is left at the start of a
+    /// frame, whether or not it was on before.
+    pub fn set_register(&mut self, addr: u16, value: u8) {
+        match addr {
+            LCDC => {
+                self.lcdc = value;
+                self.ly = 0;
+                self.dot = 0;
+                self.window_line = 0;
+                self.window_y_triggered = false;
+                self.stat_line = false;
+                self.set_mode(if self.lcd_enabled() {
+                    Mode::OamScan
+                } else {
+                    Mode::HBlank
+                });
+            }
+            BCPD if self.cgb => self.bg_palettes.write_data(value, true),
+            OCPD if self.cgb => self.obj_palettes.write_data(value, true),
+            // Nothing else does more than store the value
+            _ => self.write(addr, value),
+        }
+    }
+
+    /// Advances by M-cycles at normal speed, 4 dots each
+    pub fn tick(&mut self, m_cycles: u32, interrupt_flag: &mut u8) {
+        if !self.lcd_enabled() {
+            return;
+        }
+
+        for _ in 0..m_cycles * 4 {
+            self.step(interrupt_flag);
+        }
+    }
+
+    fn step(&mut self, interrupt_flag: &mut u8) {
+        self.dot += 1;
+
+        if self.dot == DOTS_PER_LINE {
+            self.dot = 0;
+            self.ly += 1;
+
+            if self.ly == LINES_PER_FRAME {
+                self.ly = 0;
+                self.window_line = 0;
+                self.window_y_triggered = false;
+            }
+
+            if self.ly == VBLANK_START {
+                self.set_mode(Mode::VBlank);
+                self.frame_ready = true;
+                InterruptByte(interrupt_flag).set_flag(InterruptType::VBlank, true);
+            } else if self.ly < VBLANK_START {
+                self.set_mode(Mode::OamScan);
+            }
+        } else if self.ly < VBLANK_START {
+            match self.mode {
+                Mode::OamScan if self.dot == OAM_SCAN_DOTS => self.start_drawing(),
+                Mode::Drawing => self.draw_dot(),
+                _ => {}
+            }
+        }
+
+        self.update_stat_line(interrupt_flag);
+    }
+
+    fn start_drawing(&mut self) {
+        self.set_mode(Mode::Drawing);
+
+        match self.render_mode {
+            RenderMode::Scanline => {
+                self.drawing_dots = DRAWING_DOTS;
+                self.render_scanline();
+            }
+            RenderMode::PixelFifo => {
+                let mut sprites = self.line_sprites();
+                // Objects are still fetched left to right in CGB mode, only priority changes
+                sprites.sort_by_key(|s| s.x);
+
+                self.fifo = PixelFifo {
+                    warmup: 6,
+                    discard: self.scx % 8,
+                    pending_sprites: sprites.into(),
+                    ..Default::default()
+                };
+            }
+        }
+    }
+
+    fn draw_dot(&mut self) {
+        match self.render_mode {
+            RenderMode::Scanline => {
+                self.drawing_dots -= 1;
+                if self.drawing_dots == 0 {
+                    self.set_mode(Mode::HBlank);
+                }
+            }
+            RenderMode::PixelFifo => self.fifo_dot(),
+        }
+    }
+
+    fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+
+        if mode == Mode::OamScan && self.ly == self.wy {
+            self.window_y_triggered = true;
+        }
+    }
+
+    fn update_stat_line(&mut self, interrupt_flag: &mut u8) {
+        let line = (self.stat.is_bit_set(6) && self.ly == self.lyc)
+            || (self.stat.is_bit_set(5) && self.mode == Mode::OamScan)
+            || (self.stat.is_bit_set(4) && self.mode == Mode::VBlank)
+            || (self.stat.is_bit_set(3) && self.mode == Mode::HBlank);
+
+        if line && !self.stat_line {
+            InterruptByte(interrupt_flag).set_flag(InterruptType::LCD, true);
+        }
+        self.stat_line = line;
+    }
+
+    /// Colour index 0-3 of a pixel in the tile row starting at `addr` in VRAM
+    fn tile_pixel(&self, addr: usize, x: u8) -> u8 {
+        let bit = u32::from(7 - x);
+        let low = self.vram[addr].is_bit_set(bit);
+        let high = self.vram[addr + 1].is_bit_set(bit);
+        (u8::from(high) << 1) | u8::from(low)
+    }
+
+    /// Background and window tiles either use 0x8000 with unsigned indexes or 0x9000 with
+    /// signed ones
+    fn bg_tile_addr(&self, index: u8) -> usize {
+        if self.lcdc.is_bit_set(4) {
+            usize::from(index) * 16
+        } else {
+            (0x1000 + i32::from(index as i8) * 16) as usize
+        }
+    }
+
+    /// Attributes of the tile at `addr` in a tile map, always 0 outside of CGB mode
+    fn map_attributes(&self, addr: usize) -> u8 {
+        if self.cgb {
+            self.vram[VRAM_SIZE + addr]
+        } else {
+            0
+        }
+    }
+
+    /// Address of the row of a background tile's data, after applying its attributes
+    fn bg_row_addr(&self, index: u8, attributes: u8, y: u8) -> usize {
+        let bank = usize::from(attributes.is_bit_set(BANK)) * VRAM_SIZE;
+        let row = if attributes.is_bit_set(Y_FLIP) {
+            7 - y % 8
+        } else {
+            y % 8
+        };
+        bank + self.bg_tile_addr(index) + usize::from(row) * 2
+    }
+
+    /// The pixel at `x`, `y` in the 256x256 tile map at `map`
+    fn map_pixel(&self, map: usize, x: u8, y: u8) -> BgPixel {
+        let addr = map + usize::from(y / 8) * 32 + usize::from(x / 8);
+        let attributes = self.map_attributes(addr);
+        let row = self.bg_row_addr(self.vram[addr], attributes, y);
+        let column = if attributes.is_bit_set(X_FLIP) {
+            7 - x % 8
+        } else {
+            x % 8
+        };
+        BgPixel::new(attributes, self.tile_pixel(row, column))
+    }
+
+    /// The first 10 objects in OAM that overlap the current line
+    fn scan_oam(&self) -> Vec<Sprite> {
+        let height = if self.lcdc.is_bit_set(2) { 16 } else { 8 };
+        let line = u16::from(self.ly) + 16;
+
+        self.oam
+            .chunks_exact(4)
+            .enumerate()
+            .map(|(i, o)| Sprite {
+                y: o[0],
+                x: o[1],
+                tile: o[2],
+                attributes: o[3],
+                index: i as u8,
+            })
+            .filter(|s| (u16::from(s.y)..u16::from(s.y) + height).contains(&line))
+            .take(SPRITES_PER_LINE)
+            .collect()
+    }
+
+    /// Objects on the current line, or none if they're disabled
+    fn line_sprites(&self) -> Vec<Sprite> {
+        if self.lcdc.is_bit_set(1) {
+            self.scan_oam()
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Colour indexes of the row of `sprite` on the current line, left to right after flipping
+    fn sprite_row(&self, sprite: &Sprite) -> [u8; 8] {
+        let tall = self.lcdc.is_bit_set(2);
+        let height = if tall { 16 } else { 8 };
+        let mut row = u16::from(self.ly) + 16 - u16::from(sprite.y);
+        if sprite.attributes.is_bit_set(Y_FLIP) {
+            row = height - 1 - row;
+        }
+
+        // 8x16 objects ignore the bottom bit of the tile index
+        let tile = if tall {
+            sprite.tile & 0xFE
+        } else {
+            sprite.tile
+        };
+        let bank = if self.cgb && sprite.attributes.is_bit_set(BANK) {
+            VRAM_SIZE
+        } else {
+            0
+        };
+        let addr = bank + usize::from(tile) * 16 + usize::from(row) * 2;
+
+        let mut pixels = [0; 8];
+        for (column, pixel) in pixels.iter_mut().enumerate() {
+            let column = column as u8;
+            let column = if sprite.attributes.is_bit_set(X_FLIP) {
+                7 - column
+            } else {
+                column
+            };
+            *pixel = self.tile_pixel(addr, column);
+        }
+        pixels
+    }
+
+    fn bg_map(&self) -> usize {
+        if self.lcdc.is_bit_set(3) {
+            0x1C00
+        } else {
+            0x1800
+        }
+    }
+
+    fn window_map(&self) -> usize {
+        if self.lcdc.is_bit_set(6) {
+            0x1C00
+        } else {
+            0x1800
+        }
+    }
+
+    fn window_visible(&self) -> bool {
+        self.lcdc.is_bit_set(5) && self.window_y_triggered && self.wx <= 166
+    }
+
+    /// Applies object priority and the palettes then writes the pixel out
+    fn put_pixel(&mut self, x: usize, bg: BgPixel, sprite: Option<SpritePixel>) {
+        let i = usize::from(self.ly) * SCREEN_WIDTH + x;
+
+        if self.cgb {
+            // With LCDC bit 0 clear objects are always on top
+            let sprite = sprite.filter(|s| {
+                s.color != 0
+                    && (!self.lcdc.is_bit_set(0) || bg.color == 0 || !(s.behind_bg || bg.priority))
+            });
+
+            let (color, rgb) = match sprite {
+                Some(s) => (s.color, self.obj_palettes.rgb(s.palette, s.color)),
+                None => (bg.color, self.bg_palettes.rgb(bg.palette, bg.color)),
+            };
+            self.shades[i] = color;
+            self.framebuffer[i] = rgb;
+            return;
+        }
+
+        let mut shade = palette_shade(self.bgp, bg.color);
+
+        if let Some(sprite) = sprite
+            && sprite.color != 0
+            && !(sprite.behind_bg && bg.color != 0)
+        {
+            let palette = if sprite.obp1 { self.obp1 } else { self.obp0 };
+            shade = palette_shade(palette, sprite.color);
+        }
+
+        self.shades[i] = shade;
+        self.framebuffer[i] = DMG_PALETTE[usize::from(shade)];
+    }
+
+    fn render_scanline(&mut self) {
+        let ly = self.ly;
+        let mut bg_colors = [BgPixel::default(); SCREEN_WIDTH];
+
+        // On DMG clearing LCDC bit 0 blanks both the background and window
+        if self.cgb || self.lcdc.is_bit_set(0) {
+            let bg_map = self.bg_map();
+            let window_map = self.window_map();
+            let window_visible = self.window_visible();
+            let mut window_drawn = false;
+
+            for (x, color) in bg_colors.iter_mut().enumerate() {
+                let x = x as u8;
+                *color = if window_visible && u16::from(x) + 7 >= u16::from(self.wx) {
+                    window_drawn = true;
+                    self.map_pixel(window_map, x + 7 - self.wx, self.window_line)
+                } else {
+                    self.map_pixel(bg_map, x.wrapping_add(self.scx), ly.wrapping_add(self.scy))
+                };
+            }
+
+            if window_drawn {
+                self.window_line += 1;
+            }
+        }
+
+        let mut sprites = self.line_sprites();
+        // Lower X wins, ties go to whichever came first in OAM. In CGB mode it's just OAM order.
+        if !self.cgb {
+            sprites.sort_by_key(|s| s.x);
+        }
+        let rows: Vec<_> = sprites.iter().map(|s| self.sprite_row(s)).collect();
+
+        for (x, &bg_color) in bg_colors.iter().enumerate() {
+            let sprite = sprites.iter().zip(&rows).find_map(|(s, row)| {
+                let column = (x + 8).checked_sub(usize::from(s.x))?;
+                let color = *row.get(column)?;
+                (color != 0).then(|| SpritePixel::new(s, color))
+            });
+
+            self.put_pixel(x, bg_color, sprite);
+        }
+    }
+
+    fn fifo_dot(&mut self) {
+        if self.fifo.warmup > 0 {
+            self.fifo.warmup -= 1;
+            return;
+        }
+
+        // Hitting the window throws away whatever background is queued and restarts the fetcher
+        if !self.fifo.fetcher.window
+            && self.window_visible()
+            && u16::from(self.fifo.lx) + 7 >= u16::from(self.wx)
+        {
+            self.fifo.bg.clear();
+            self.fifo.fetcher = Fetcher {
+                window: true,
+                ..Default::default()
+            };
+            self.fifo.window_drawn = true;
+        }
+
+        if self.fifo.sprite_fetch.is_none()
+            && self.lcdc.is_bit_set(1)
+            && let Some(sprite) = self.fifo.pending_sprites.front()
+            && u16::from(sprite.x) <= u16::from(self.fifo.lx) + 8
+        {
+            let sprite = *sprite;
+            self.fifo.pending_sprites.pop_front();
+            self.fifo.sprite_fetch = Some((sprite, self.sprite_fetch_dots(sprite.x)));
+        }
+
+        if let Some((sprite, dots)) = self.fifo.sprite_fetch {
+            if dots > 1 {
+                self.fifo.sprite_fetch = Some((sprite, dots - 1));
+            } else {
+                self.fifo.sprite_fetch = None;
+                self.merge_sprite(&sprite);
+            }
+            return;
+        }
+
+        self.step_fetcher();
+
+        let Some(bg) = self.fifo.bg.pop_front() else {
+            return;
+        };
+        if self.fifo.discard > 0 && !self.fifo.fetcher.window {
+            self.fifo.discard -= 1;
+            return;
+        }
+
+        let sprite = self.fifo.sprites.pop_front();
+        let bg = if self.cgb || self.lcdc.is_bit_set(0) {
+            bg
+        } else {
+            BgPixel::default()
+        };
+        self.put_pixel(usize::from(self.fifo.lx), bg, sprite);
+        self.fifo.lx += 1;
+
+        if usize::from(self.fifo.lx) == SCREEN_WIDTH {
+            if self.fifo.window_drawn {
+                self.window_line += 1;
+            }
+            self.set_mode(Mode::HBlank);
+        }
+    }
+
+    /// An object fetch takes 6 dots, plus however long it takes the background fetcher to get
+    /// to the end of the tile it's working on. One hidden at X=0 always waits the full 5.
+    fn sprite_fetch_dots(&mut self, sprite_x: u8) -> u8 {
+        if sprite_x == 0 {
+            return 11;
+        }
+
+        let position = if self.fifo.fetcher.window {
+            self.fifo.lx.wrapping_add(7).wrapping_sub(self.wx)
+        } else {
+            self.fifo.lx.wrapping_add(self.scx)
+        };
+
+        let tile = position / 8;
+        if self.fifo.penalized_tile == Some(tile) {
+            return 6;
+        }
+        self.fifo.penalized_tile = Some(tile);
+
+        6 + 5u8.saturating_sub(position % 8)
+    }
+
+    fn step_fetcher(&mut self) {
+        let fetcher = &mut self.fifo.fetcher;
+        if fetcher.step != FetcherStep::Push {
+            fetcher.waiting = !fetcher.waiting;
+            if fetcher.waiting {
+                return;
+            }
+        }
+
+        let fetcher = self.fifo.fetcher;
+        let y = if fetcher.window {
+            self.window_line
+        } else {
+            self.ly.wrapping_add(self.scy)
+        };
+        match fetcher.step {
+            FetcherStep::Tile => {
+                let (map, column) = if fetcher.window {
+                    (self.window_map(), fetcher.x)
+                } else {
+                    (self.bg_map(), (self.scx / 8).wrapping_add(fetcher.x))
+                };
+                let addr = map + usize::from(y / 8) * 32 + usize::from(column % 32);
+                self.fifo.fetcher.tile = self.vram[addr];
+                self.fifo.fetcher.attributes = self.map_attributes(addr);
+                self.fifo.fetcher.step = FetcherStep::DataLow;
+            }
+            FetcherStep::DataLow => {
+                let row = self.bg_row_addr(fetcher.tile, fetcher.attributes, y);
+                self.fifo.fetcher.low = self.vram[row];
+                self.fifo.fetcher.step = FetcherStep::DataHigh;
+            }
+            FetcherStep::DataHigh => {
+                let row = self.bg_row_addr(fetcher.tile, fetcher.attributes, y);
+                self.fifo.fetcher.high = self.vram[row + 1];
+                self.fifo.fetcher.step = FetcherStep::Push;
+            }
+            FetcherStep::Push => {
+                if !self.fifo.bg.is_empty() {
+                    return;
+                }
+
+                for x in 0..8 {
+                    let bit = if fetcher.attributes.is_bit_set(X_FLIP) {
+                        x
+                    } else {
+                        7 - x
+                    };
+                    let low = fetcher.low.is_bit_set(bit);
+                    let high = fetcher.high.is_bit_set(bit);
+                    let color = (u8::from(high) << 1) | u8::from(low);
+                    self.fifo
+                        .bg
+                        .push_back(BgPixel::new(fetcher.attributes, color));
+                }
+                self.fifo.fetcher.x = fetcher.x.wrapping_add(1);
+                self.fifo.fetcher.step = FetcherStep::Tile;
+            }
+        }
+    }
+
+    /// Objects only fill in transparent pixels, so whichever was fetched first keeps priority. In
+    /// CGB mode a lower OAM index takes over instead.
+    fn merge_sprite(&mut self, sprite: &Sprite) {
+        let row = self.sprite_row(sprite);
+        // Columns left of the current pixel are either off screen or already drawn
+        let skip = (usize::from(self.fifo.lx) + 8)
+            .saturating_sub(usize::from(sprite.x))
+            .min(8);
+
+        while self.fifo.sprites.len() < 8 - skip {
+            self.fifo.sprites.push_back(SpritePixel::default());
+        }
+
+        let cgb = self.cgb;
+        for (queued, &color) in self.fifo.sprites.iter_mut().zip(&row[skip..]) {
+            if queued.color == 0 || (cgb && color != 0 && sprite.index < queued.index) {
+                *queued = SpritePixel::new(sprite, color);
+            }
+        }
+    }
+}
+
+fn palette_shade(palette: u8, color: u8) -> u8 {
+    (palette >> (color * 2)) & 0x03
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ppu::{
+        BCPD, BCPS, BGP, LCDC, LY, LYC, Mode, OBP0, OCPD, OCPS, Ppu, RenderMode, SCREEN_WIDTH, SCX,
+        STAT, VBK, VRAM_SIZE, WX, WY,
+    };
+
+    /// Runs until the frame currently being drawn is done
+    fn run_frame(ppu: &mut Ppu) -> u8 {
+        let mut iflag = 0;
+        while !ppu.take_frame() {
+            ppu.tick(1, &mut iflag);
+        }
+        iflag
+    }
+
+    /// Tile 1 is solid colour 3, tile 2 is solid colour 1
+    fn with_tiles() -> Ppu {
+        let mut ppu = Ppu::default();
+        ppu.vram[0x10..0x20].fill(0xFF);
+        for row in ppu.vram[0x20..0x30].chunks_exact_mut(2) {
+            row[0] = 0xFF;
+        }
+        ppu.write(BGP, 0b11_10_01_00);
+        ppu.write(OBP0, 0b11_10_01_00);
+        ppu
+    }
+
+    #[test]
+    fn mode_timing() {
+        let mut ppu = Ppu::default();
+        let mut iflag = 0;
+        ppu.write(LCDC, 0x80);
+        assert_eq!(ppu.mode(), Mode::OamScan);
+
+        ppu.tick(20, &mut iflag);
+        assert_eq!(ppu.mode(), Mode::Drawing);
+        ppu.tick(43, &mut iflag);
+        assert_eq!(ppu.mode(), Mode::HBlank);
+        ppu.tick(51, &mut iflag);
+        assert_eq!(ppu.read(LY), 1);
+        assert_eq!(ppu.mode(), Mode::OamScan);
+
+        // A whole frame is 17556 M-cycles
+        ppu.tick(114 * 143, &mut iflag);
+        assert_eq!(ppu.read(LY), 144);
+        assert_eq!(ppu.mode(), Mode::VBlank);
+        assert_eq!(iflag, 0b00001);
+        ppu.tick(114 * 10, &mut iflag);
+        assert_eq!(ppu.read(LY), 0);
+
+        // Turning the LCD off resets LY
+        ppu.tick(114 * 5, &mut iflag);
+        ppu.write(LCDC, 0x00);
+        assert_eq!(ppu.read(LY), 0);
+        assert_eq!(ppu.read(STAT) & 0x03, 0);
+    }
+
+    #[test]
+    fn stat_interrupts() {
+        let mut ppu = Ppu::default();
+        let mut iflag = 0;
+        ppu.write(LYC, 2);
+        ppu.write(STAT, 0b0100_0000);
+        ppu.write(LCDC, 0x80);
+
+        ppu.tick(114, &mut iflag);
+        assert_eq!(iflag, 0);
+        ppu.tick(114, &mut iflag);
+        assert_eq!(iflag, 0b00010);
+        assert_eq!(ppu.read(STAT), 0b1100_0110);
+
+        // HBlank and LYC back to back only fire once since the line stays high
+        let mut ppu = Ppu::default();
+        let mut iflag = 0;
+        ppu.write(LYC, 0);
+        ppu.write(STAT, 0b0100_1000);
+        ppu.write(LCDC, 0x80);
+        ppu.tick(1, &mut iflag);
+        iflag = 0;
+        ppu.tick(100, &mut iflag);
+        assert_eq!(iflag, 0);
+    }
+
+    #[test]
+    fn background_and_window() {
+        let mut ppu = with_tiles();
+        // Tile 1 at the top left of the 0x9800 map, tile 2 all over the 0x9C00 one
+        ppu.vram[0x1800] = 1;
+        ppu.vram[0x1C00..0x2000].fill(2);
+        ppu.write(SCX, 4);
+        ppu.write(WY, 8);
+        ppu.write(WX, 7 + 80);
+        ppu.write(LCDC, 0b1111_0001);
+        run_frame(&mut ppu);
+
+        // Scrolled 4 pixels left
+        assert_eq!(&ppu.shades()[..6], &[3, 3, 3, 3, 0, 0]);
+        // Window covers the bottom right
+        assert_eq!(ppu.shades()[8 * SCREEN_WIDTH + 79], 0);
+        assert_eq!(ppu.shades()[8 * SCREEN_WIDTH + 80], 1);
+        assert_eq!(ppu.shades()[7 * SCREEN_WIDTH + 80], 0);
+        assert_eq!(ppu.framebuffer()[0], 0x000000);
+    }
+
+    #[test]
+    fn sprites() {
+        let mut ppu = with_tiles();
+        // 11 objects on the first line, the last one is dropped
+        for i in 0..11 {
+            let o = i * 4;
+            ppu.oam[o..o + 4].copy_from_slice(&[16, 8 + 10 * i as u8, 1, 0]);
+        }
+        ppu.write(LCDC, 0b1000_0011);
+        run_frame(&mut ppu);
+
+        let line = &ppu.shades()[..SCREEN_WIDTH];
+        assert_eq!(line[0], 3);
+        assert_eq!(line[90], 3);
+        assert_eq!(line[100], 0);
+
+        let mut ppu = with_tiles();
+        ppu.vram[0x1800] = 2;
+        // Background priority hides objects behind colours 1-3
+        ppu.oam[..4].copy_from_slice(&[16, 8, 1, 0x80]);
+        ppu.oam[4..8].copy_from_slice(&[16, 16, 1, 0x80]);
+        // Overlapping objects, the one with the lower X wins even though it's later in OAM
+        ppu.oam[8..12].copy_from_slice(&[32, 40, 1, 0]);
+        ppu.oam[12..16].copy_from_slice(&[32, 36, 2, 0]);
+        ppu.write(LCDC, 0b1001_0011);
+        run_frame(&mut ppu);
+
+        assert_eq!(ppu.shades()[0], 1);
+        assert_eq!(ppu.shades()[8], 3);
+        let line = &ppu.shades()[16 * SCREEN_WIDTH..];
+        assert_eq!(&line[28..36], &[1, 1, 1, 1, 1, 1, 1, 1]);
+        assert_eq!(&line[36..40], &[3, 3, 3, 3]);
+    }
+
+    /// Dots spent in mode 3 on the first line after turning the LCD on
+    fn drawing_dots(ppu: &mut Ppu) -> u32 {
+        let mut iflag = 0;
+        ppu.write(LCDC, 0b1001_0011);
+        while ppu.mode() != Mode::Drawing {
+            ppu.step(&mut iflag);
+        }
+
+        let mut dots = 0;
+        while ppu.mode() == Mode::Drawing {
+            ppu.step(&mut iflag);
+            dots += 1;
+        }
+        ppu.write(LCDC, 0);
+        dots
+    }
+
+    #[test]
+    fn fifo_mode3_length() {
+        let mut ppu = with_tiles();
+        ppu.set_render_mode(RenderMode::PixelFifo);
+        assert_eq!(drawing_dots(&mut ppu), 172);
+
+        // Fine scroll discards pixels
+        ppu.write(SCX, 3);
+        assert_eq!(drawing_dots(&mut ppu), 175);
+
+        // An object at X=0 pays the full penalty whatever the scroll
+        ppu.oam[..4].copy_from_slice(&[16, 0, 1, 0]);
+        assert_eq!(drawing_dots(&mut ppu), 175 + 11);
+        ppu.write(SCX, 0);
+
+        // An object at the start of a tile waits on the whole background fetch
+        ppu.oam[..4].copy_from_slice(&[16, 8, 1, 0]);
+        assert_eq!(drawing_dots(&mut ppu), 172 + 11);
+
+        // A second one in the same tile only pays for its own fetch
+        ppu.oam[4..8].copy_from_slice(&[16, 10, 1, 0]);
+        assert_eq!(drawing_dots(&mut ppu), 172 + 11 + 6);
+
+        // One near the end of a tile barely waits
+        ppu.oam[4..8].copy_from_slice(&[16, 8 + 21, 1, 0]);
+        assert_eq!(drawing_dots(&mut ppu), 172 + 11 + 6);
+    }
+
+    #[test]
+    fn fifo_matches_scanline() {
+        let mut scanline = with_tiles();
+        for (i, tile) in scanline.vram[0x1800..0x1C00].iter_mut().enumerate() {
+            *tile = (i % 3) as u8;
+        }
+        scanline.oam[..4].copy_from_slice(&[20, 13, 1, 0x20]);
+        scanline.oam[4..8].copy_from_slice(&[18, 3, 2, 0]);
+        scanline.write(SCX, 5);
+        scanline.write(WY, 40);
+        scanline.write(WX, 50);
+        scanline.write(LCDC, 0b1011_0011);
+        run_frame(&mut scanline);
+
+        let mut fifo = with_tiles();
+        fifo.vram = scanline.vram;
+        fifo.oam = scanline.oam;
+        fifo.set_render_mode(RenderMode::PixelFifo);
+        fifo.write(SCX, 5);
+        fifo.write(WY, 40);
+        fifo.write(WX, 50);
+        fifo.write(LCDC, 0b1011_0011);
+        run_frame(&mut fifo);
+
+        assert_eq!(scanline.shades(), fifo.shades());
+    }
+
+    #[test]
+    fn fifo_mid_line_palette_change() {
+        let mut ppu = with_tiles();
+        ppu.vram[0x1800..0x1C00].fill(1);
+        ppu.set_render_mode(RenderMode::PixelFifo);
+        ppu.write(LCDC, 0b1001_0001);
+
+        let mut iflag = 0;
+        // 80 dots of OAM scan, 12 before the first pixel then 80 pixels in
+        ppu.tick(43, &mut iflag);
+        ppu.write(BGP, 0b00_00_00_00);
+        run_frame(&mut ppu);
+
+        let line = &ppu.shades()[..SCREEN_WIDTH];
+        assert_eq!(line[79], 3);
+        assert_eq!(line[80], 0);
+    }
+
+    #[test]
+    fn cgb_palettes() {
+        let mut ppu = Ppu::default();
+        // Nothing there outside of CGB mode
+        ppu.write(BCPS, 0x80);
+        assert_eq!(ppu.read(BCPS), 0xFF);
+
+        ppu.set_cgb_mode(true);
+        ppu.write(BCPS, 0x80 | 0x3E);
+        assert_eq!(ppu.read(BCPS), 0xFE);
+        ppu.write(BCPD, 0x12);
+        ppu.write(BCPD, 0x34);
+        // Auto increment wraps around
+        assert_eq!(ppu.read(BCPS), 0xC0);
+        ppu.write(BCPS, 0x3E);
+        assert_eq!(ppu.read(BCPD), 0x12);
+        ppu.write(BCPD, 0x56);
+        assert_eq!(ppu.read(BCPS), 0x7E);
+        assert_eq!(ppu.read(BCPD), 0x56);
+
+        // Locked during mode 3, but the address still moves on
+        let mut iflag = 0;
+        ppu.write(OCPS, 0x80);
+        ppu.write(LCDC, 0x80);
+        ppu.tick(21, &mut iflag);
+        assert_eq!(ppu.read(OCPD), 0xFF);
+        ppu.write(OCPD, 0x00);
+        assert_eq!(ppu.read(OCPS), 0xC1);
+        ppu.write(LCDC, 0x00);
+        ppu.write(OCPS, 0x00);
+        assert_eq!(ppu.read(OCPD), 0xFF);
+    }
+
+    fn cgb_frame(render_mode: RenderMode, lcdc: u8) -> Ppu {
+        let mut ppu = Ppu::default();
+        ppu.set_cgb_mode(true);
+        ppu.set_render_mode(render_mode);
+        ppu.write(VBK, 0x01);
+        assert_eq!(ppu.read(VBK), 0xFF);
+        ppu.write(VBK, 0x00);
+
+        // Tile 1 in bank 1 has colour 1 on the left half and 2 on the right
+        for row in ppu.vram[VRAM_SIZE + 0x10..VRAM_SIZE + 0x20].chunks_exact_mut(2) {
+            row.copy_from_slice(&[0xF0, 0x0F]);
+        }
+        // Bank 0 tile 2 is solid colour 3
+        ppu.vram[0x20..0x30].fill(0xFF);
+
+        // Both use palette 2, the first is flipped and the second has priority over objects
+        ppu.vram[0x1800] = 1;
+        ppu.vram[VRAM_SIZE + 0x1800] = 0b0010_1010;
+        ppu.vram[0x1801] = 1;
+        ppu.vram[VRAM_SIZE + 0x1801] = 0b1000_1010;
+
+        // Palette 2 colour 1 is pure red, colour 2 pure blue
+        ppu.write(BCPS, 0x80 | 0x12);
+        for byte in [0x1F, 0x00, 0x00, 0x7C] {
+            ppu.write(BCPD, byte);
+        }
+        // Object palette 1 colour 3 is red, palette 3 colour 3 is green
+        ppu.write(OCPS, 0x80 | 0x0E);
+        ppu.write(OCPD, 0x1F);
+        ppu.write(OCPD, 0x00);
+        ppu.write(OCPS, 0x80 | 0x1E);
+        ppu.write(OCPD, 0xE0);
+        ppu.write(OCPD, 0x03);
+
+        // The first in OAM wins where they overlap at X 16-19, even though it's further right
+        ppu.oam[..4].copy_from_slice(&[16, 24, 2, 0x03]);
+        ppu.oam[4..8].copy_from_slice(&[16, 20, 2, 0x01]);
+        ppu.write(LCDC, lcdc);
+        run_frame(&mut ppu);
+        ppu
+    }
+
+    #[test]
+    fn cgb_rendering() {
+        const RED: u32 = 0xFF0000;
+        const GREEN: u32 = 0x00FF00;
+        const BLUE: u32 = 0x0000FF;
+
+        for render_mode in [RenderMode::Scanline, RenderMode::PixelFifo] {
+            let ppu = cgb_frame(render_mode, 0b1001_0011);
+            let line = &ppu.framebuffer()[..SCREEN_WIDTH];
+            // Flipped, so blue then red
+            assert_eq!(&line[..4], &[BLUE; 4], "{render_mode:?}");
+            assert_eq!(&line[4..8], &[RED; 4], "{render_mode:?}");
+            assert_eq!(&line[8..12], &[RED; 4], "{render_mode:?}");
+            // The background's priority keeps the object behind
+            assert_eq!(&line[12..16], &[BLUE; 4], "{render_mode:?}");
+            assert_eq!(&line[16..24], &[GREEN; 8], "{render_mode:?}");
+            assert_eq!(ppu.shades()[16], 3, "{render_mode:?}");
+            // Colour 0 of the default white palette
+            assert_eq!(line[24], 0xFFFFFF, "{render_mode:?}");
+        }
+
+        // With LCDC bit 0 clear objects go on top regardless
+        let ppu = cgb_frame(RenderMode::Scanline, 0b1001_0010);
+        assert_eq!(&ppu.framebuffer()[12..16], &[RED; 4]);
+        assert_eq!(ppu.framebuffer()[0], BLUE);
+    }
+}
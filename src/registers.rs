@@ -318,20 +318,20 @@ mod tests {
             ..Default::default()
         };
 
-        assert_eq!(r.z_flg(), true);
-        assert_eq!(r.n_flg(), false);
-        assert_eq!(r.h_flg(), false);
-        assert_eq!(r.c_flg(), false);
+        assert!(r.z_flg());
+        assert!(!r.n_flg());
+        assert!(!r.h_flg());
+        assert!(!r.c_flg());
 
         let r = Registers {
             af: 176, // 10110000
             ..Default::default()
         };
 
-        assert_eq!(r.z_flg(), true);
-        assert_eq!(r.n_flg(), false);
-        assert_eq!(r.h_flg(), true);
-        assert_eq!(r.c_flg(), true);
+        assert!(r.z_flg());
+        assert!(!r.n_flg());
+        assert!(r.h_flg());
+        assert!(r.c_flg());
     }
 
     #[test]
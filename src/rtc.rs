@@ -0,0 +1,343 @@
+use std::{
+    fmt::Debug,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::utils::BitExt;
+
+/// M-cycles per second at normal speed
+pub const CYCLES_PER_SECOND: u32 = 1 << 20;
+pub const RTC_FOOTER_SIZE: usize = 48;
+/// Older emulators write the footer with a 32 bit timestamp
+pub const RTC_FOOTER_SIZE_32: usize = 44;
+
+const SECONDS_PER_DAY: u64 = 60 * 60 * 24;
+
+/// Source of wall time for the RTC so tests don't have to wait on the real clock
+pub trait Clock: Debug + Send {
+    /// Seconds since the unix epoch
+    fn now(&self) -> u64;
+
+    fn box_clone(&self) -> Box<dyn Clock>;
+}
+
+impl Clone for Box<dyn Clock> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn box_clone(&self) -> Box<dyn Clock> {
+        Box::new(self.clone())
+    }
+
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+/// ┌──────┬────────┬─────────────────────────────────────────┐
+/// │ 0x08 │ RTC S  │ Seconds 0-59                            │
+/// │ 0x09 │ RTC M  │ Minutes 0-59                            │
+/// │ 0x0A │ RTC H  │ Hours 0-23                              │
+/// │ 0x0B │ RTC DL │ Lower 8 bits of day counter             │
+/// │ 0x0C │ RTC DH │ Bit 0: day bit 8, 6: halt, 7: day carry │
+/// └──────┴────────┴─────────────────────────────────────────┘
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RtcRegisters {
+    pub seconds: u8,
+    pub minutes: u8,
+    pub hours: u8,
+    pub day_low: u8,
+    pub day_high: u8,
+}
+
+impl RtcRegisters {
+    pub fn get(&self, register: u8) -> u8 {
+        match register {
+            0x08 => self.seconds,
+            0x09 => self.minutes,
+            0x0A => self.hours,
+            0x0B => self.day_low,
+            0x0C => self.day_high,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn days(&self) -> u16 {
+        u16::from_le_bytes([self.day_low, self.day_high & 0x01])
+    }
+
+    fn set_days(&mut self, days: u16) {
+        let [low, high] = days.to_le_bytes();
+        self.day_low = low;
+        self.day_high.set_bit(0, high & 0x01 == 1);
+    }
+
+    pub fn halted(&self) -> bool {
+        self.day_high.is_bit_set(6)
+    }
+
+    pub fn day_carry(&self) -> bool {
+        self.day_high.is_bit_set(7)
+    }
+
+    fn in_range(&self) -> bool {
+        self.seconds < 60 && self.minutes < 60 && self.hours < 24
+    }
+
+    /// The counters are plain 6/5 bit registers, values written out of range count up to the
+    /// register's max before wrapping to 0 without carrying.
+    fn tick_second(&mut self) {
+        self.seconds = (self.seconds + 1) & 0x3F;
+        if self.seconds != 60 {
+            return;
+        }
+        self.seconds = 0;
+
+        self.minutes = (self.minutes + 1) & 0x3F;
+        if self.minutes != 60 {
+            return;
+        }
+        self.minutes = 0;
+
+        self.hours = (self.hours + 1) & 0x1F;
+        if self.hours != 24 {
+            return;
+        }
+        self.hours = 0;
+
+        self.add_days(1);
+    }
+
+    fn add_days(&mut self, days: u64) {
+        let total = u64::from(self.days()) + days;
+        if total > 0x1FF {
+            self.day_high.set_bit(7, true);
+        }
+        self.set_days((total & 0x1FF) as u16);
+    }
+
+    fn advance(&mut self, mut seconds: u64) {
+        while seconds > 0 && !self.in_range() {
+            self.tick_second();
+            seconds -= 1;
+        }
+
+        let total = u64::from(self.seconds)
+            + u64::from(self.minutes) * 60
+            + u64::from(self.hours) * 60 * 60
+            + seconds;
+
+        self.seconds = (total % 60) as u8;
+        self.minutes = (total / 60 % 60) as u8;
+        self.hours = (total / (60 * 60) % 24) as u8;
+        self.add_days(total / SECONDS_PER_DAY);
+    }
+}
+
+// https://gbdev.io/pandocs/MBC3.html#the-clock-counter-registers
+#[derive(Debug, Clone)]
+pub struct Rtc {
+    pub registers: RtcRegisters,
+    pub latched: RtcRegisters,
+    cycles: u32,
+    last_latch_write: u8,
+    clock: Box<dyn Clock>,
+}
+
+impl Default for Rtc {
+    fn default() -> Self {
+        Self::new(Box::new(SystemClock))
+    }
+}
+
+impl Rtc {
+    pub fn new(clock: Box<dyn Clock>) -> Self {
+        Self {
+            registers: RtcRegisters::default(),
+            latched: RtcRegisters::default(),
+            cycles: 0,
+            last_latch_write: 0xFF,
+            clock,
+        }
+    }
+
+    pub fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    pub fn tick(&mut self, m_cycles: u32) {
+        if self.registers.halted() {
+            return;
+        }
+
+        self.cycles += m_cycles;
+        while self.cycles >= CYCLES_PER_SECOND {
+            self.cycles -= CYCLES_PER_SECOND;
+            self.registers.tick_second();
+        }
+    }
+
+    /// Writing 0x00 then 0x01 copies the live counters into the latched registers
+    pub fn write_latch(&mut self, value: u8) {
+        if self.last_latch_write == 0x00 && value == 0x01 {
+            self.latched = self.registers;
+        }
+        self.last_latch_write = value;
+    }
+
+    pub fn read(&self, register: u8) -> u8 {
+        self.latched.get(register)
+    }
+
+    pub fn write(&mut self, register: u8, value: u8) {
+        match register {
+            0x08 => {
+                self.registers.seconds = value & 0x3F;
+                // Writing seconds resets the sub-second divider
+                self.cycles = 0;
+            }
+            0x09 => self.registers.minutes = value & 0x3F,
+            0x0A => self.registers.hours = value & 0x1F,
+            0x0B => self.registers.day_low = value,
+            0x0C => self.registers.day_high = value & 0b1100_0001,
+            _ => {}
+        }
+    }
+
+    /// The 48 byte footer used by VBA-M, BGB, SameBoy and friends:
+    /// 5 little endian u32s for the live registers, 5 for the latched ones, then a u64 unix timestamp.
+    pub fn to_footer(&self) -> [u8; RTC_FOOTER_SIZE] {
+        let mut footer = [0; RTC_FOOTER_SIZE];
+        let registers = [self.registers, self.latched]
+            .into_iter()
+            .flat_map(|r| [r.seconds, r.minutes, r.hours, r.day_low, r.day_high]);
+
+        for (chunk, value) in footer.chunks_exact_mut(4).zip(registers) {
+            chunk.copy_from_slice(&u32::from(value).to_le_bytes());
+        }
+        footer[40..].copy_from_slice(&self.clock.now().to_le_bytes());
+
+        footer
+    }
+
+    /// Restores the registers from a footer and catches up on the time that passed on the host
+    /// since it was written.
+    pub fn load_footer(&mut self, footer: &[u8; RTC_FOOTER_SIZE]) {
+        let value = |i: usize| footer[i * 4];
+        let registers = |offset: usize| RtcRegisters {
+            seconds: value(offset) & 0x3F,
+            minutes: value(offset + 1) & 0x3F,
+            hours: value(offset + 2) & 0x1F,
+            day_low: value(offset + 3),
+            day_high: value(offset + 4) & 0b1100_0001,
+        };
+
+        self.registers = registers(0);
+        self.latched = registers(5);
+        self.cycles = 0;
+
+        let saved_at = u64::from_le_bytes(footer[40..].try_into().expect("8 byte timestamp"));
+        if !self.registers.halted() {
+            self.registers
+                .advance(self.clock.now().saturating_sub(saved_at));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    };
+
+    use crate::rtc::{CYCLES_PER_SECOND, Clock, Rtc};
+
+    #[derive(Debug, Clone, Default)]
+    struct FakeClock(Arc<AtomicU64>);
+
+    impl Clock for FakeClock {
+        fn now(&self) -> u64 {
+            self.0.load(Ordering::Relaxed)
+        }
+
+        fn box_clone(&self) -> Box<dyn Clock> {
+            Box::new(self.clone())
+        }
+    }
+
+    #[test]
+    fn counts_and_latches() {
+        let mut rtc = Rtc::new(Box::new(FakeClock::default()));
+        rtc.write(0x08, 59);
+        rtc.write(0x09, 59);
+        rtc.write(0x0A, 23);
+        rtc.write(0x0B, 0xFF);
+        rtc.write(0x0C, 0x01);
+
+        rtc.write_latch(0x00);
+        rtc.write_latch(0x01);
+        rtc.tick(CYCLES_PER_SECOND);
+
+        // Nothing is visible until latched again
+        assert_eq!(rtc.read(0x08), 59);
+        rtc.write_latch(0x00);
+        rtc.write_latch(0x01);
+
+        assert_eq!(rtc.read(0x08), 0);
+        assert_eq!(rtc.read(0x09), 0);
+        assert_eq!(rtc.read(0x0A), 0);
+        assert_eq!(rtc.read(0x0B), 0);
+        // Day counter overflowed so the carry is set
+        assert_eq!(rtc.read(0x0C), 0x80);
+
+        // Halted clocks don't tick
+        rtc.write(0x0C, 0x40);
+        rtc.tick(CYCLES_PER_SECOND * 2);
+        assert_eq!(rtc.registers.seconds, 0);
+    }
+
+    #[test]
+    fn out_of_range_wraps_without_carry() {
+        let mut rtc = Rtc::new(Box::new(FakeClock::default()));
+        rtc.write(0x08, 63);
+
+        rtc.tick(CYCLES_PER_SECOND);
+
+        assert_eq!(rtc.registers.seconds, 0);
+        assert_eq!(rtc.registers.minutes, 0);
+    }
+
+    #[test]
+    fn footer_round_trip_catches_up() {
+        let clock = FakeClock::default();
+        clock.0.store(1_000_000, Ordering::Relaxed);
+
+        let mut rtc = Rtc::new(Box::new(clock.clone()));
+        rtc.write(0x08, 30);
+        rtc.write(0x0A, 5);
+        let footer = rtc.to_footer();
+        assert_eq!(footer[0], 30);
+        assert_eq!(footer[8], 5);
+
+        // A day, an hour and 40 seconds later
+        clock.0.fetch_add(86400 + 3600 + 40, Ordering::Relaxed);
+
+        let mut restored = Rtc::new(Box::new(clock));
+        restored.load_footer(&footer);
+
+        assert_eq!(restored.registers.seconds, 10);
+        assert_eq!(restored.registers.minutes, 1);
+        assert_eq!(restored.registers.hours, 6);
+        assert_eq!(restored.registers.days(), 1);
+    }
+}
@@ -0,0 +1,252 @@
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    memory::{InterruptByte, InterruptType},
+    utils::BitExt,
+};
+
+pub const SB: u16 = 0xFF01;
+pub const SC: u16 = 0xFF02;
+
+/// The internal clock runs at 8192 Hz
+const CYCLES_PER_BIT: u32 = 128;
+/// CGB only, SC bit 1 speeds the internal clock up to 262144 Hz
+const FAST_CYCLES_PER_BIT: u32 = 4;
+
+/// Whatever is plugged into the link port
+pub trait SerialDevice: Debug + Send {
+    /// We're driving the clock and just shifted out `byte`. Returns the byte shifted in from the
+    /// other end, or None if nothing answered and the line stayed high.
+    fn transfer(&mut self, byte: u8) -> Option<u8>;
+
+    /// Polled while we're waiting on the other end to drive the clock. Returns the byte it sent
+    /// once it has, in exchange for `byte`.
+    fn external_transfer(&mut self, _byte: u8) -> Option<u8> {
+        None
+    }
+
+    fn tick(&mut self, _m_cycles: u32) {}
+}
+
+/// Records every byte sent, like the text Blargg's test ROMs print
+#[derive(Debug, Clone, Default)]
+pub struct CaptureDevice(Arc<Mutex<Vec<u8>>>);
+
+impl CaptureDevice {
+    pub fn bytes(&self) -> Vec<u8> {
+        self.0.lock().map(|b| b.clone()).unwrap_or_default()
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.bytes()).into_owned()
+    }
+}
+
+impl SerialDevice for CaptureDevice {
+    fn transfer(&mut self, byte: u8) -> Option<u8> {
+        if let Ok(mut bytes) = self.0.lock() {
+            bytes.push(byte);
+        }
+        None
+    }
+}
+
+/// ┌────┬────────┬───┬───┬───┬───┬───┬─────┬──────┐
+/// │ SC │   7    │ 6 │ 5 │ 4 │ 3 │ 2 │  1  │  0   │
+/// ├────┼────────┼───┼───┼───┼───┼───┼─────┼──────┤
+/// │    │Transfer│   │   │   │   │   │Speed│Clock │
+/// │    │ enable │   │   │   │   │   │(CGB)│select│
+/// └────┴────────┴───┴───┴───┴───┴───┴─────┴──────┘
+// https://gbdev.io/pandocs/Serial_Data_Transfer_(Link_Cable).html
+#[derive(Debug, Default)]
+pub struct Serial {
+    sb: u8,
+    sc: u8,
+    /// M-cycles until an internally clocked transfer is done
+    cycles: u32,
+    device: Option<Box<dyn SerialDevice>>,
+}
+
+/// A clone isn't plugged into anything, whatever is on the other end stays with the original
+impl Clone for Serial {
+    fn clone(&self) -> Self {
+        Self {
+            sb: self.sb,
+            sc: self.sc,
+            cycles: self.cycles,
+            device: None,
+        }
+    }
+}
+
+impl Serial {
+    pub fn connect(&mut self, device: Box<dyn SerialDevice>) {
+        self.device = Some(device);
+    }
+
+    pub fn disconnect(&mut self) -> Option<Box<dyn SerialDevice>> {
+        self.device.take()
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            SB => self.sb,
+            SC => self.sc | 0x7C,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            SB => self.sb = value,
+            SC => {
+                self.sc = value & 0x83;
+                let per_bit = if self.sc.is_bit_set(1) {
+                    FAST_CYCLES_PER_BIT
+                } else {
+                    CYCLES_PER_BIT
+                };
+                self.cycles = per_bit * 8;
+            }
+            _ => {}
+        }
+    }
+
+    /// Sets a register without starting a transfer
+    pub fn set_register(&mut self, addr: u16, value: u8) {
+        match addr {
+            SB => self.sb = value,
+            SC => self.sc = value & 0x03,
+            _ => {}
+        }
+    }
+
+    fn transferring(&self) -> bool {
+        self.sc.is_bit_set(7)
+    }
+
+    fn internal_clock(&self) -> bool {
+        self.sc.is_bit_set(0)
+    }
+
+    pub fn tick(&mut self, m_cycles: u32, interrupt_flag: &mut u8) {
+        if let Some(device) = &mut self.device {
+            device.tick(m_cycles);
+        }
+
+        if !self.transferring() {
+            return;
+        }
+
+        let received = if self.internal_clock() {
+            self.cycles = self.cycles.saturating_sub(m_cycles);
+            if self.cycles > 0 {
+                return;
+            }
+
+            // With nothing on the other end every bit shifted in is 1
+            self.device
+                .as_mut()
+                .and_then(|d| d.transfer(self.sb))
+                .unwrap_or(0xFF)
+        } else {
+            match self
+                .device
+                .as_mut()
+                .and_then(|d| d.external_transfer(self.sb))
+            {
+                Some(byte) => byte,
+                None => return,
+            }
+        };
+
+        self.sb = received;
+        self.sc.set_bit(7, false);
+        InterruptByte(interrupt_flag).set_flag(InterruptType::Serial, true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        cartridge::{Cartridge, test_rom},
+        cpu::Cpu,
+        memory::Memory,
+        serial::{CaptureDevice, SB, SC, Serial, SerialDevice},
+    };
+
+    #[derive(Debug)]
+    struct Echo;
+
+    impl SerialDevice for Echo {
+        fn transfer(&mut self, byte: u8) -> Option<u8> {
+            Some(byte)
+        }
+
+        fn external_transfer(&mut self, byte: u8) -> Option<u8> {
+            Some(!byte)
+        }
+    }
+
+    #[test]
+    fn capture() {
+        let capture = CaptureDevice::default();
+        let mut serial = Serial::default();
+        serial.connect(Box::new(capture.clone()));
+        let mut iflag = 0;
+
+        for &c in b"Passed" {
+            serial.write(SB, c);
+            serial.write(SC, 0x81);
+            serial.tick(8 * 128 - 1, &mut iflag);
+            assert_eq!(serial.read(SC), 0xFD);
+            serial.tick(1, &mut iflag);
+        }
+
+        assert_eq!(capture.text(), "Passed");
+        assert_eq!(iflag, 0b01000);
+        assert_eq!(serial.read(SB), 0xFF);
+        assert_eq!(serial.read(SC), 0x7D);
+    }
+
+    #[test]
+    fn external_clock() {
+        let mut serial = Serial::default();
+        let mut iflag = 0;
+        serial.write(SB, 0x0F);
+        serial.write(SC, 0x80);
+
+        // Nothing is driving the clock so it waits forever
+        serial.tick(10_000, &mut iflag);
+        assert_eq!(serial.read(SC), 0xFC);
+
+        serial.connect(Box::new(Echo));
+        serial.tick(1, &mut iflag);
+        assert_eq!(serial.read(SB), 0xF0);
+        assert_eq!(iflag, 0b01000);
+    }
+
+    #[test]
+    fn cpu_output() {
+        let cartridge = Cartridge::from_bytes(test_rom(0x00, 0x00, 0x00)).expect("Valid ROM");
+        let mut cpu = Cpu::new(Memory::with_cartridge(cartridge));
+        let capture = CaptureDevice::default();
+        cpu.memory.serial.connect(Box::new(capture.clone()));
+
+        // ld a, 'A'; ldh [SB], a; ld a, 0x81; ldh [SC], a; jr -2
+        let program = [0x3E, b'A', 0xE0, 0x01, 0x3E, 0x81, 0xE0, 0x02, 0x18, 0xFE];
+        for (i, &byte) in program.iter().enumerate() {
+            cpu.memory.set_byte(0xC000 + i as u16, byte);
+        }
+        cpu.registers.pc = 0xC000;
+
+        for _ in 0..400 {
+            cpu.run_next_instruction()
+                .expect("Unable to process CPU instructions");
+        }
+        assert_eq!(capture.text(), "A");
+    }
+}
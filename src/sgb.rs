@@ -0,0 +1,598 @@
+use crate::{
+    ppu::{Mode, Ppu, SCREEN_HEIGHT, SCREEN_WIDTH, rgb555_to_rgb},
+    utils::BitExt,
+};
+
+/// The SGB draws a border around the Game Boy screen
+pub const SGB_WIDTH: usize = 256;
+pub const SGB_HEIGHT: usize = 224;
+/// Where the Game Boy screen sits inside the border
+const SCREEN_X: usize = 48;
+const SCREEN_Y: usize = 40;
+
+const PACKET_SIZE: usize = 16;
+const PACKET_BITS: usize = PACKET_SIZE * 8;
+
+// https://gbdev.io/pandocs/SGB_Command_Summary.html
+const PAL01: u8 = 0x00;
+const PAL23: u8 = 0x01;
+const PAL03: u8 = 0x02;
+const PAL12: u8 = 0x03;
+const ATTR_BLK: u8 = 0x04;
+const PAL_SET: u8 = 0x0A;
+const PAL_TRN: u8 = 0x0B;
+const MLT_REQ: u8 = 0x11;
+const CHR_TRN: u8 = 0x13;
+const PCT_TRN: u8 = 0x14;
+const ATTR_TRN: u8 = 0x15;
+const MASK_EN: u8 = 0x17;
+
+/// VRAM transfers send 4 KiB by putting it on screen as 256 tiles, 20 to a row
+const TRANSFER_SIZE: usize = 0x1000;
+const TILES_PER_ROW: usize = SCREEN_WIDTH / 8;
+
+const SYSTEM_PALETTES: usize = 512;
+const ATTRIBUTE_FILES: usize = 45;
+const ATTRIBUTE_FILE_SIZE: usize = 90;
+
+/// The game screen in tiles, palettes are picked per tile
+const ATTRIBUTE_WIDTH: usize = SCREEN_WIDTH / 8;
+const ATTRIBUTE_HEIGHT: usize = SCREEN_HEIGHT / 8;
+
+const BORDER_TILES: usize = 256;
+/// SNES tiles have 4 bit planes
+const BORDER_TILE_SIZE: usize = 32;
+const BORDER_MAP_WIDTH: usize = 32;
+const BORDER_PALETTES: usize = 4;
+
+/// Shades of grey until the game sends its own colours
+const DEFAULT_PALETTE: [u16; 4] = [0x7FFF, 0x56B5, 0x294A, 0x0000];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Transfer {
+    Palettes,
+    Attributes,
+    /// Either the lower or upper half of the border tiles
+    Tiles(usize),
+    Border,
+}
+
+/// MASK_EN hides the game screen while the game sets up the next one
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum Mask {
+    #[default]
+    Off,
+    Freeze,
+    Black,
+    Color0,
+}
+
+/// Commands are sent as packets of 16 bytes by bit-banging P1. Both lines low starts a packet,
+/// then P14 low is a 0 and P15 low is a 1 with both lines going high after each bit, least
+/// significant bit first. A 0 stop bit follows the 128 data bits.
+///
+/// ┌─────────┬──────────────┬──────────────┐
+/// │ Byte 0  │   7 - 3      │    2 - 0     │
+/// ├─────────┼──────────────┼──────────────┤
+/// │         │ Command      │ Packets      │
+/// └─────────┴──────────────┴──────────────┘
+// https://gbdev.io/pandocs/SGB_Functions.html
+#[derive(Debug, Clone)]
+pub struct Sgb {
+    /// Set from the reset pulse until the stop bit
+    receiving: bool,
+    /// Both lines have gone high since the last bit
+    released: bool,
+    bits: usize,
+    packet: [u8; PACKET_SIZE],
+    /// Packets of the command being received
+    command: Vec<u8>,
+    players: usize,
+    /// Colour 0 of the first palette is used by all of them
+    palettes: [[u16; 4]; 4],
+    system_palettes: Vec<[u16; 4]>,
+    /// Palette of each tile on the game screen
+    attributes: [u8; ATTRIBUTE_WIDTH * ATTRIBUTE_HEIGHT],
+    attribute_files: Vec<u8>,
+    mask: Mask,
+    /// Waiting on the next frame to read the data off the screen
+    transfer: Option<Transfer>,
+    border_tiles: Vec<u8>,
+    border_map: [u16; BORDER_MAP_WIDTH * BORDER_MAP_WIDTH],
+    /// Border palettes 4-7, colour 0 of each is transparent
+    border_palettes: [[u16; 16]; BORDER_PALETTES],
+    /// The coloured game screen, kept while the mask freezes it
+    screen: Vec<u32>,
+    framebuffer: Vec<u32>,
+    in_vblank: bool,
+}
+
+impl Default for Sgb {
+    fn default() -> Self {
+        Self {
+            receiving: false,
+            released: false,
+            bits: 0,
+            packet: [0; PACKET_SIZE],
+            command: Vec::new(),
+            players: 1,
+            palettes: [DEFAULT_PALETTE; 4],
+            system_palettes: vec![DEFAULT_PALETTE; SYSTEM_PALETTES],
+            attributes: [0; ATTRIBUTE_WIDTH * ATTRIBUTE_HEIGHT],
+            attribute_files: vec![0; ATTRIBUTE_FILES * ATTRIBUTE_FILE_SIZE],
+            mask: Mask::Off,
+            transfer: None,
+            border_tiles: vec![0; BORDER_TILES * BORDER_TILE_SIZE],
+            border_map: [0; BORDER_MAP_WIDTH * BORDER_MAP_WIDTH],
+            border_palettes: [[0; 16]; BORDER_PALETTES],
+            screen: vec![rgb555_to_rgb(DEFAULT_PALETTE[0]); SCREEN_WIDTH * SCREEN_HEIGHT],
+            framebuffer: vec![rgb555_to_rgb(DEFAULT_PALETTE[0]); SGB_WIDTH * SGB_HEIGHT],
+            in_vblank: false,
+        }
+    }
+}
+
+impl Sgb {
+    /// Controllers the game asked for with MLT_REQ
+    pub fn players(&self) -> usize {
+        self.players
+    }
+
+    /// The 256x224 picture as 0x00RRGGBB, the game screen inside the border
+    pub fn framebuffer(&self) -> &[u32] {
+        &self.framebuffer
+    }
+
+    /// Watches the P1 select lines for packet bits
+    pub fn write_p1(&mut self, value: u8) {
+        match value & 0x30 {
+            0x00 => {
+                self.receiving = true;
+                self.released = false;
+                self.bits = 0;
+                self.packet = [0; PACKET_SIZE];
+            }
+            0x30 => self.released = true,
+            lines if self.receiving && self.released => {
+                self.released = false;
+                let bit = lines == 0x10;
+
+                if self.bits == PACKET_BITS {
+                    self.receiving = false;
+                    // A 1 where the stop bit should be throws the packet away
+                    if !bit {
+                        self.receive_packet();
+                    }
+                    return;
+                }
+
+                self.packet[self.bits / 8].set_bit((self.bits % 8) as u32, bit);
+                self.bits += 1;
+            }
+            _ => {}
+        }
+    }
+
+    fn receive_packet(&mut self) {
+        self.command.extend_from_slice(&self.packet);
+
+        // The first packet says how many make up the command
+        let packets = usize::from(self.command[0] & 0x07).max(1);
+        if self.command.len() >= packets * PACKET_SIZE {
+            let command = std::mem::take(&mut self.command);
+            self.run_command(&command);
+        }
+    }
+
+    fn run_command(&mut self, data: &[u8]) {
+        match data[0] >> 3 {
+            PAL01 => self.set_palettes(0, 1, data),
+            PAL23 => self.set_palettes(2, 3, data),
+            PAL03 => self.set_palettes(0, 3, data),
+            PAL12 => self.set_palettes(1, 2, data),
+            ATTR_BLK => self.attribute_blocks(data),
+            PAL_SET => self.palette_set(data),
+            PAL_TRN => self.transfer = Some(Transfer::Palettes),
+            ATTR_TRN => self.transfer = Some(Transfer::Attributes),
+            CHR_TRN => self.transfer = Some(Transfer::Tiles(usize::from(data[1] & 0x01))),
+            PCT_TRN => self.transfer = Some(Transfer::Border),
+            MLT_REQ => {
+                self.players = match data[1] & 0x03 {
+                    1 => 2,
+                    3 => 4,
+                    _ => 1,
+                }
+            }
+            MASK_EN => {
+                self.mask = match data[1] & 0x03 {
+                    1 => Mask::Freeze,
+                    2 => Mask::Black,
+                    3 => Mask::Color0,
+                    _ => Mask::Off,
+                }
+            }
+            // Sound, SNES code and the rest have nothing to do here
+            _ => {}
+        }
+    }
+
+    /// Colour 0 is shared, then colours 1-3 of the first palette followed by the second's
+    fn set_palettes(&mut self, first: usize, second: usize, data: &[u8]) {
+        let colors: Vec<u16> = data[1..15]
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect();
+
+        self.palettes[0][0] = colors[0];
+        self.palettes[first][1..].copy_from_slice(&colors[1..4]);
+        self.palettes[second][1..].copy_from_slice(&colors[4..7]);
+    }
+
+    /// Each data set colours a rectangle of tiles, 6 bytes each after the count in byte 1
+    ///
+    /// ┌──────────┬─────────────────────────────────────────────┐
+    /// │ Byte 0   │ Change: bit 0 inside, 1 edge, 2 outside     │
+    /// │ Byte 1   │ Palettes: 1-0 inside, 3-2 edge, 5-4 outside │
+    /// │ Byte 2-5 │ Left, top, right and bottom in tiles        │
+    /// └──────────┴─────────────────────────────────────────────┘
+    fn attribute_blocks(&mut self, data: &[u8]) {
+        let sets = usize::from(data[1] & 0x1F);
+
+        for set in data[2..].chunks_exact(6).take(sets) {
+            let control = set[0] & 0x07;
+            let inside = set[1] & 0x03;
+            let outside = (set[1] >> 4) & 0x03;
+            // Changing just the inside or just the outside changes the edge along with it
+            let edge = match control {
+                0x01 => Some(inside),
+                0x04 => Some(outside),
+                _ => control.is_bit_set(1).then_some((set[1] >> 2) & 0x03),
+            };
+            let (left, top, right, bottom) = (
+                usize::from(set[2] & 0x1F),
+                usize::from(set[3] & 0x1F),
+                usize::from(set[4] & 0x1F),
+                usize::from(set[5] & 0x1F),
+            );
+
+            for y in 0..ATTRIBUTE_HEIGHT {
+                for x in 0..ATTRIBUTE_WIDTH {
+                    let within = (left..=right).contains(&x) && (top..=bottom).contains(&y);
+                    let on_edge = within && (x == left || x == right || y == top || y == bottom);
+
+                    let palette = if on_edge {
+                        edge
+                    } else if within {
+                        control.is_bit_set(0).then_some(inside)
+                    } else {
+                        control.is_bit_set(2).then_some(outside)
+                    };
+                    if let Some(palette) = palette {
+                        self.attributes[y * ATTRIBUTE_WIDTH + x] = palette;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Picks 4 of the system palettes sent with PAL_TRN and optionally an attribute file
+    fn palette_set(&mut self, data: &[u8]) {
+        for (palette, number) in self.palettes.iter_mut().zip(data[1..9].chunks_exact(2)) {
+            let number = usize::from(u16::from_le_bytes([number[0], number[1]])) % SYSTEM_PALETTES;
+            *palette = self.system_palettes[number];
+        }
+
+        let flags = data[9];
+        if flags.is_bit_set(7) {
+            let file = usize::from(flags & 0x3F).min(ATTRIBUTE_FILES - 1);
+            self.apply_attribute_file(file);
+        }
+        if flags.is_bit_set(6) {
+            self.mask = Mask::Off;
+        }
+    }
+
+    /// Each byte has the palettes of 4 tiles, 2 bits each from the top
+    fn apply_attribute_file(&mut self, file: usize) {
+        let start = file * ATTRIBUTE_FILE_SIZE;
+        let bytes = &self.attribute_files[start..start + ATTRIBUTE_FILE_SIZE];
+
+        for (i, attribute) in self.attributes.iter_mut().enumerate() {
+            *attribute = (bytes[i / 4] >> (6 - 2 * (i % 4))) & 0x03;
+        }
+    }
+
+    /// Called after the PPU has run, a new frame is put together when VBlank starts
+    pub fn tick(&mut self, ppu: &Ppu) {
+        let in_vblank = ppu.lcd_enabled() && ppu.mode() == Mode::VBlank;
+        if in_vblank && !self.in_vblank {
+            self.frame(ppu.shades());
+        }
+        self.in_vblank = in_vblank;
+    }
+
+    fn frame(&mut self, shades: &[u8]) {
+        if let Some(transfer) = self.transfer.take() {
+            self.receive_transfer(transfer, &transfer_data(shades));
+        }
+
+        self.draw_screen(shades);
+        self.draw_border();
+    }
+
+    fn receive_transfer(&mut self, transfer: Transfer, data: &[u8]) {
+        let to_colors = |bytes: &[u8]| -> Vec<u16> {
+            bytes
+                .chunks_exact(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .collect()
+        };
+
+        match transfer {
+            Transfer::Palettes => {
+                for (palette, colors) in self.system_palettes.iter_mut().zip(data.chunks_exact(8)) {
+                    palette.copy_from_slice(&to_colors(colors));
+                }
+            }
+            Transfer::Attributes => {
+                let size = self.attribute_files.len();
+                self.attribute_files.copy_from_slice(&data[..size]);
+            }
+            Transfer::Tiles(half) => {
+                let start = half * TRANSFER_SIZE;
+                self.border_tiles[start..start + TRANSFER_SIZE].copy_from_slice(data);
+            }
+            Transfer::Border => {
+                let map_size = self.border_map.len() * 2;
+                self.border_map
+                    .copy_from_slice(&to_colors(&data[..map_size]));
+                for (palette, colors) in self
+                    .border_palettes
+                    .iter_mut()
+                    .zip(data[map_size..].chunks_exact(32))
+                {
+                    palette.copy_from_slice(&to_colors(colors));
+                }
+            }
+        }
+    }
+
+    fn draw_screen(&mut self, shades: &[u8]) {
+        let backdrop = rgb555_to_rgb(self.palettes[0][0]);
+
+        match self.mask {
+            Mask::Freeze => {}
+            Mask::Black => self.screen.fill(0x000000),
+            Mask::Color0 => self.screen.fill(backdrop),
+            Mask::Off => {
+                for (i, (pixel, &shade)) in self.screen.iter_mut().zip(shades).enumerate() {
+                    let (x, y) = (i % SCREEN_WIDTH, i / SCREEN_WIDTH);
+                    let palette = self.attributes[(y / 8) * ATTRIBUTE_WIDTH + x / 8];
+                    *pixel = match shade {
+                        0 => backdrop,
+                        _ => rgb555_to_rgb(self.palettes[usize::from(palette)][usize::from(shade)]),
+                    };
+                }
+            }
+        }
+    }
+
+    /// The border goes over the game screen, where it's transparent the screen or colour 0 of the
+    /// first palette shows through
+    fn draw_border(&mut self) {
+        let backdrop = rgb555_to_rgb(self.palettes[0][0]);
+
+        for (i, pixel) in self.framebuffer.iter_mut().enumerate() {
+            let (x, y) = (i % SGB_WIDTH, i / SGB_WIDTH);
+
+            let entry = self.border_map[(y / 8) * BORDER_MAP_WIDTH + x / 8];
+            let column = if entry.is_bit_set(14) {
+                7 - x % 8
+            } else {
+                x % 8
+            };
+            let row = if entry.is_bit_set(15) {
+                7 - y % 8
+            } else {
+                y % 8
+            };
+            let tile = &self.border_tiles[usize::from(entry & 0xFF) * BORDER_TILE_SIZE..];
+
+            // Planes 0 and 1 are interleaved in the first 16 bytes, 2 and 3 in the rest
+            let bit = 7 - column as u32;
+            let color = (0..4).fold(0, |color, plane| {
+                let byte = tile[(plane / 2) * 16 + row * 2 + plane % 2];
+                color | (usize::from(byte.is_bit_set(bit)) << plane)
+            });
+
+            let screen_x = x.wrapping_sub(SCREEN_X);
+            let screen_y = y.wrapping_sub(SCREEN_Y);
+            *pixel = if color != 0 {
+                let palette = usize::from(entry >> 10) & (BORDER_PALETTES - 1);
+                rgb555_to_rgb(self.border_palettes[palette][color])
+            } else if screen_x < SCREEN_WIDTH && screen_y < SCREEN_HEIGHT {
+                self.screen[screen_y * SCREEN_WIDTH + screen_x]
+            } else {
+                backdrop
+            };
+        }
+    }
+}
+
+/// Turns the screen back into the tile data it was drawn from. The SGB sees what the LCD shows,
+/// so games send data with the identity palette and tiles 0-255 laid out in order.
+fn transfer_data(shades: &[u8]) -> Vec<u8> {
+    let mut data = vec![0; TRANSFER_SIZE];
+
+    for (i, byte) in data.iter_mut().enumerate() {
+        let tile = i / 16;
+        let row = (i % 16) / 2;
+        // Even bytes are the low bit of each colour, odd ones the high bit
+        let plane = i % 2;
+
+        let y = (tile / TILES_PER_ROW) * 8 + row;
+        let x = (tile % TILES_PER_ROW) * 8;
+        for column in 0..8 {
+            let shade = shades[y * SCREEN_WIDTH + x + column];
+            byte.set_bit(7 - column as u32, shade.is_bit_set(plane as u32));
+        }
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        boot::Model,
+        cartridge::{Cartridge, HEADER_CHECKSUM, header_checksum, test_rom},
+        cpu::Cpu,
+        joypad::{Button, P1},
+        memory::Memory,
+        ppu::{SCREEN_HEIGHT, SCREEN_WIDTH},
+        sgb::{SCREEN_X, SCREEN_Y, SGB_WIDTH, Sgb, TRANSFER_SIZE, transfer_data},
+    };
+
+    fn sgb_memory() -> Memory {
+        let mut rom = test_rom(0x00, 0x00, 0x00);
+        rom[0x0146] = 0x03;
+        rom[0x014B] = 0x33;
+        rom[HEADER_CHECKSUM] = header_checksum(&rom);
+        Memory::with_cartridge(Cartridge::from_bytes(rom).expect("Valid ROM"))
+    }
+
+    /// Bit-bangs a packet through P1 the way games do
+    fn send(mem: &mut Memory, packet: [u8; 16], stop_bit: bool) {
+        mem.set_byte(P1, 0x00);
+        mem.set_byte(P1, 0x30);
+        let bits = (0..128).map(|i| packet[i / 8] >> (i % 8) & 1 == 1);
+        for bit in bits.chain([stop_bit]) {
+            mem.set_byte(P1, if bit { 0x10 } else { 0x20 });
+            mem.set_byte(P1, 0x30);
+        }
+    }
+
+    fn packet(command: u8, data: &[u8]) -> [u8; 16] {
+        let mut packet = [0; 16];
+        packet[0] = (command << 3) | 1;
+        packet[1..=data.len()].copy_from_slice(data);
+        packet
+    }
+
+    /// The screen that sends `data` over in a VRAM transfer
+    fn transfer_screen(data: &[u8]) -> Vec<u8> {
+        let mut shades = vec![0; SCREEN_WIDTH * SCREEN_HEIGHT];
+        for (tile, bytes) in data.chunks_exact(16).enumerate() {
+            for (row, planes) in bytes.chunks_exact(2).enumerate() {
+                let y = (tile / 20) * 8 + row;
+                for column in 0..8 {
+                    let low = planes[0] >> (7 - column) & 1;
+                    let high = planes[1] >> (7 - column) & 1;
+                    shades[y * SCREEN_WIDTH + (tile % 20) * 8 + column] = (high << 1) | low;
+                }
+            }
+        }
+        shades
+    }
+
+    #[test]
+    fn packets_and_multiplayer() {
+        let mut mem = sgb_memory();
+        assert_eq!(mem.model, Model::Sgb);
+
+        // A bad stop bit loses the packet
+        send(&mut mem, packet(0x11, &[0x01]), true);
+        assert_eq!(mem.joypad.players(), 1);
+        send(&mut mem, packet(0x11, &[0x01]), false);
+        assert_eq!(mem.joypad.players(), 2);
+
+        // The controller ID shows up with neither group selected
+        mem.joypad.press_player(1, Button::A);
+        assert_eq!(mem.get_byte(P1).expect("Unable to get byte"), 0xFF);
+        mem.set_byte(P1, 0x10);
+        assert_eq!(mem.get_byte(P1).expect("Unable to get byte"), 0xDF);
+        mem.set_byte(P1, 0x30);
+        assert_eq!(mem.get_byte(P1).expect("Unable to get byte"), 0xFE);
+        mem.set_byte(P1, 0x10);
+        assert_eq!(mem.get_byte(P1).expect("Unable to get byte"), 0xDE);
+        mem.set_byte(P1, 0x30);
+        assert_eq!(mem.get_byte(P1).expect("Unable to get byte"), 0xFF);
+
+        send(&mut mem, packet(0x11, &[0x00]), false);
+        assert_eq!(mem.joypad.players(), 1);
+
+        let mut cpu = Cpu::new(mem);
+        cpu.skip_boot();
+        assert_eq!(cpu.registers.af, 0x0100);
+        assert_eq!(cpu.registers.bc, 0x0014);
+        assert_eq!(cpu.registers.de, 0x0000);
+        assert_eq!(cpu.registers.hl, 0xC060);
+    }
+
+    fn screen_pixel(sgb: &Sgb, x: usize, y: usize) -> u32 {
+        sgb.framebuffer()[(SCREEN_Y + y) * SGB_WIDTH + SCREEN_X + x]
+    }
+
+    #[test]
+    fn colorization() {
+        let mut sgb = Sgb::default();
+        // Colour 0 black, palette 0 colour 3 red, palette 1 colour 3 blue
+        sgb.run_command(&packet(
+            0x00,
+            &[0, 0, 0, 0, 0, 0, 0x1F, 0, 0, 0, 0, 0, 0x00, 0x7C],
+        ));
+        // Palette 1 inside and on the edge of tiles 1,1 to 3,3
+        sgb.run_command(&packet(0x04, &[1, 0x03, 0b0000_0101, 1, 1, 3, 3]));
+
+        let shades = vec![3; SCREEN_WIDTH * SCREEN_HEIGHT];
+        sgb.frame(&shades);
+        assert_eq!(screen_pixel(&sgb, 0, 0), 0xFF0000);
+        assert_eq!(screen_pixel(&sgb, 8, 8), 0x0000FF);
+        assert_eq!(screen_pixel(&sgb, 31, 31), 0x0000FF);
+        assert_eq!(screen_pixel(&sgb, 32, 32), 0xFF0000);
+        // Outside the game screen is colour 0
+        assert_eq!(sgb.framebuffer()[0], 0x000000);
+
+        // Frozen keeps the last frame, black blanks it
+        sgb.run_command(&packet(0x17, &[0x01]));
+        sgb.frame(&vec![0; SCREEN_WIDTH * SCREEN_HEIGHT]);
+        assert_eq!(screen_pixel(&sgb, 0, 0), 0xFF0000);
+        sgb.run_command(&packet(0x17, &[0x02]));
+        sgb.frame(&shades);
+        assert_eq!(screen_pixel(&sgb, 8, 8), 0x000000);
+    }
+
+    #[test]
+    fn vram_transfers() {
+        let data: Vec<u8> = (0..TRANSFER_SIZE).map(|i| (i * 7 % 251) as u8).collect();
+        assert_eq!(transfer_data(&transfer_screen(&data)), data);
+
+        let mut sgb = Sgb::default();
+
+        // System palette 1 has green as colour 1, picked for palette 0
+        let mut palettes = vec![0; TRANSFER_SIZE];
+        palettes[10..12].copy_from_slice(&0x03E0u16.to_le_bytes());
+        sgb.run_command(&packet(0x0B, &[]));
+        sgb.frame(&transfer_screen(&palettes));
+        sgb.run_command(&packet(0x0A, &[1, 0, 0, 0, 0, 0, 0, 0, 0x40]));
+
+        // Border tile 1 is solid colour 15
+        let mut tiles = vec![0; TRANSFER_SIZE];
+        tiles[32..64].fill(0xFF);
+        sgb.run_command(&packet(0x13, &[0x00]));
+        sgb.frame(&transfer_screen(&tiles));
+
+        // The top left tile of the border is tile 1 with palette 5, colour 15 of that is white
+        let mut border = vec![0; TRANSFER_SIZE];
+        border[..2].copy_from_slice(&(0x1400u16 | 1).to_le_bytes());
+        border[0x800 + 32 + 30..0x800 + 32 + 32].copy_from_slice(&0x7FFFu16.to_le_bytes());
+        sgb.run_command(&packet(0x14, &[]));
+        sgb.frame(&transfer_screen(&border));
+
+        sgb.frame(&vec![1; SCREEN_WIDTH * SCREEN_HEIGHT]);
+        assert_eq!(sgb.framebuffer()[0], 0xFFFFFF);
+        assert_eq!(sgb.framebuffer()[7], 0xFFFFFF);
+        // Colour 0 of the new palette 0 shows through the transparent tiles
+        assert_eq!(sgb.framebuffer()[8], 0x000000);
+        assert_eq!(sgb.framebuffer()[SCREEN_Y * SGB_WIDTH + SCREEN_X], 0x00FF00);
+    }
+}
@@ -0,0 +1,202 @@
+use crate::{
+    memory::{InterruptByte, InterruptType},
+    utils::BitExt,
+};
+
+pub const DIV: u16 = 0xFF04;
+pub const TIMA: u16 = 0xFF05;
+pub const TMA: u16 = 0xFF06;
+pub const TAC: u16 = 0xFF07;
+
+/// ┌─────┬───┬───┬───┬───┬───┬──────┬───────┬───────┐
+/// │ TAC │ 7 │ 6 │ 5 │ 4 │ 3 │  2   │   1   │   0   │
+/// ├─────┼───┼───┼───┼───┼───┼──────┼───────┼───────┤
+/// │     │   │   │   │   │   │Enable│ Clock select  │
+/// └─────┴───┴───┴───┴───┴───┴──────┴───────────────┘
+///
+/// DIV is the upper byte of a 16 bit counter that goes up every T-cycle. TIMA is incremented
+/// whenever the selected counter bit ANDed with the enable bit goes from 1 to 0, so resetting DIV
+/// or changing TAC can tick TIMA too.
+// https://gbdev.io/pandocs/Timer_Obscure_Behaviour.html
+#[derive(Debug, Clone, Default)]
+pub struct Timer {
+    counter: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    /// TIMA overflowed and reads 0x00 for an M-cycle before it's reloaded from TMA
+    overflowed: bool,
+    /// The M-cycle TMA is copied into TIMA, writes to TIMA are ignored during it
+    reloading: bool,
+}
+
+impl Timer {
+    pub fn counter(&self) -> u16 {
+        self.counter
+    }
+
+    /// Sets the internal counter directly, used to match the state the boot ROM leaves it in
+    pub fn set_counter(&mut self, counter: u16) {
+        self.counter = counter;
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            DIV => self.counter.to_be_bytes()[0],
+            TIMA => self.tima,
+            TMA => self.tma,
+            TAC => self.tac | 0xF8,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            // Any write resets the whole counter
+            DIV => self.update_counter(0),
+            TIMA if self.reloading => {}
+            TIMA => {
+                // Writing during the overflow cycle cancels the reload and the interrupt
+                self.tima = value;
+                self.overflowed = false;
+            }
+            TMA => {
+                self.tma = value;
+                if self.reloading {
+                    self.tima = value;
+                }
+            }
+            TAC => {
+                let signal = self.signal();
+                self.tac = value & 0x07;
+                self.detect_falling_edge(signal);
+            }
+            _ => {}
+        }
+    }
+
+    /// Sets a register without the side effects of a CPU write. DIV is set through `set_counter`.
+    pub fn set_register(&mut self, addr: u16, value: u8) {
+        match addr {
+            TIMA => self.tima = value,
+            TMA => self.tma = value,
+            TAC => self.tac = value & 0x07,
+            _ => {}
+        }
+    }
+
+    pub fn tick(&mut self, m_cycles: u8, interrupt_flag: &mut u8) {
+        for _ in 0..m_cycles {
+            self.reloading = false;
+
+            if self.overflowed {
+                self.overflowed = false;
+                self.reloading = true;
+                self.tima = self.tma;
+                InterruptByte(interrupt_flag).set_flag(InterruptType::Timer, true);
+            }
+
+            self.update_counter(self.counter.wrapping_add(4));
+        }
+    }
+
+    /// Counter bit TIMA is clocked from
+    fn selected_bit(&self) -> u32 {
+        match self.tac & 0x03 {
+            0b00 => 9, // 4096 Hz
+            0b01 => 3, // 262144 Hz
+            0b10 => 5, // 65536 Hz
+            _ => 7,    // 16384 Hz
+        }
+    }
+
+    fn signal(&self) -> bool {
+        self.tac.is_bit_set(2) && self.counter.is_bit_set(self.selected_bit())
+    }
+
+    fn update_counter(&mut self, counter: u16) {
+        let signal = self.signal();
+        self.counter = counter;
+        self.detect_falling_edge(signal);
+    }
+
+    fn detect_falling_edge(&mut self, old_signal: bool) {
+        if !old_signal || self.signal() {
+            return;
+        }
+
+        let (tima, overflow) = self.tima.overflowing_add(1);
+        self.tima = tima;
+        self.overflowed |= overflow;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::timer::{DIV, TAC, TIMA, TMA, Timer};
+
+    #[test]
+    fn div_and_tima() {
+        let mut timer = Timer::default();
+        let mut iflag = 0;
+
+        timer.tick(64, &mut iflag);
+        assert_eq!(timer.read(DIV), 1);
+        // Disabled so TIMA doesn't move
+        assert_eq!(timer.read(TIMA), 0);
+
+        // 262144 Hz is every 4 M-cycles
+        timer.write(DIV, 0x12);
+        timer.write(TAC, 0b101);
+        assert_eq!(timer.read(DIV), 0);
+        timer.tick(16, &mut iflag);
+        assert_eq!(timer.read(TIMA), 4);
+        assert_eq!(timer.read(TAC), 0xFD);
+    }
+
+    #[test]
+    fn overflow_reload_delay() {
+        let mut timer = Timer::default();
+        let mut iflag = 0;
+        timer.write(TMA, 0x42);
+        timer.write(TIMA, 0xFF);
+        timer.write(TAC, 0b101);
+
+        timer.tick(4, &mut iflag);
+        // TIMA reads 0 for a cycle before the reload and interrupt
+        assert_eq!(timer.read(TIMA), 0x00);
+        assert_eq!(iflag, 0);
+
+        timer.tick(1, &mut iflag);
+        assert_eq!(timer.read(TIMA), 0x42);
+        assert_eq!(iflag, 0b00100);
+
+        // Writing TIMA in the cycle after the overflow cancels the reload
+        let mut timer = Timer::default();
+        let mut iflag = 0;
+        timer.write(TIMA, 0xFF);
+        timer.write(TAC, 0b101);
+        timer.tick(4, &mut iflag);
+        timer.write(TIMA, 0x10);
+        timer.tick(1, &mut iflag);
+        assert_eq!(timer.read(TIMA), 0x10);
+        assert_eq!(iflag, 0);
+    }
+
+    #[test]
+    fn falling_edge_glitches() {
+        let mut timer = Timer::default();
+        let mut iflag = 0;
+        timer.write(TAC, 0b101);
+        timer.tick(2, &mut iflag);
+
+        // Bit 3 is set so resetting DIV is a falling edge
+        timer.write(DIV, 0);
+        assert_eq!(timer.read(TIMA), 1);
+
+        // So is disabling the timer
+        timer.tick(2, &mut iflag);
+        timer.write(TAC, 0b001);
+        assert_eq!(timer.read(TIMA), 2);
+    }
+}
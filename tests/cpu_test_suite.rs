@@ -105,11 +105,7 @@ fn test_file(tests: Vec<Test>) {
     }
 }
 
-gen_test!(0x00, 0x0F);
-// TODO: 0x10 STOP
-gen_test!(0x11, 0x75);
-// TODO: 0x76 HALT
-gen_test!(0x77, 0xCA);
+gen_test!(0x00, 0xCA);
 // 0xCB Empty
 gen_test!(0xCC, 0xD2);
 // 0xD3 Empty